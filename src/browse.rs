@@ -1,27 +1,65 @@
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 use anyhow::Context;
 use anyhow::Result;
+use colored::Colorize;
 use futures::StreamExt;
+use itertools::Itertools;
 use serde::Deserialize;
 use serde::Serialize;
 use tracing::debug;
 use tracing::info;
 use tracing::warn;
 
+use crate::browse_server;
+use crate::catalog::LibraryCatalog;
+use crate::catalog::search_shelf;
 use crate::goodreads;
+use crate::history::AvailabilityHistory;
+use crate::hoopla::HooplaClient;
+use crate::hoopla::HooplaKind;
+use crate::json_state;
 use crate::libby::BookType;
 use crate::libby::LibbyClient;
+use crate::libby::LibbyEndpoints;
+use crate::libby::LibbySearchResultItem;
 use crate::libby::SearchOptions;
+use crate::openlibrary::OpenLibraryClient;
+use crate::request_policy::RequestPolicy;
 
-#[derive(Debug, Serialize)]
+/// Where a browse result was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BrowseSource {
+    Libby,
+    /// Hoopla titles can always be borrowed instantly
+    Hoopla,
+}
+
+impl std::fmt::Display for BrowseSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Libby => write!(f, "libby"),
+            Self::Hoopla => write!(f, "hoopla"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct BrowseResult {
     pub title: String,
     pub author: String,
     pub pages: Option<i64>,
     pub goodreads_shelves: Vec<String>,
-    pub libby_id: String,
+    pub source: BrowseSource,
+    /// Id of the title in `source`. Serialized as `libby_id`, the name it
+    /// had before other sources were searched.
+    #[serde(rename = "libby_id")]
+    pub source_id: String,
+    /// Other sources that have the same book, merged into this result
+    pub also_on: Vec<BrowseSource>,
     pub goodreads_id: i64,
     pub is_available: bool,
     pub estimated_wait_days: Option<i64>,
@@ -29,53 +67,223 @@ pub struct BrowseResult {
     pub owned_copies: Option<i64>,
     pub available_copies: Option<i64>,
     pub has_kindle: Option<bool>,
+    /// Audiobook length
+    pub duration_minutes: Option<i64>,
     pub subjects: Vec<String>,
     pub average_rating: Option<f64>,
     pub year_published: Option<i16>,
     pub date_added: String,
     pub private_notes: Option<String>,
+    /// Days waited at each recorded browse, oldest first, with 0 for
+    /// available. Empty unless a history file is kept.
+    pub wait_history: Vec<i64>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
-struct FormatCache {
-    entries: HashMap<String, Vec<String>>,
+/// How `browse` writes its results.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BrowseFormat {
+    /// A sortable, filterable page
+    #[default]
+    Html,
+    /// One JSON array
+    Json,
+    /// One JSON object per line
+    Ndjson,
+    /// Shelves and subjects are joined with ", "
+    Csv,
+    /// A colored table sized to the terminal
+    Table,
+}
+
+/// A `BrowseResult` flattened for CSV, which has no lists.
+#[derive(Serialize)]
+struct BrowseCsvRow<'a> {
+    title: &'a str,
+    author: &'a str,
+    pages: Option<i64>,
+    goodreads_shelves: String,
+    source: BrowseSource,
+    libby_id: &'a str,
+    also_on: String,
+    goodreads_id: i64,
+    is_available: bool,
+    estimated_wait_days: Option<i64>,
+    holds_count: Option<i64>,
+    owned_copies: Option<i64>,
+    available_copies: Option<i64>,
+    has_kindle: Option<bool>,
+    duration_minutes: Option<i64>,
+    subjects: String,
+    average_rating: Option<f64>,
+    year_published: Option<i16>,
+    date_added: &'a str,
+    private_notes: Option<&'a str>,
+    wait_history: String,
 }
 
-impl FormatCache {
-    async fn load(path: &PathBuf) -> Self {
-        match tokio::fs::read_to_string(path).await {
-            Ok(data) => serde_json::from_str(&data).unwrap_or_default(),
-            Err(_) => Self::default(),
+impl<'a> From<&'a BrowseResult> for BrowseCsvRow<'a> {
+    fn from(r: &'a BrowseResult) -> Self {
+        Self {
+            title: &r.title,
+            author: &r.author,
+            pages: r.pages,
+            goodreads_shelves: r.goodreads_shelves.join(", "),
+            source: r.source,
+            libby_id: &r.source_id,
+            also_on: r.also_on.iter().map(|s| s.to_string()).join(", "),
+            goodreads_id: r.goodreads_id,
+            is_available: r.is_available,
+            estimated_wait_days: r.estimated_wait_days,
+            holds_count: r.holds_count,
+            owned_copies: r.owned_copies,
+            available_copies: r.available_copies,
+            has_kindle: r.has_kindle,
+            duration_minutes: r.duration_minutes,
+            subjects: r.subjects.join(", "),
+            average_rating: r.average_rating,
+            year_published: r.year_published,
+            date_added: &r.date_added,
+            private_notes: r.private_notes.as_deref(),
+            wait_history: r.wait_history.iter().map(|w| w.to_string()).join(" "),
         }
     }
+}
 
-    async fn save(&self, path: &PathBuf) -> Result<()> {
-        let data = serde_json::to_string_pretty(self)?;
-        tokio::fs::write(path, data).await?;
-        Ok(())
-    }
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct FormatCache {
+    entries: HashMap<String, Vec<String>>,
+    /// Audiobook lengths in minutes, None for titles Libby has no length for
+    #[serde(default)]
+    durations: HashMap<String, Option<i64>>,
 }
 
 pub struct BrowseArgs {
     pub goodreads_export_csv: PathBuf,
     pub card_id: String,
     pub goodreads_shelf: String,
+    pub book_type: BookType,
     pub tags: Vec<String>,
     pub min_pages: Option<i64>,
     pub max_pages: Option<i64>,
-    pub output: PathBuf,
+    /// Audiobook length filters. Titles of unknown length are kept.
+    pub min_hours: Option<f64>,
+    pub max_hours: Option<f64>,
+    pub format: BrowseFormat,
+    /// Where to write the results. Defaults to browse.html for HTML and
+    /// stdout for the other formats.
+    pub output: Option<PathBuf>,
     pub cache_file: PathBuf,
+    pub request_policy: RequestPolicy,
+    pub libby_endpoints: LibbyEndpoints,
+    /// Also search Hoopla, at this base URL
+    pub hoopla_base: Option<String>,
+    pub hoopla_kind: HooplaKind,
+    /// Fill in missing pages, years and ISBNs from Open Library at this base
+    /// URL, before the page filters and the Hoopla ISBN match
+    pub openlibrary_base: Option<String>,
+    pub openlibrary_cache_file: PathBuf,
+    /// Serve the page on this address instead of writing it
+    pub serve: Option<SocketAddr>,
+    /// Record each run's availability here and show the wait trends
+    pub history_file: Option<PathBuf>,
+    /// Keep only titles that can be borrowed now
+    pub available_only: bool,
+    /// Keep only titles that can be sent to a Kindle
+    pub kindle_only: bool,
 }
 
+/// Search the library for the books on a Goodreads shelf and write the
+/// matches out in `args.format`, or serve them when `args.serve` is set.
 pub async fn browse(args: BrowseArgs, libby_conf_file: PathBuf) -> Result<()> {
-    let libby_client = LibbyClient::new(libby_conf_file, args.card_id)
-        .await
-        .context("client creation")?;
+    let libby_client = LibbyClient::new_with_endpoints(
+        libby_conf_file,
+        args.card_id.clone(),
+        args.libby_endpoints.clone(),
+    )
+    .await
+    .context("client creation")?
+    .with_request_policy(args.request_policy.clone());
     eprintln!("Client setup: {}", libby_client);
 
+    let mut results = find_results(&libby_client, &args).await?;
+    eprintln!("Requests: {}.", libby_client.request_stats());
+    record_history(&args, &mut results)?;
+    if let Some(addr) = args.serve {
+        return browse_server::serve(addr, libby_client, args, results).await;
+    }
+
+    // 7. Filter, render and write
+    filter_results(&mut results, &args);
+    let rendered = render(&results, args.format, args.book_type)?;
+    let output = args
+        .output
+        .clone()
+        .or_else(|| (args.format == BrowseFormat::Html).then(|| PathBuf::from("browse.html")));
+    match output {
+        Some(output) => {
+            tokio::fs::write(&output, rendered).await?;
+            eprintln!("Wrote {}", output.display());
+        }
+        None => print!("{}", rendered),
+    }
+
+    Ok(())
+}
+
+/// Record the results in the history file, if one is kept, and give them
+/// their wait trends.
+pub(crate) fn record_history(args: &BrowseArgs, results: &mut [BrowseResult]) -> Result<()> {
+    if let Some(history_file) = &args.history_file {
+        AvailabilityHistory::open(history_file)?.record_and_annotate(results)?;
+    }
+    Ok(())
+}
+
+/// Steps 1-6 of `browse` against Libby, and Hoopla when asked to, sorted.
+pub(crate) async fn find_results(
+    libby_client: &LibbyClient,
+    args: &BrowseArgs,
+) -> Result<Vec<BrowseResult>> {
+    let books = load_shelf(args).await?;
+    let mut results = match &args.hoopla_base {
+        Some(hoopla_base) => {
+            let hoopla = HooplaClient::new(hoopla_base.as_str())?
+                .with_request_policy(args.request_policy.clone());
+            let (libby_results, hoopla_results) = futures::join!(
+                search_catalog(libby_client, args, &books),
+                search_hoopla(&hoopla, args.hoopla_kind, &books)
+            );
+            merge_sources(libby_results?, hoopla_results)
+        }
+        None => search_catalog(libby_client, args, &books).await?,
+    };
+    sort_results(&mut results, args.book_type);
+    eprintln!(
+        "Generated browse page: {} books ({} available now)",
+        results.len(),
+        results.iter().filter(|r| r.is_available).count()
+    );
+    Ok(results)
+}
+
+/// Steps 1-6 of `browse`: the books on the shelf that the catalog has,
+/// available ones first.
+pub async fn browse_catalog<C: LibraryCatalog>(
+    catalog: &C,
+    args: &BrowseArgs,
+) -> Result<Vec<BrowseResult>> {
+    let books = load_shelf(args).await?;
+    let mut results = search_catalog(catalog, args, &books).await?;
+    sort_results(&mut results, args.book_type);
+    Ok(results)
+}
+
+/// Steps 1-3: the books on the shelf that pass the tag and page filters,
+/// enriched from Open Library when asked to.
+async fn load_shelf(args: &BrowseArgs) -> Result<Vec<goodreads::BookInfo>> {
     // 1. Parse Goodreads CSV
     let books = goodreads::get_book_titles_from_goodreads_shelf(
-        args.goodreads_export_csv,
+        args.goodreads_export_csv.clone(),
         &args.goodreads_shelf,
     )
     .await
@@ -87,7 +295,7 @@ pub async fn browse(args: BrowseArgs, libby_conf_file: PathBuf) -> Result<()> {
     );
 
     // 2. Filter by tags
-    let books: Vec<_> = books
+    let mut books: Vec<_> = books
         .into_iter()
         .filter(|b| args.tags.iter().all(|tag| b.bookshelves.contains(tag)))
         .collect();
@@ -95,6 +303,14 @@ pub async fn browse(args: BrowseArgs, libby_conf_file: PathBuf) -> Result<()> {
         info!("After tag filter ({:?}): {} books", args.tags, books.len());
     }
 
+    if let Some(openlibrary_base) = &args.openlibrary_base {
+        OpenLibraryClient::new(openlibrary_base.as_str())?
+            .with_request_policy(args.request_policy.clone())
+            .enrich(&mut books, &args.openlibrary_cache_file)
+            .await
+            .context("enriching from open library")?;
+    }
+
     // 3. Filter by page count
     let books: Vec<_> = books
         .into_iter()
@@ -107,29 +323,29 @@ pub async fn browse(args: BrowseArgs, libby_conf_file: PathBuf) -> Result<()> {
         )
         .collect();
     info!("After page filter: {} books", books.len());
+    Ok(books)
+}
 
+/// Steps 4-6: search the catalog for the books and build results.
+async fn search_catalog<C: LibraryCatalog>(
+    catalog: &C,
+    args: &BrowseArgs,
+    books: &[goodreads::BookInfo],
+) -> Result<Vec<BrowseResult>> {
     // 4. Search Libby in parallel
-    eprintln!("Searching Libby for {} ebooks...", books.len());
-    let lc = &libby_client;
-    let search_results: Vec<_> = futures::stream::iter(books.iter().map(|book| async move {
-        let result = lc
-            .search_for_book_details(
-                SearchOptions {
-                    book_type: BookType::Ebook,
-                    deep_search: true,
-                    max_results: 24,
-                },
-                &book.title,
-                Some(&book.authors),
-            )
-            .await;
-        (book, result)
-    }))
-    .buffer_unordered(25)
-    .collect()
+    eprintln!("Searching Libby for {} {}s...", books.len(), args.book_type);
+    let search_results = search_shelf(
+        catalog,
+        SearchOptions {
+            book_type: args.book_type,
+            deep_search: true,
+            max_results: 24,
+        },
+        books,
+    )
     .await;
 
-    let mut found: Vec<(&goodreads::BookInfo, crate::libby::LibbySearchResultItem)> = Vec::new();
+    let mut found: Vec<(&goodreads::BookInfo, LibbySearchResultItem)> = Vec::new();
     let mut not_found = 0usize;
     for (book, result) in search_results {
         match result {
@@ -148,51 +364,62 @@ pub async fn browse(args: BrowseArgs, libby_conf_file: PathBuf) -> Result<()> {
     );
 
     // 5. Load format cache and fetch missing
-    let mut cache = FormatCache::load(&args.cache_file).await;
+    let mut cache: FormatCache = json_state::load(&args.cache_file).await;
+    // Audiobooks cached before lengths were kept have formats but no length
+    let needs_duration = matches!(args.book_type, BookType::Audiobook);
     let uncached: Vec<&str> = found
         .iter()
-        .filter(|(_, item)| !cache.entries.contains_key(&item.id))
+        .filter(|(_, item)| {
+            !cache.entries.contains_key(&item.id)
+                || needs_duration && !cache.durations.contains_key(&item.id)
+        })
         .map(|(_, item)| item.id.as_str())
         .collect();
 
     if !uncached.is_empty() {
         eprintln!("Fetching format details for {} books...", uncached.len());
-        let format_results: Vec<_> = futures::stream::iter(uncached.into_iter().map(|id| {
-            let lc = &libby_client;
-            async move {
-                let formats = lc.get_book_formats(id).await;
-                (id.to_string(), formats)
-            }
-        }))
+        let format_results: Vec<_> = futures::stream::iter(
+            uncached
+                .into_iter()
+                .map(|id| async move {
+                    let details = catalog.get_book_details(id).await;
+                    (id.to_string(), details)
+                })
+                .collect::<Vec<_>>(),
+        )
         .buffer_unordered(10)
         .collect()
         .await;
 
-        for (id, formats) in format_results {
-            match formats {
-                Ok(f) => {
-                    cache.entries.insert(id, f);
+        for (id, details) in format_results {
+            match details {
+                Ok(details) => {
+                    cache.durations.insert(id.clone(), details.duration_minutes);
+                    cache.entries.insert(id, details.formats);
                 }
                 Err(e) => {
                     warn!("Failed to fetch formats for {}: {:?}", id, e);
                 }
             }
         }
-        cache.save(&args.cache_file).await?;
+        json_state::save(&cache, &args.cache_file).await?;
     }
 
-    // 6. Build results
-    let mut results: Vec<BrowseResult> = found
+    // 6. Build results, filtering by length
+    let results: Vec<BrowseResult> = found
         .into_iter()
         .map(|(book, item)| {
             let formats = cache.entries.get(&item.id);
             let has_kindle = formats.map(|f| f.iter().any(|fmt| fmt == "ebook-kindle"));
+            let duration_minutes = cache.durations.get(&item.id).copied().flatten();
             BrowseResult {
                 title: item.sort_title,
                 author: item.first_creator_name,
                 pages: book.number_of_pages,
                 goodreads_shelves: book.bookshelves.clone(),
-                libby_id: item.id,
+                source: BrowseSource::Libby,
+                source_id: item.id,
+                also_on: vec![],
                 goodreads_id: book.book_id,
                 is_available: item.is_available,
                 estimated_wait_days: item.estimated_wait_days,
@@ -200,41 +427,261 @@ pub async fn browse(args: BrowseArgs, libby_conf_file: PathBuf) -> Result<()> {
                 owned_copies: item.owned_copies,
                 available_copies: item.available_copies,
                 has_kindle,
+                duration_minutes,
                 subjects: item.subjects.into_iter().map(|s| s.name).collect(),
                 average_rating: book.average_rating,
                 year_published: book.year_published,
                 date_added: book.date_added.clone(),
                 private_notes: book.private_notes.clone(),
+                wait_history: vec![],
             }
         })
+        .filter(|r| within_hours(args, r.duration_minutes))
         .collect();
+    if args.min_hours.is_some() || args.max_hours.is_some() {
+        info!("After hours filter: {} books", results.len());
+    }
+    Ok(results)
+}
 
-    // Sort: available first, then by pages ascending
-    results.sort_by(|a, b| {
-        b.is_available.cmp(&a.is_available).then_with(|| {
-            a.pages
-                .unwrap_or(i64::MAX)
-                .cmp(&b.pages.unwrap_or(i64::MAX))
+fn within_hours(args: &BrowseArgs, duration_minutes: Option<i64>) -> bool {
+    let Some(minutes) = duration_minutes else {
+        return true;
+    };
+    let hours = minutes as f64 / 60.0;
+    args.min_hours.is_none_or(|min| hours >= min) && args.max_hours.is_none_or(|max| hours <= max)
+}
+
+/// Search Hoopla for the books. Failed searches are logged and skipped.
+async fn search_hoopla(
+    hoopla: &HooplaClient,
+    kind: HooplaKind,
+    books: &[goodreads::BookInfo],
+) -> Vec<BrowseResult> {
+    eprintln!("Searching Hoopla for {} {}s...", books.len(), kind);
+    let search_results: Vec<_> = futures::stream::iter(
+        books
+            .iter()
+            .map(|book| async move {
+                let found = hoopla
+                    .find_book(&book.title, &book.authors, Some(&book.isbn), kind)
+                    .await;
+                (book, found)
+            })
+            .collect::<Vec<_>>(),
+    )
+    .buffer_unordered(10)
+    .collect()
+    .await;
+
+    let results: Vec<BrowseResult> = search_results
+        .into_iter()
+        .filter_map(|(book, found)| match found {
+            Ok(Some(title)) => Some(BrowseResult {
+                author: title.author().to_string(),
+                title: title.title,
+                pages: book.number_of_pages.or(title.pages),
+                goodreads_shelves: book.bookshelves.clone(),
+                source: BrowseSource::Hoopla,
+                source_id: title.id,
+                also_on: vec![],
+                goodreads_id: book.book_id,
+                is_available: true,
+                estimated_wait_days: None,
+                holds_count: None,
+                owned_copies: None,
+                available_copies: None,
+                has_kindle: None,
+                duration_minutes: None,
+                subjects: vec![],
+                average_rating: book.average_rating,
+                year_published: book.year_published.or(title.year),
+                date_added: book.date_added.clone(),
+                private_notes: book.private_notes.clone(),
+                wait_history: vec![],
+            }),
+            Ok(None) => {
+                debug!("Not found in Hoopla: '{}'", book.title);
+                None
+            }
+            Err(e) => {
+                warn!("Hoopla search for '{}' failed: {:?}", book.title, e);
+                None
+            }
         })
+        .collect();
+    eprintln!("Found {} of {} books in Hoopla", results.len(), books.len());
+    results
+}
+
+/// One result per Goodreads book: Hoopla finds of books Libby also has are
+/// noted on the Libby result, which keeps the holds and history, instead of
+/// being listed twice.
+fn merge_sources(mut libby: Vec<BrowseResult>, hoopla: Vec<BrowseResult>) -> Vec<BrowseResult> {
+    let by_book: HashMap<i64, usize> = libby
+        .iter()
+        .enumerate()
+        .map(|(i, r)| (r.goodreads_id, i))
+        .collect();
+    let mut only_hoopla = vec![];
+    for result in hoopla {
+        match by_book.get(&result.goodreads_id) {
+            Some(&i) => libby[i].also_on.push(result.source),
+            None => only_hoopla.push(result),
+        }
+    }
+    libby.extend(only_hoopla);
+    libby
+}
+
+/// Available first, then shortest first: by length for audiobooks, by
+/// pages otherwise.
+fn sort_results(results: &mut [BrowseResult], book_type: BookType) {
+    let length = |r: &BrowseResult| {
+        match book_type {
+            BookType::Audiobook => r.duration_minutes,
+            BookType::Ebook => r.pages,
+        }
+        .unwrap_or(i64::MAX)
+    };
+    results.sort_by(|a, b| {
+        b.is_available
+            .cmp(&a.is_available)
+            .then_with(|| length(a).cmp(&length(b)))
     });
+}
 
-    let available_count = results.iter().filter(|r| r.is_available).count();
-    eprintln!(
-        "Generated browse page: {} books ({} available now)",
-        results.len(),
-        available_count
-    );
+/// Drop the results `--available-only` and `--kindle-only` rule out, the
+/// same way the page's checkboxes do.
+fn filter_results(results: &mut Vec<BrowseResult>, args: &BrowseArgs) {
+    results.retain(|r| {
+        (!args.available_only || r.is_available)
+            && (!args.kindle_only || r.has_kindle == Some(true))
+    });
+}
 
-    // 7. Render and write HTML
-    let html = render_html(&results);
-    tokio::fs::write(&args.output, html).await?;
-    eprintln!("Wrote {}", args.output.display());
+/// Serialize the results in `format`.
+pub fn render(
+    results: &[BrowseResult],
+    format: BrowseFormat,
+    book_type: BookType,
+) -> Result<String> {
+    Ok(match format {
+        BrowseFormat::Html => render_html(results, book_type, None),
+        BrowseFormat::Json => serde_json::to_string_pretty(results)? + "\n",
+        BrowseFormat::Ndjson => results
+            .iter()
+            .map(|r| serde_json::to_string(r).map(|line| line + "\n"))
+            .collect::<serde_json::Result<String>>()?,
+        BrowseFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            for result in results {
+                writer.serialize(BrowseCsvRow::from(result))?;
+            }
+            String::from_utf8(writer.into_inner()?)?
+        }
+        BrowseFormat::Table => render_table(results, book_type, terminal_width()),
+    })
+}
 
-    Ok(())
+/// Width from `COLUMNS`, as shells set it, or a conservative default.
+fn terminal_width() -> usize {
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(100)
 }
 
-fn render_html(results: &[BrowseResult]) -> String {
-    let json_data = serde_json::to_string(results).unwrap_or_else(|_| "[]".to_string());
+/// Pad or cut `text` to exactly `width` characters.
+fn fit(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        format!("{:<width$}", text)
+    } else {
+        let cut: String = text.chars().take(width.saturating_sub(1)).collect();
+        format!("{}…", cut)
+    }
+}
+
+/// One line per result, title and author sharing what the fixed-width
+/// columns leave of `width`.
+fn render_table(results: &[BrowseResult], book_type: BookType, width: usize) -> String {
+    const GAP: &str = "  ";
+    let show_length = matches!(book_type, BookType::Audiobook);
+    let mut fixed = [("status", 9), ("wait", 5), ("pages", 5), ("rating", 6)].to_vec();
+    if show_length {
+        fixed.push(("length", 6));
+    }
+    fixed.push(("kindle", 6));
+    let used: usize = fixed.iter().map(|(_, w)| w + GAP.len()).sum::<usize>() + GAP.len();
+    let flex = width.saturating_sub(used).max(20);
+    let title_width = flex * 3 / 5;
+    let author_width = flex - title_width;
+
+    let mut out = String::new();
+    let mut header = vec![fit("title", title_width), fit("author", author_width)];
+    header.extend(fixed.iter().map(|(name, w)| fit(name, *w)));
+    out.push_str(&header.join(GAP).dimmed().to_string());
+    out.push('\n');
+
+    let missing = |w: usize| fit("-", w).dimmed().to_string();
+    for r in results {
+        let status = if r.source == BrowseSource::Hoopla {
+            fit("instant", 9).green()
+        } else if !r.is_available && r.also_on.contains(&BrowseSource::Hoopla) {
+            fit("hoopla", 9).green()
+        } else if r.is_available {
+            fit("available", 9).green()
+        } else if r.estimated_wait_days.is_some() {
+            fit("wait", 9).yellow()
+        } else {
+            fit("waitlist", 9).dimmed()
+        };
+        let mut row = vec![
+            fit(&r.title, title_width).bold().to_string(),
+            fit(&r.author, author_width),
+            status.to_string(),
+            r.estimated_wait_days
+                .filter(|_| !r.is_available)
+                .map(|d| fit(&format!("{}d", d), 5).yellow().to_string())
+                .unwrap_or_else(|| missing(5)),
+            r.pages
+                .map(|p| format!("{:>5}", p))
+                .unwrap_or_else(|| missing(5)),
+            r.average_rating
+                .map(|a| format!("{:>6.2}", a).yellow().to_string())
+                .unwrap_or_else(|| missing(6)),
+        ];
+        if show_length {
+            row.push(
+                r.duration_minutes
+                    .map(|m| format!("{:>6}", format!("{}h{:02}", m / 60, m % 60)))
+                    .unwrap_or_else(|| missing(6)),
+            );
+        }
+        row.push(match r.has_kindle {
+            Some(true) => fit("kindle", 6).red().to_string(),
+            Some(false) => fit("no", 6),
+            None => missing(6),
+        });
+        out.push_str(row.join(GAP).trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// The browse page. With an `api_token`, it is served by `browse_server` and
+/// each Libby row gets buttons that call its API, sending the token along.
+pub(crate) fn render_html(
+    results: &[BrowseResult],
+    book_type: BookType,
+    api_token: Option<&str>,
+) -> String {
+    // A title containing "</script>" must not end the script early
+    let json_data = serde_json::to_string(results)
+        .unwrap_or_else(|_| "[]".to_string())
+        .replace("</", "<\\/");
+    let live = api_token.is_some();
+    let api_token = api_token.unwrap_or_default();
     let available_count = results.iter().filter(|r| r.is_available).count();
 
     format!(
@@ -243,7 +690,7 @@ fn render_html(results: &[BrowseResult]) -> String {
 <head>
 <meta charset="utf-8">
 <meta name="viewport" content="width=device-width, initial-scale=1">
-<title>browse // libby ebooks</title>
+<title>browse // libby {book_type}s</title>
 <style>
 * {{ box-sizing: border-box; margin: 0; padding: 0; }}
 body {{
@@ -359,6 +806,18 @@ tr:hover td {{ background: #111; }}
 .unavail {{ color: #5f5f5f; }}
 .kindle {{ color: #d75f5f; }}
 .sort-arrow {{ font-size: 10px; margin-left: 4px; }}
+.act {{
+  background: none;
+  border: 1px solid #333;
+  color: #888;
+  font-family: inherit;
+  font-size: 11px;
+  padding: 1px 6px;
+  margin: 1px 2px;
+  cursor: pointer;
+}}
+.act:hover {{ color: #5faf5f; border-color: #5faf5f; }}
+.act:disabled {{ color: #444; cursor: wait; }}
 .col-hidden {{ display: none; }}
 .gear-wrapper {{
   position: relative;
@@ -402,10 +861,11 @@ tr:hover td {{ background: #111; }}
 <body>
 
 <div class="header">
-  <h1>&gt; browse // libby ebooks</h1>
+  <h1>&gt; browse // libby {book_type}s</h1>
   <div class="stats">
     <span id="shown-count">{total}</span> of {total} books shown
     &middot; <span>{available}</span> available now
+    <button class="act" id="refresh-all" style="display:none">refresh all</button>
     <span class="gear-wrapper">
       <button class="gear-btn" id="gear-btn" title="Column settings">&#9881;</button>
       <div class="gear-panel" id="gear-panel"></div>
@@ -450,14 +910,18 @@ tr:hover td {{ background: #111; }}
       <th data-sort="title" data-col="title">title<span class="sort-arrow"></span></th>
       <th data-sort="author" data-col="author">author<span class="sort-arrow"></span></th>
       <th data-sort="pages" data-col="pages">pages<span class="sort-arrow"></span></th>
+      <th data-sort="duration" data-col="duration">length<span class="sort-arrow"></span></th>
       <th data-sort="rating" data-col="rating">rating<span class="sort-arrow"></span></th>
       <th data-col="shelves">shelves</th>
       <th data-col="subjects">subjects</th>
       <th data-sort="year" data-col="year">year<span class="sort-arrow"></span></th>
       <th data-sort="added" data-col="added">added<span class="sort-arrow"></span></th>
       <th data-col="notes">notes</th>
+      <th data-sort="source" data-col="source">source<span class="sort-arrow"></span></th>
       <th data-sort="available" data-col="status">status<span class="sort-arrow"></span></th>
+      <th data-col="trend">wait trend</th>
       <th data-col="link">link</th>
+      <th data-col="actions">actions</th>
     </tr>
   </thead>
   <tbody id="book-table"></tbody>
@@ -465,6 +929,13 @@ tr:hover td {{ background: #111; }}
 
 <script>
 const DATA = {json_data};
+const LIVE = {live};
+const API_TOKEN = "{api_token}";
+
+function esc(s) {{
+  return String(s).replace(/[&<>"']/g, c =>
+    ({{ "&": "&amp;", "<": "&lt;", ">": "&gt;", '"': "&quot;", "'": "&#39;" }})[c]);
+}}
 
 let sortCol = "available";
 let sortAsc = false;
@@ -475,7 +946,7 @@ const allSubjects = [...new Set(DATA.flatMap(b => b.subjects))].sort();
 function initShelves() {{
   const el = document.getElementById("shelf-filters");
   el.innerHTML = allShelves.map(s =>
-    `<span class="shelf-tag" data-shelf="${{s}}">${{s}}</span>`
+    `<span class="shelf-tag" data-shelf="${{esc(s)}}">${{esc(s)}}</span>`
   ).join("");
   el.querySelectorAll(".shelf-tag").forEach(t =>
     t.addEventListener("click", () => {{ t.classList.toggle("active"); render(); }})
@@ -485,7 +956,7 @@ function initShelves() {{
 function initSubjects() {{
   const el = document.getElementById("subject-filters");
   el.innerHTML = allSubjects.map(s =>
-    `<span class="shelf-tag subject-tag" data-subject="${{s}}">${{s}}</span>`
+    `<span class="shelf-tag subject-tag" data-subject="${{esc(s)}}">${{esc(s)}}</span>`
   ).join("");
   el.querySelectorAll(".subject-tag").forEach(t =>
     t.addEventListener("click", () => {{ t.classList.toggle("active"); render(); }})
@@ -507,9 +978,11 @@ function sortData(data) {{
       case "title": va = a.title.toLowerCase(); vb = b.title.toLowerCase(); break;
       case "author": va = a.author.toLowerCase(); vb = b.author.toLowerCase(); break;
       case "pages": va = a.pages || 99999; vb = b.pages || 99999; break;
+      case "duration": va = a.duration_minutes || 99999; vb = b.duration_minutes || 99999; break;
       case "rating": va = a.average_rating || 0; vb = b.average_rating || 0; break;
       case "year": va = a.year_published || 0; vb = b.year_published || 0; break;
       case "added": va = a.date_added; vb = b.date_added; break;
+      case "source": va = a.source; vb = b.source; break;
       case "available":
         va = a.is_available ? 0 : (a.estimated_wait_days || 999);
         vb = b.is_available ? 0 : (b.estimated_wait_days || 999);
@@ -522,6 +995,14 @@ function sortData(data) {{
   }});
 }}
 
+const SPARKS = "\u2581\u2582\u2583\u2584\u2585\u2586\u2587\u2588";
+
+function sparkline(values) {{
+  const min = Math.min(...values), max = Math.max(...values);
+  const range = Math.max(max - min, 1);
+  return values.map(v => SPARKS[Math.floor((v - min) * (SPARKS.length - 1) / range)]).join("");
+}}
+
 function render() {{
   const search = document.getElementById("search").value.toLowerCase();
   const minP = parseInt(document.getElementById("min-pages").value) || 0;
@@ -553,9 +1034,11 @@ function render() {{
 
   const tbody = document.getElementById("book-table");
   tbody.innerHTML = filtered.map(b => {{
-    const shelves = b.goodreads_shelves.map(s => `<span class="badge">${{s}}</span>`).join("");
+    const shelves = b.goodreads_shelves.map(s => `<span class="badge">${{esc(s)}}</span>`).join("");
     let status;
-    if (b.is_available) {{
+    if (b.source === "hoopla") {{
+      status = `<span class="avail">instant</span>`;
+    }} else if (b.is_available) {{
       status = `<span class="avail">available</span>`;
     }} else if (b.estimated_wait_days != null) {{
       status = `<span class="wait">~${{b.estimated_wait_days}}d wait</span>`;
@@ -565,29 +1048,48 @@ function render() {{
     if (b.holds_count != null) {{
       status += `<br><span style="color:#555;font-size:11px">${{b.holds_count}} holds / ${{b.owned_copies || "?"}} copies</span>`;
     }}
+    if (b.also_on.includes("hoopla")) {{
+      status += `<br><span class="avail">instant on hoopla</span>`;
+    }}
     if (b.has_kindle === true) {{
       status += `<br><span class="kindle">kindle</span>`;
     }}
     const pages = b.pages != null ? b.pages : `<span style="color:#333">-</span>`;
+    const duration = b.duration_minutes != null
+      ? `${{Math.floor(b.duration_minutes / 60)}}h ${{String(b.duration_minutes % 60).padStart(2, "0")}}m`
+      : `<span style="color:#333">-</span>`;
     const rating = b.average_rating != null
       ? `<span class="rating">${{b.average_rating.toFixed(2)}}</span>`
       : `<span style="color:#333">-</span>`;
-    const subjects = b.subjects.map(s => `<span class="badge">${{s}}</span>`).join("");
+    const subjects = b.subjects.map(s => `<span class="badge">${{esc(s)}}</span>`).join("");
     const year = b.year_published != null ? b.year_published : `<span style="color:#333">-</span>`;
     const added = b.date_added || `<span style="color:#333">-</span>`;
-    const notes = b.private_notes ? b.private_notes : `<span style="color:#333">-</span>`;
+    const notes = b.private_notes ? esc(b.private_notes) : `<span style="color:#333">-</span>`;
+    const waits = b.wait_history;
+    const trend = waits.length > 1
+      ? `<span class="${{waits[waits.length - 1] < waits[0] ? "avail" : "wait"}}" title="${{waits.join("d, ")}}d">${{sparkline(waits)}}</span>`
+      : `<span style="color:#333">-</span>`;
+    const actions = LIVE && b.source === "libby"
+      ? ["availability", "tag", "hold", "borrow"].map(act =>
+          `<button class="act" data-act="${{act}}" data-id="${{esc(b.libby_id)}}">${{act === "availability" ? "refresh" : act}}</button>`
+        ).join("")
+      : "";
     return `<tr>
-      <td data-col="title">${{b.title}}</td>
-      <td data-col="author">${{b.author}}</td>
+      <td data-col="title">${{esc(b.title)}}</td>
+      <td data-col="author">${{esc(b.author)}}</td>
       <td data-col="pages">${{pages}}</td>
+      <td data-col="duration">${{duration}}</td>
       <td data-col="rating">${{rating}}</td>
       <td data-col="shelves">${{shelves}}</td>
       <td data-col="subjects">${{subjects}}</td>
       <td data-col="year">${{year}}</td>
       <td data-col="added">${{added}}</td>
       <td data-col="notes">${{notes}}</td>
+      <td data-col="source"><span class="badge">${{b.source}}</span></td>
       <td data-col="status">${{status}}</td>
+      <td data-col="trend">${{trend}}</td>
       <td data-col="link"><a href="https://www.goodreads.com/book/show/${{b.goodreads_id}}" target="_blank">open</a></td>
+      <td data-col="actions">${{actions}}</td>
     </tr>`;
   }}).join("");
   if (typeof applyColVisibility === "function") applyColVisibility();
@@ -608,6 +1110,51 @@ document.querySelectorAll("th[data-sort]").forEach(th => {{
   document.getElementById(id).addEventListener("change", render)
 );
 
+if (LIVE) {{
+  const refreshAll = document.getElementById("refresh-all");
+  refreshAll.style.display = "";
+  refreshAll.addEventListener("click", async () => {{
+    refreshAll.disabled = true;
+    const resp = await fetch("/api/refresh", {{
+      method: "POST",
+      headers: {{ "X-Browse-Token": API_TOKEN }},
+    }});
+    refreshAll.disabled = false;
+    if (!resp.ok) {{ alert(`refresh failed: ${{await resp.text()}}`); return; }}
+    DATA.splice(0, DATA.length, ...(await resp.json()));
+    render();
+  }});
+
+  document.getElementById("book-table").addEventListener("click", async e => {{
+    const btn = e.target.closest("button[data-act]");
+    if (!btn) return;
+    const {{ act, id }} = btn.dataset;
+    let body;
+    if (act === "tag") {{
+      const tag = prompt("Tag name", localStorage.getItem("browse-tag") || "");
+      if (!tag) return;
+      localStorage.setItem("browse-tag", tag);
+      body = JSON.stringify({{ tag }});
+    }}
+    btn.disabled = true;
+    const resp = await fetch(`/api/titles/${{id}}/${{act}}`, {{
+      method: "POST",
+      headers: {{ "Content-Type": "application/json", "X-Browse-Token": API_TOKEN }},
+      body,
+    }});
+    btn.disabled = false;
+    if (!resp.ok) {{ alert(`${{act}} failed: ${{await resp.text()}}`); return; }}
+    if (act === "availability") {{
+      const row = await resp.json();
+      const i = DATA.findIndex(b => b.source === "libby" && b.libby_id === id);
+      if (i >= 0) DATA[i] = row;
+      render();
+    }} else {{
+      btn.textContent = `${{act}} \u2713`;
+    }}
+  }});
+}}
+
 document.getElementById("subjects-toggle").addEventListener("click", () => {{
   const el = document.getElementById("subject-filters");
   const arrow = document.getElementById("subjects-arrow");
@@ -619,14 +1166,18 @@ const COLUMNS = [
   {{ key: "title", label: "Title", defaultOn: true }},
   {{ key: "author", label: "Author", defaultOn: true }},
   {{ key: "pages", label: "Pages", defaultOn: true }},
+  {{ key: "duration", label: "Length", defaultOn: true }},
   {{ key: "rating", label: "Rating", defaultOn: true }},
   {{ key: "shelves", label: "Shelves", defaultOn: true }},
   {{ key: "subjects", label: "Subjects", defaultOn: false }},
   {{ key: "year", label: "Year", defaultOn: true }},
   {{ key: "added", label: "Added", defaultOn: true }},
   {{ key: "notes", label: "Notes", defaultOn: false }},
+  {{ key: "source", label: "Source", defaultOn: true }},
   {{ key: "status", label: "Status", defaultOn: true }},
+  {{ key: "trend", label: "Wait trend", defaultOn: true }},
   {{ key: "link", label: "Link", defaultOn: true }},
+  {{ key: "actions", label: "Actions", defaultOn: LIVE }},
 ];
 const STORAGE_KEY = "browse-col-visibility";
 
@@ -690,3 +1241,248 @@ applyColVisibility();
         json_data = json_data,
     )
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::catalog::InMemoryLibrary;
+    use crate::fake_libby;
+    use crate::fake_libby::FakeLibby;
+    use crate::fake_libby::browse_args;
+
+    fn out_dir(name: &str, port: u16) -> PathBuf {
+        let out_dir =
+            std::env::temp_dir().join(format!("gr2libby-{}-{}-{}", name, std::process::id(), port));
+        std::fs::create_dir_all(&out_dir).expect("create output dir");
+        out_dir
+    }
+
+    #[tokio::test]
+    async fn test_browse_against_fake_libby() {
+        let fake = FakeLibby::start().await;
+        let out_dir = out_dir("browse", fake.server.address().port());
+
+        browse(
+            browse_args(&out_dir, fake.endpoints()),
+            fake.conf_file.clone(),
+        )
+        .await
+        .expect("browse");
+
+        let html = std::fs::read_to_string(out_dir.join("browse.html")).expect("read html");
+        assert!(html.contains(fake_libby::FOUND_TITLE));
+        assert!(!html.contains("A Book No Library Has"));
+        let cache = std::fs::read_to_string(out_dir.join("browse_cache.json")).expect("cache");
+        assert!(cache.contains("ebook-kindle"));
+        let _ = std::fs::remove_dir_all(&out_dir);
+    }
+
+    fn result(title: &str, shelves: &[&str]) -> BrowseResult {
+        BrowseResult {
+            title: title.to_string(),
+            author: "Andy Weir".to_string(),
+            pages: Some(476),
+            goodreads_shelves: shelves.iter().map(|s| s.to_string()).collect(),
+            source: BrowseSource::Libby,
+            source_id: "2002".to_string(),
+            also_on: vec![],
+            goodreads_id: 54493401,
+            is_available: true,
+            estimated_wait_days: None,
+            holds_count: Some(0),
+            owned_copies: Some(3),
+            available_copies: Some(1),
+            has_kindle: Some(true),
+            duration_minutes: None,
+            subjects: vec!["Science Fiction".to_string()],
+            average_rating: Some(4.52),
+            year_published: Some(2021),
+            date_added: "2024/01/05".to_string(),
+            private_notes: None,
+            wait_history: vec![],
+        }
+    }
+
+    #[test]
+    fn test_render_formats() {
+        let results = [
+            result("Project Hail Mary", &["to-read", "sci-fi"]),
+            result("The Martian", &[]),
+        ];
+
+        let json = render(&results, BrowseFormat::Json, BookType::Ebook).expect("json");
+        let parsed: serde_json::Value = serde_json::from_str(&json).expect("parse json");
+        assert_eq!(parsed[0]["title"], "Project Hail Mary");
+        assert_eq!(parsed[0]["source"], "libby");
+        assert_eq!(parsed[1]["goodreads_shelves"], serde_json::json!([]));
+
+        let ndjson = render(&results, BrowseFormat::Ndjson, BookType::Ebook).expect("ndjson");
+        let lines: Vec<serde_json::Value> = ndjson
+            .lines()
+            .map(|l| serde_json::from_str(l).expect("parse line"))
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["title"], "The Martian");
+
+        let csv = render(&results, BrowseFormat::Csv, BookType::Ebook).expect("csv");
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        let headers = reader.headers().expect("headers").clone();
+        let rows: Vec<csv::StringRecord> = reader.records().map(|r| r.expect("row")).collect();
+        assert_eq!(rows.len(), 2);
+        let shelves = headers
+            .iter()
+            .position(|h| h == "goodreads_shelves")
+            .expect("shelves column");
+        assert_eq!(&rows[0][shelves], "to-read, sci-fi");
+    }
+
+    #[test]
+    fn test_render_html_escapes_book_text() {
+        let mut hostile = result("</script><script>alert(1)</script>", &[]);
+        hostile.private_notes = Some("<img src=x onerror=alert(1)>".to_string());
+        let html = render_html(&[hostile], BookType::Ebook, None);
+        assert!(!html.contains("</script><script>alert(1)"));
+        assert!(html.contains("<\\/script><script>alert(1)<\\/script>"));
+        assert!(html.contains("${esc(b.title)}"));
+        assert!(html.contains("esc(b.private_notes)"));
+        assert!(html.contains("const API_TOKEN = \"\";"));
+    }
+
+    #[test]
+    fn test_merge_sources_lists_each_book_once() {
+        let mut only_hoopla = result("Artemis", &[]);
+        only_hoopla.goodreads_id = 34928122;
+        let hoopla = [result("Project Hail Mary", &[]), only_hoopla]
+            .map(|r| BrowseResult {
+                source: BrowseSource::Hoopla,
+                source_id: "h1".to_string(),
+                ..r
+            })
+            .to_vec();
+
+        let merged = merge_sources(vec![result("Project Hail Mary", &[])], hoopla);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].source, BrowseSource::Libby);
+        assert_eq!(merged[0].also_on, vec![BrowseSource::Hoopla]);
+        assert_eq!(merged[1].title, "Artemis");
+
+        // Still serialized under the name consumers know it by
+        let json = render(&merged, BrowseFormat::Json, BookType::Ebook).expect("json");
+        let parsed: serde_json::Value = serde_json::from_str(&json).expect("parse json");
+        assert_eq!(parsed[0]["libby_id"], "2002");
+        assert_eq!(parsed[0]["also_on"], serde_json::json!(["hoopla"]));
+    }
+
+    #[test]
+    fn test_render_table_fits_width() {
+        colored::control::set_override(false);
+        let mut waiting = result("A Very Long Title That Will Not Fit In The Column", &[]);
+        waiting.is_available = false;
+        waiting.estimated_wait_days = Some(14);
+        waiting.has_kindle = None;
+        let table = render_table(
+            &[result("Project Hail Mary", &[]), waiting],
+            BookType::Ebook,
+            80,
+        );
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|l| l.chars().count() <= 80));
+        assert!(lines[1].starts_with("Project Hail Mary"));
+        assert!(lines[1].contains("available"));
+        assert!(lines[1].contains("4.52"));
+        assert!(lines[1].contains("kindle"));
+        assert!(lines[2].contains('…'));
+        assert!(lines[2].contains("14d"));
+    }
+
+    #[test]
+    fn test_filter_results() {
+        colored::control::set_override(false);
+        let mut waiting = result("Artemis", &[]);
+        waiting.is_available = false;
+        let mut no_kindle = result("The Martian", &[]);
+        no_kindle.has_kindle = None;
+        let all = vec![result("Project Hail Mary", &[]), waiting, no_kindle];
+        let out_dir = out_dir("browse-filter", 0);
+        let mut args = browse_args(&out_dir, LibbyEndpoints::default());
+
+        let mut results = all.clone();
+        filter_results(&mut results, &args);
+        assert_eq!(results.len(), 3);
+
+        args.available_only = true;
+        let mut results = all.clone();
+        filter_results(&mut results, &args);
+        let titles: Vec<_> = results.iter().map(|r| r.title.as_str()).collect();
+        assert_eq!(titles, ["Project Hail Mary", "The Martian"]);
+
+        args.kindle_only = true;
+        let mut results = all;
+        filter_results(&mut results, &args);
+        let table = render_table(&results, BookType::Ebook, 80);
+        assert_eq!(table.lines().count(), 2);
+        assert!(table.contains("Project Hail Mary"));
+    }
+
+    #[tokio::test]
+    async fn test_browse_catalog_filters_audiobook_hours() {
+        let out_dir = out_dir("browse-hours", 0);
+        let library = InMemoryLibrary::new()
+            .with_item(
+                fake_libby::audiobook(fake_libby::item("3003", "Project Hail Mary", "Andy Weir")),
+                &["audiobook-overdrive"],
+            )
+            .with_duration("3003", 970);
+
+        let args = BrowseArgs {
+            book_type: BookType::Audiobook,
+            min_hours: Some(10.0),
+            ..browse_args(&out_dir, LibbyEndpoints::default())
+        };
+        // Cached before lengths were, so the length is fetched again
+        std::fs::write(
+            &args.cache_file,
+            r#"{"entries": {"3003": ["audiobook-overdrive"]}}"#,
+        )
+        .expect("write cache");
+        let results = browse_catalog(&library, &args).await.expect("browse");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].duration_minutes, Some(970));
+
+        let args = BrowseArgs {
+            book_type: BookType::Audiobook,
+            max_hours: Some(10.0),
+            ..browse_args(&out_dir, LibbyEndpoints::default())
+        };
+        assert!(
+            browse_catalog(&library, &args)
+                .await
+                .expect("browse")
+                .is_empty()
+        );
+        let _ = std::fs::remove_dir_all(&out_dir);
+    }
+
+    #[tokio::test]
+    async fn test_browse_catalog_caches_missing_audiobook_hours() {
+        let out_dir = out_dir("browse-no-hours", 0);
+        let library = InMemoryLibrary::new().with_item(
+            fake_libby::audiobook(fake_libby::item("3003", "Project Hail Mary", "Andy Weir")),
+            &["audiobook-overdrive"],
+        );
+        let args = BrowseArgs {
+            book_type: BookType::Audiobook,
+            ..browse_args(&out_dir, LibbyEndpoints::default())
+        };
+
+        let results = browse_catalog(&library, &args).await.expect("browse");
+        assert_eq!(results[0].duration_minutes, None);
+        assert_eq!(library.detail_lookups(), 1);
+        // Having no length is remembered too
+        let results = browse_catalog(&library, &args).await.expect("browse");
+        assert_eq!(results.len(), 1);
+        assert_eq!(library.detail_lookups(), 1);
+        let _ = std::fs::remove_dir_all(&out_dir);
+    }
+}
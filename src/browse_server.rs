@@ -0,0 +1,467 @@
+//! `browse --serve`: the browse page served locally, with buttons on each
+//! Libby row to refresh its availability, tag it, place a hold or borrow it.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::anyhow;
+use anyhow::bail;
+use axum::Json;
+use axum::Router;
+use axum::extract::Path;
+use axum::extract::Request;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::http::header;
+use axum::middleware::Next;
+use axum::middleware::from_fn_with_state;
+use axum::response::Html;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::routing::get;
+use axum::routing::post;
+use serde::Deserialize;
+use tokio::sync::RwLock;
+
+use crate::browse::BrowseArgs;
+use crate::browse::BrowseResult;
+use crate::browse::BrowseSource;
+use crate::browse::find_results;
+use crate::browse::record_history;
+use crate::browse::render_html;
+use crate::libby::LibbyClient;
+use crate::libby::LibbyError;
+
+/// The header the page sends its API token in.
+const TOKEN_HEADER: &str = "x-browse-token";
+
+struct ServerState {
+    libby_client: LibbyClient,
+    args: BrowseArgs,
+    results: RwLock<Vec<BrowseResult>>,
+    /// Embedded in the page and required on every action, so other sites
+    /// open in the same browser can't act on the library account.
+    api_token: String,
+    /// The `Host` values the server answers to: its loopback port by
+    /// address or as localhost. Anything else is a page on another domain
+    /// rebound to this address.
+    allowed_hosts: Vec<String>,
+}
+
+impl ServerState {
+    fn new(
+        libby_client: LibbyClient,
+        args: BrowseArgs,
+        results: Vec<BrowseResult>,
+        local_addr: SocketAddr,
+    ) -> Result<Self> {
+        let port = local_addr.port();
+        Ok(Self {
+            libby_client,
+            args,
+            results: RwLock::new(results),
+            api_token: random_token()?,
+            allowed_hosts: vec![
+                format!("127.0.0.1:{port}"),
+                format!("[::1]:{port}"),
+                format!("localhost:{port}"),
+            ],
+        })
+    }
+}
+
+/// 128 bits from the OS random number generator, as hex.
+fn random_token() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::fill(&mut bytes).map_err(|e| anyhow!("generating API token: {e}"))?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// An error for the page to show, as plain text.
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, self.1).into_response()
+    }
+}
+
+impl From<LibbyError> for ApiError {
+    fn from(e: LibbyError) -> Self {
+        let status = match e {
+            LibbyError::NotFound(_) | LibbyError::TagNotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::BAD_GATEWAY,
+        };
+        Self(status, e.to_string())
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}"))
+    }
+}
+
+#[derive(Deserialize)]
+struct TagRequest {
+    tag: String,
+}
+
+fn router(state: Arc<ServerState>) -> Router {
+    Router::new()
+        .route("/api/refresh", post(refresh))
+        .route("/api/titles/{id}/availability", post(availability))
+        .route("/api/titles/{id}/tag", post(tag))
+        .route("/api/titles/{id}/hold", post(hold))
+        .route("/api/titles/{id}/borrow", post(borrow))
+        // Only the routes above act on the account
+        .route_layer(from_fn_with_state(state.clone(), check_caller))
+        .route("/", get(index))
+        .route("/api/results", get(results))
+        // Every route, the page with its token included
+        .route_layer(from_fn_with_state(state.clone(), check_host))
+        .with_state(state)
+}
+
+/// Refuse requests addressed to any other host name, which is how a
+/// DNS-rebinding page on another domain would reach the server.
+async fn check_host(
+    State(state): State<Arc<ServerState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default();
+    if !state.allowed_hosts.iter().any(|allowed| allowed == host) {
+        return Err(ApiError(
+            StatusCode::FORBIDDEN,
+            format!("requests for host {host:?} are refused"),
+        ));
+    }
+    Ok(next.run(request).await)
+}
+
+/// Refuse actions from other origins, or without the page's token.
+async fn check_caller(
+    State(state): State<Arc<ServerState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let headers = request.headers();
+    if let Some(origin) = headers.get(header::ORIGIN) {
+        let origin_allowed = origin.to_str().is_ok_and(|origin| {
+            state
+                .allowed_hosts
+                .iter()
+                .any(|host| origin == format!("http://{host}"))
+        });
+        if !origin_allowed {
+            return Err(ApiError(
+                StatusCode::FORBIDDEN,
+                format!("requests from {origin:?} are refused"),
+            ));
+        }
+    }
+    let token = headers.get(TOKEN_HEADER).and_then(|t| t.to_str().ok());
+    if token != Some(state.api_token.as_str()) {
+        return Err(ApiError(
+            StatusCode::FORBIDDEN,
+            "missing or stale API token, reload the page".to_string(),
+        ));
+    }
+    Ok(next.run(request).await)
+}
+
+/// Serve the browse page for `results` on `addr` until interrupted. Only
+/// loopback addresses are allowed, as anyone who can load the page can act
+/// on the library account.
+pub(crate) async fn serve(
+    addr: SocketAddr,
+    libby_client: LibbyClient,
+    args: BrowseArgs,
+    results: Vec<BrowseResult>,
+) -> Result<()> {
+    if !addr.ip().is_loopback() {
+        bail!(
+            "refusing to serve on {addr}: the page can borrow, hold and tag with your \
+             account, so --listen must be a loopback address such as 127.0.0.1"
+        );
+    }
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("listening on {addr}"))?;
+    let local_addr = listener.local_addr()?;
+    eprintln!("Serving browse page on http://{local_addr}");
+    let state = Arc::new(ServerState::new(libby_client, args, results, local_addr)?);
+    axum::serve(listener, router(state))
+        .await
+        .context("serving browse page")
+}
+
+async fn index(State(state): State<Arc<ServerState>>) -> Html<String> {
+    Html(render_html(
+        &state.results.read().await,
+        state.args.book_type,
+        Some(&state.api_token),
+    ))
+}
+
+async fn results(State(state): State<Arc<ServerState>>) -> Json<Vec<BrowseResult>> {
+    Json(state.results.read().await.clone())
+}
+
+/// Search everything again.
+async fn refresh(
+    State(state): State<Arc<ServerState>>,
+) -> Result<Json<Vec<BrowseResult>>, ApiError> {
+    let mut results = find_results(&state.libby_client, &state.args).await?;
+    record_history(&state.args, &mut results)?;
+    *state.results.write().await = results.clone();
+    Ok(Json(results))
+}
+
+/// Only titles on the page can be acted on.
+async fn check_known(state: &ServerState, libby_id: &str) -> Result<(), ApiError> {
+    let known = state
+        .results
+        .read()
+        .await
+        .iter()
+        .any(|r| r.source == BrowseSource::Libby && r.source_id == libby_id);
+    if known {
+        Ok(())
+    } else {
+        Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("{libby_id} is not on the page"),
+        ))
+    }
+}
+
+/// Update a row with its current availability and return it.
+async fn availability(
+    State(state): State<Arc<ServerState>>,
+    Path(libby_id): Path<String>,
+) -> Result<Json<BrowseResult>, ApiError> {
+    check_known(&state, &libby_id).await?;
+    let availability = state.libby_client.get_availability(&libby_id).await?;
+    let mut results = state.results.write().await;
+    let row = results
+        .iter_mut()
+        .find(|r| r.source == BrowseSource::Libby && r.source_id == libby_id)
+        .ok_or_else(|| ApiError(StatusCode::NOT_FOUND, format!("{libby_id} went away")))?;
+    row.is_available = availability.is_available;
+    row.owned_copies = availability.owned_copies;
+    row.available_copies = availability.available_copies;
+    row.holds_count = availability.holds_count;
+    row.estimated_wait_days = availability.estimated_wait_days;
+    record_history(&state.args, std::slice::from_mut(row))?;
+    Ok(Json(row.clone()))
+}
+
+async fn tag(
+    State(state): State<Arc<ServerState>>,
+    Path(libby_id): Path<String>,
+    Json(request): Json<TagRequest>,
+) -> Result<StatusCode, ApiError> {
+    check_known(&state, &libby_id).await?;
+    let tag_info = state
+        .libby_client
+        .get_existing_tag_by_name(&request.tag)
+        .await?;
+    state
+        .libby_client
+        .tag_book_by_overdrive_id(&tag_info, &libby_id)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn hold(
+    State(state): State<Arc<ServerState>>,
+    Path(libby_id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    check_known(&state, &libby_id).await?;
+    state.libby_client.place_hold(&libby_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn borrow(
+    State(state): State<Arc<ServerState>>,
+    Path(libby_id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    check_known(&state, &libby_id).await?;
+    state
+        .libby_client
+        .borrow(&libby_id, state.args.book_type)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fake_libby;
+    use crate::fake_libby::FakeLibby;
+    use crate::fake_libby::browse_args;
+    use crate::history::AvailabilityHistory;
+
+    #[tokio::test]
+    async fn test_serve_actions_against_fake_libby() {
+        let fake = FakeLibby::start().await;
+        let out_dir = std::env::temp_dir().join(format!(
+            "gr2libby-browse-serve-{}-{}",
+            std::process::id(),
+            fake.server.address().port()
+        ));
+        std::fs::create_dir_all(&out_dir).expect("create output dir");
+        let libby_client = fake.client().await;
+        let history_file = out_dir.join("history.sqlite");
+        let args = BrowseArgs {
+            history_file: Some(history_file.clone()),
+            ..browse_args(&out_dir, fake.endpoints())
+        };
+        let results = find_results(&libby_client, &args).await.expect("results");
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind");
+        let local_addr = listener.local_addr().expect("addr");
+        let base = format!("http://{local_addr}");
+        let state =
+            Arc::new(ServerState::new(libby_client, args, results, local_addr).expect("state"));
+        let api_token = state.api_token.clone();
+        assert_eq!(api_token.len(), 32);
+        tokio::spawn(async move { axum::serve(listener, router(state)).await });
+        let client = reqwest::Client::new();
+        let title_url = |act: &str| format!("{base}/api/titles/{}/{act}", fake_libby::FOUND_ID);
+
+        let page = client
+            .get(&base)
+            .send()
+            .await
+            .expect("page")
+            .text()
+            .await
+            .expect("page body");
+        assert!(page.contains(fake_libby::FOUND_TITLE));
+        assert!(page.contains("const LIVE = true;"));
+        assert!(page.contains(&format!("const API_TOKEN = \"{api_token}\";")));
+
+        // A page on another domain rebound to this address gets nothing
+        let rebound_host = format!("evil.example:{}", local_addr.port());
+        let resp = client
+            .get(&base)
+            .header(header::HOST, &rebound_host)
+            .send()
+            .await
+            .expect("rebound page");
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = client
+            .post(title_url("borrow"))
+            .header(header::HOST, &rebound_host)
+            .header(header::ORIGIN, format!("http://{rebound_host}"))
+            .header(TOKEN_HEADER, &api_token)
+            .send()
+            .await
+            .expect("rebound borrow");
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // Actions need the page's token, and must not come from another site
+        let resp = client
+            .post(title_url("hold"))
+            .send()
+            .await
+            .expect("hold without token");
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = client
+            .post(title_url("hold"))
+            .header(TOKEN_HEADER, &api_token)
+            .header(header::ORIGIN, "http://evil.example")
+            .send()
+            .await
+            .expect("hold from another origin");
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let client = reqwest::Client::builder()
+            .default_headers(
+                [(
+                    header::HeaderName::from_static(TOKEN_HEADER),
+                    api_token.parse().expect("token header"),
+                )]
+                .into_iter()
+                .collect(),
+            )
+            .build()
+            .expect("client");
+
+        let row: serde_json::Value = client
+            .post(title_url("availability"))
+            .send()
+            .await
+            .expect("availability")
+            .json()
+            .await
+            .expect("availability body");
+        assert_eq!(row["is_available"], false);
+        assert_eq!(row["holds_count"], 7);
+        assert_eq!(row["estimated_wait_days"], 28);
+        assert_eq!(row["wait_history"], serde_json::json!([28]));
+
+        for act in ["hold", "borrow"] {
+            let resp = client.post(title_url(act)).send().await.expect(act);
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT, "{act}");
+        }
+        let resp = client
+            .post(title_url("tag"))
+            .json(&serde_json::json!({ "tag": fake_libby::TAG_NAME }))
+            .send()
+            .await
+            .expect("tag");
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let resp = client
+            .post(format!("{base}/api/titles/9999/hold"))
+            .send()
+            .await
+            .expect("unknown hold");
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let results: Vec<serde_json::Value> = client
+            .get(format!("{base}/api/results"))
+            .send()
+            .await
+            .expect("results")
+            .json()
+            .await
+            .expect("results body");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["holds_count"], 7);
+
+        // A full refresh is recorded too
+        let results: Vec<serde_json::Value> = client
+            .post(format!("{base}/api/refresh"))
+            .send()
+            .await
+            .expect("refresh")
+            .json()
+            .await
+            .expect("refresh body");
+        assert_eq!(results.len(), 1);
+        assert!(
+            !results[0]["wait_history"]
+                .as_array()
+                .expect("waits")
+                .is_empty()
+        );
+        let trends = AvailabilityHistory::open(&history_file)
+            .expect("history")
+            .trends()
+            .expect("trends");
+        assert_eq!(trends.len(), 1);
+        assert_eq!(trends[0].libby_id, fake_libby::FOUND_ID);
+        let _ = std::fs::remove_dir_all(&out_dir);
+    }
+}
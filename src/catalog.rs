@@ -0,0 +1,367 @@
+//! The library operations the tagging and browsing code needs, as traits, so
+//! they can run against `LibbyClient` or another backend such as
+//! `InMemoryLibrary`.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use futures::StreamExt;
+use reqwest::StatusCode;
+
+use crate::goodreads;
+use crate::libby::BookInfo;
+use crate::libby::LibbyClient;
+use crate::libby::LibbyError;
+use crate::libby::LibbySearchResultItem;
+use crate::libby::MediaDetails;
+use crate::libby::SearchOptions;
+use crate::libby::TagInfo;
+use crate::normalize::authors_match;
+use crate::normalize::normalize_title;
+
+/// Search a library's catalog.
+pub trait LibraryCatalog {
+    /// Find a title, keeping the first result by one of `authors` when given.
+    fn search_for_book_by_title(
+        &self,
+        search_opts: SearchOptions,
+        title: &str,
+        authors: Option<&HashSet<String>>,
+    ) -> impl Future<Output = Result<BookInfo, LibbyError>> + Send;
+
+    /// Like `search_for_book_by_title`, with the full search result.
+    fn search_for_book_details(
+        &self,
+        search_opts: SearchOptions,
+        title: &str,
+        authors: Option<&HashSet<String>>,
+    ) -> impl Future<Output = Result<LibbySearchResultItem, LibbyError>> + Send;
+
+    /// Format ids (e.g. "ebook-kindle") a title is offered in.
+    fn get_book_formats(
+        &self,
+        libby_id: &str,
+    ) -> impl Future<Output = Result<Vec<String>, LibbyError>> + Send;
+
+    /// Formats and, for audiobooks, length of a title.
+    fn get_book_details(
+        &self,
+        libby_id: &str,
+    ) -> impl Future<Output = Result<MediaDetails, LibbyError>> + Send;
+}
+
+/// Read and change the titles on a user's tags.
+pub trait TagStore {
+    fn get_existing_tag_by_name(
+        &self,
+        name: &str,
+    ) -> impl Future<Output = Result<TagInfo, LibbyError>> + Send;
+
+    fn get_books_for_tag(
+        &self,
+        tag_info: &TagInfo,
+    ) -> impl Future<Output = Result<Vec<BookInfo>, LibbyError>> + Send;
+
+    fn tag_book_by_overdrive_id(
+        &self,
+        tag_info: &TagInfo,
+        title_id: &str,
+    ) -> impl Future<Output = Result<(), LibbyError>> + Send;
+
+    fn untag_book_by_overdrive_id(
+        &self,
+        tag_info: &TagInfo,
+        title_id: &str,
+    ) -> impl Future<Output = Result<(), LibbyError>> + Send;
+}
+
+/// Search the catalog for each book, 25 at a time, returning the results
+/// in the order they finish.
+pub async fn search_shelf<'b, C: LibraryCatalog>(
+    catalog: &C,
+    search_opts: SearchOptions,
+    books: impl IntoIterator<Item = &'b goodreads::BookInfo>,
+) -> Vec<(
+    &'b goodreads::BookInfo,
+    Result<LibbySearchResultItem, LibbyError>,
+)> {
+    // The searches are collected before streaming them so that the future
+    // stays Send, which `browse --serve` needs.
+    let searches: Vec<_> = books
+        .into_iter()
+        .map(|book| {
+            let search_opts = search_opts.clone();
+            async move {
+                let result = catalog
+                    .search_for_book_details(search_opts, &book.title, Some(&book.authors))
+                    .await;
+                (book, result)
+            }
+        })
+        .collect();
+    futures::stream::iter(searches)
+        .buffer_unordered(25)
+        .collect()
+        .await
+}
+
+impl LibraryCatalog for LibbyClient {
+    async fn search_for_book_by_title(
+        &self,
+        search_opts: SearchOptions,
+        title: &str,
+        authors: Option<&HashSet<String>>,
+    ) -> Result<BookInfo, LibbyError> {
+        LibbyClient::search_for_book_by_title(self, search_opts, title, authors).await
+    }
+
+    async fn search_for_book_details(
+        &self,
+        search_opts: SearchOptions,
+        title: &str,
+        authors: Option<&HashSet<String>>,
+    ) -> Result<LibbySearchResultItem, LibbyError> {
+        LibbyClient::search_for_book_details(self, search_opts, title, authors).await
+    }
+
+    async fn get_book_formats(&self, libby_id: &str) -> Result<Vec<String>, LibbyError> {
+        LibbyClient::get_book_formats(self, libby_id).await
+    }
+
+    async fn get_book_details(&self, libby_id: &str) -> Result<MediaDetails, LibbyError> {
+        LibbyClient::get_book_details(self, libby_id).await
+    }
+}
+
+impl TagStore for LibbyClient {
+    async fn get_existing_tag_by_name(&self, name: &str) -> Result<TagInfo, LibbyError> {
+        LibbyClient::get_existing_tag_by_name(self, name).await
+    }
+
+    async fn get_books_for_tag(&self, tag_info: &TagInfo) -> Result<Vec<BookInfo>, LibbyError> {
+        LibbyClient::get_books_for_tag(self, tag_info).await
+    }
+
+    async fn tag_book_by_overdrive_id(
+        &self,
+        tag_info: &TagInfo,
+        title_id: &str,
+    ) -> Result<(), LibbyError> {
+        LibbyClient::tag_book_by_overdrive_id(self, tag_info, title_id).await
+    }
+
+    async fn untag_book_by_overdrive_id(
+        &self,
+        tag_info: &TagInfo,
+        title_id: &str,
+    ) -> Result<(), LibbyError> {
+        LibbyClient::untag_book_by_overdrive_id(self, tag_info, title_id).await
+    }
+}
+
+/// A library held in memory: a fixed catalog and tags that can be changed.
+/// Searches match normalized titles and authors.
+#[derive(Default)]
+pub struct InMemoryLibrary {
+    items: Vec<LibbySearchResultItem>,
+    details: HashMap<String, MediaDetails>,
+    /// Title ids on each tag, by tag name
+    tags: Mutex<HashMap<String, Vec<String>>>,
+    /// Title ids whose tagging or untagging fails
+    failing: HashSet<String>,
+    /// Title ids whose tagging or untagging finds the session expired
+    unauthorized: HashSet<String>,
+    /// How many times title details were asked for
+    detail_lookups: AtomicUsize,
+}
+
+impl InMemoryLibrary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_item(mut self, item: LibbySearchResultItem, formats: &[&str]) -> Self {
+        self.details.insert(
+            item.id.clone(),
+            MediaDetails {
+                formats: formats.iter().map(|f| f.to_string()).collect(),
+                duration_minutes: None,
+            },
+        );
+        self.items.push(item);
+        self
+    }
+
+    /// Give an item added with `with_item` an audiobook length.
+    pub fn with_duration(mut self, title_id: &str, minutes: i64) -> Self {
+        self.details
+            .entry(title_id.to_string())
+            .or_default()
+            .duration_minutes = Some(minutes);
+        self
+    }
+
+    pub fn with_tag(self, name: &str, title_ids: &[&str]) -> Self {
+        self.tags.lock().expect("tags lock poisoned").insert(
+            name.to_string(),
+            title_ids.iter().map(|id| id.to_string()).collect(),
+        );
+        self
+    }
+
+    /// Make tag changes for `title_id` fail, as an unavailable server would.
+    pub fn with_failing_title(mut self, title_id: &str) -> Self {
+        self.failing.insert(title_id.to_string());
+        self
+    }
+
+    /// Make tag changes for `title_id` fail as if the session had expired.
+    pub fn with_unauthorized_title(mut self, title_id: &str) -> Self {
+        self.unauthorized.insert(title_id.to_string());
+        self
+    }
+
+    /// How many times `get_book_details` has been called.
+    pub fn detail_lookups(&self) -> usize {
+        self.detail_lookups.load(Ordering::Relaxed)
+    }
+
+    /// Title ids currently on the tag.
+    pub fn tagged(&self, name: &str) -> Vec<String> {
+        self.tags
+            .lock()
+            .expect("tags lock poisoned")
+            .get(name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn find(
+        &self,
+        title: &str,
+        authors: Option<&HashSet<String>>,
+    ) -> Option<&LibbySearchResultItem> {
+        let title = normalize_title(title);
+        self.items.iter().find(|item| {
+            normalize_title(&item.sort_title) == title
+                && authors.is_none_or(|authors| {
+                    authors
+                        .iter()
+                        .any(|a| authors_match(a, &item.first_creator_name))
+                })
+        })
+    }
+
+    fn change_tag(&self, tag_info: &TagInfo, title_id: &str, add: bool) -> Result<(), LibbyError> {
+        if self.unauthorized.contains(title_id) {
+            return Err(LibbyError::Unauthorized(format!("tagging {title_id}")));
+        }
+        if self.failing.contains(title_id) {
+            return Err(LibbyError::UnexpectedStatus {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                body: String::new(),
+            });
+        }
+        let mut tags = self.tags.lock().expect("tags lock poisoned");
+        let ids = tags
+            .get_mut(&tag_info.name)
+            .ok_or_else(|| LibbyError::TagNotFound(tag_info.name.clone()))?;
+        ids.retain(|id| id != title_id);
+        if add {
+            ids.push(title_id.to_string());
+        }
+        Ok(())
+    }
+}
+
+impl LibraryCatalog for InMemoryLibrary {
+    async fn search_for_book_by_title(
+        &self,
+        search_opts: SearchOptions,
+        title: &str,
+        authors: Option<&HashSet<String>>,
+    ) -> Result<BookInfo, LibbyError> {
+        let item = self
+            .search_for_book_details(search_opts, title, authors)
+            .await?;
+        Ok(BookInfo {
+            libby_id: item.id,
+            title: item.sort_title,
+            is_available: Some(item.is_available),
+        })
+    }
+
+    async fn search_for_book_details(
+        &self,
+        _search_opts: SearchOptions,
+        title: &str,
+        authors: Option<&HashSet<String>>,
+    ) -> Result<LibbySearchResultItem, LibbyError> {
+        self.find(title, authors)
+            .cloned()
+            .ok_or_else(|| LibbyError::NotFound(format!("Book '{}'", title)))
+    }
+
+    async fn get_book_formats(&self, libby_id: &str) -> Result<Vec<String>, LibbyError> {
+        Ok(self.get_book_details(libby_id).await?.formats)
+    }
+
+    async fn get_book_details(&self, libby_id: &str) -> Result<MediaDetails, LibbyError> {
+        self.detail_lookups.fetch_add(1, Ordering::Relaxed);
+        self.details
+            .get(libby_id)
+            .cloned()
+            .ok_or_else(|| LibbyError::HttpNotFound(libby_id.to_string()))
+    }
+}
+
+impl TagStore for InMemoryLibrary {
+    async fn get_existing_tag_by_name(&self, name: &str) -> Result<TagInfo, LibbyError> {
+        let tags = self.tags.lock().expect("tags lock poisoned");
+        let ids = tags
+            .get(name)
+            .ok_or_else(|| LibbyError::TagNotFound(name.to_string()))?;
+        Ok(TagInfo {
+            uuid: name.to_string(),
+            name: name.to_string(),
+            total_tagged: ids.len() as i64,
+        })
+    }
+
+    async fn get_books_for_tag(&self, tag_info: &TagInfo) -> Result<Vec<BookInfo>, LibbyError> {
+        Ok(self
+            .tagged(&tag_info.name)
+            .into_iter()
+            .map(|id| BookInfo {
+                title: self
+                    .items
+                    .iter()
+                    .find(|item| item.id == id)
+                    .map(|item| item.sort_title.clone())
+                    .unwrap_or_default(),
+                libby_id: id,
+                is_available: None,
+            })
+            .collect())
+    }
+
+    async fn tag_book_by_overdrive_id(
+        &self,
+        tag_info: &TagInfo,
+        title_id: &str,
+    ) -> Result<(), LibbyError> {
+        self.change_tag(tag_info, title_id, true)
+    }
+
+    async fn untag_book_by_overdrive_id(
+        &self,
+        tag_info: &TagInfo,
+        title_id: &str,
+    ) -> Result<(), LibbyError> {
+        self.change_tag(tag_info, title_id, false)
+    }
+}
@@ -0,0 +1,171 @@
+//! Local stand-in for goodreads.com, serving the canned pages in
+//! tests/fixtures/goodreads so the exporter can be tested offline.
+
+use std::path::PathBuf;
+
+use wiremock::Mock;
+use wiremock::MockServer;
+use wiremock::ResponseTemplate;
+use wiremock::matchers::header;
+use wiremock::matchers::method;
+use wiremock::matchers::path;
+use wiremock::matchers::path_regex;
+use wiremock::matchers::query_param;
+
+use crate::goodreads_export::GoodreadsExporter;
+
+pub(crate) const USER_ID: &str = "1234";
+pub(crate) const CSRF_TOKEN: &str = "fake-csrf-token-0123456789";
+pub(crate) const EXPORT_CSV: &str = include_str!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/fixtures/goodreads_export.csv"
+));
+
+macro_rules! fixture {
+    ($name:literal) => {
+        include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/goodreads/",
+            $name
+        ))
+    };
+}
+
+fn html(body: &str) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_raw(body, "text/html; charset=utf-8")
+}
+
+/// How the fake answers the export request and the polling after it.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum ExportBehavior {
+    /// The CSV 404s for this many polls, then is ready
+    ReadyAfter(u64),
+    /// The CSV never becomes ready
+    NeverReady,
+    /// The export request is refused, as it is for expired cookies
+    Refused,
+    /// Polling for the CSV fails with a server error
+    PollFails,
+}
+
+pub(crate) struct FakeGoodreads {
+    pub(crate) server: MockServer,
+    /// Goodreads config for the fake user, removed again on drop
+    pub(crate) conf_file: PathBuf,
+}
+
+impl FakeGoodreads {
+    /// Start the fake. When not `signed_in`, every page redirects to sign-in.
+    pub(crate) async fn start(signed_in: bool, export: ExportBehavior) -> Self {
+        let server = MockServer::start().await;
+        let csv_path = format!("/review_porter/export/{USER_ID}/goodreads_export.csv");
+
+        Mock::given(method("GET"))
+            .and(path("/user/sign_in"))
+            .respond_with(html(fixture!("sign_in.html")))
+            .mount(&server)
+            .await;
+        if !signed_in {
+            Mock::given(method("GET"))
+                .and(path_regex(r"^/(review|review_porter)/"))
+                .respond_with(ResponseTemplate::new(302).insert_header("Location", "/user/sign_in"))
+                .with_priority(1)
+                .mount(&server)
+                .await;
+        }
+
+        Mock::given(method("GET"))
+            .and(path("/review/import"))
+            .respond_with(html(fixture!("import.html")))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/review/list/{USER_ID}")))
+            .and(query_param("shelf", "to-read"))
+            .respond_with(html(fixture!("review_list_to_read.html")))
+            .mount(&server)
+            .await;
+
+        let export_status = match export {
+            ExportBehavior::Refused => 403,
+            _ => 200,
+        };
+        Mock::given(method("POST"))
+            .and(path(format!("/review_porter/export/{USER_ID}")))
+            .and(header("X-CSRF-Token", CSRF_TOKEN))
+            .respond_with(ResponseTemplate::new(export_status))
+            .mount(&server)
+            .await;
+
+        match export {
+            ExportBehavior::ReadyAfter(polls) => {
+                if polls > 0 {
+                    Mock::given(method("HEAD"))
+                        .and(path(&csv_path))
+                        .respond_with(ResponseTemplate::new(404))
+                        .up_to_n_times(polls)
+                        .with_priority(1)
+                        .mount(&server)
+                        .await;
+                }
+                Mock::given(method("HEAD"))
+                    .and(path(&csv_path))
+                    .respond_with(ResponseTemplate::new(200))
+                    .mount(&server)
+                    .await;
+            }
+            ExportBehavior::PollFails => {
+                Mock::given(method("HEAD"))
+                    .and(path(&csv_path))
+                    .respond_with(ResponseTemplate::new(500))
+                    .mount(&server)
+                    .await;
+            }
+            ExportBehavior::NeverReady | ExportBehavior::Refused => {
+                Mock::given(method("HEAD"))
+                    .and(path(&csv_path))
+                    .respond_with(ResponseTemplate::new(404))
+                    .mount(&server)
+                    .await;
+            }
+        }
+        Mock::given(method("GET"))
+            .and(path(&csv_path))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(EXPORT_CSV, "text/csv"))
+            .mount(&server)
+            .await;
+
+        let conf_file = std::env::temp_dir().join(format!(
+            "gr2libby-fake-goodreads-{}-{}.json",
+            std::process::id(),
+            server.address().port()
+        ));
+        std::fs::write(&conf_file, fixture!("goodreads_config.json"))
+            .expect("write goodreads config");
+        Self { server, conf_file }
+    }
+
+    pub(crate) async fn exporter(&self) -> GoodreadsExporter {
+        GoodreadsExporter::new(self.conf_file.clone())
+            .await
+            .expect("create exporter")
+            .with_base(self.server.uri())
+    }
+
+    /// Number of times the export CSV was polled for.
+    pub(crate) async fn polls(&self) -> usize {
+        self.server
+            .received_requests()
+            .await
+            .unwrap_or_default()
+            .iter()
+            .filter(|r| r.method == wiremock::http::Method::HEAD)
+            .count()
+    }
+}
+
+impl Drop for FakeGoodreads {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.conf_file);
+    }
+}
@@ -0,0 +1,190 @@
+//! The fake Libby and OverDrive server from tests/common with a client
+//! config to go with it, and builders for the books and titles tests use.
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+use wiremock::Mock;
+use wiremock::MockServer;
+use wiremock::ResponseTemplate;
+use wiremock::matchers::header;
+use wiremock::matchers::method;
+use wiremock::matchers::path;
+
+use crate::browse::BrowseArgs;
+use crate::browse::BrowseFormat;
+use crate::goodreads;
+use crate::hoopla::HooplaKind;
+use crate::libby::BookType;
+use crate::libby::LibbyBookType;
+use crate::libby::LibbyClient;
+use crate::libby::LibbyEndpoints;
+use crate::libby::LibbySearchResultItem;
+use crate::request_policy::RequestPolicy;
+
+// The server itself is shared with the integration tests
+#[macro_use]
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+pub(crate) use common::CARD_ID;
+pub(crate) use common::FOUND_ID;
+pub(crate) use common::FOUND_TITLE;
+pub(crate) use common::TAG_NAME;
+use common::json;
+
+/// A to-read shelf book with only its title and author known.
+pub(crate) fn book(book_id: i64, title: &str, author: &str) -> goodreads::BookInfo {
+    goodreads::BookInfo {
+        title: title.to_string(),
+        author: author.to_string(),
+        isbn: String::new(),
+        authors: HashSet::from([author.to_string()]),
+        shelf: "to-read".to_string(),
+        number_of_pages: None,
+        bookshelves: vec![],
+        average_rating: None,
+        book_id,
+        year_published: None,
+        date_added: String::new(),
+        private_notes: None,
+    }
+}
+
+/// An ebook the library owns one copy of, available now.
+pub(crate) fn item(id: &str, title: &str, author: &str) -> LibbySearchResultItem {
+    LibbySearchResultItem {
+        is_available: true,
+        is_owned: Some(true),
+        owned_copies: Some(1),
+        estimated_wait_days: None,
+        holds_count: None,
+        available_copies: Some(1),
+        id: id.to_string(),
+        first_creator_name: author.to_string(),
+        sort_title: title.to_string(),
+        book_type: LibbyBookType {
+            id: "ebook".to_string(),
+            name: "eBook".to_string(),
+        },
+        subjects: vec![],
+        detailed_series: None,
+    }
+}
+
+/// The title listed in the catalog without the library owning a copy.
+pub(crate) fn unowned(item: LibbySearchResultItem) -> LibbySearchResultItem {
+    LibbySearchResultItem {
+        is_available: false,
+        is_owned: Some(false),
+        owned_copies: Some(0),
+        available_copies: Some(0),
+        ..item
+    }
+}
+
+/// The title as an audiobook.
+pub(crate) fn audiobook(item: LibbySearchResultItem) -> LibbySearchResultItem {
+    LibbySearchResultItem {
+        book_type: LibbyBookType {
+            id: "audiobook".to_string(),
+            name: "Audiobook".to_string(),
+        },
+        ..item
+    }
+}
+
+pub(crate) struct FakeLibby {
+    pub(crate) server: MockServer,
+    /// Libby config with a saved token, removed again on drop
+    pub(crate) conf_file: PathBuf,
+}
+
+impl FakeLibby {
+    pub(crate) async fn start() -> Self {
+        let server = common::start_fake_libby().await;
+        let conf_file = std::env::temp_dir().join(format!(
+            "gr2libby-fake-libby-{}-{}.json",
+            std::process::id(),
+            server.address().port()
+        ));
+        std::fs::write(&conf_file, fixture!("libby_config.json")).expect("write libby config");
+        Self { server, conf_file }
+    }
+
+    /// Reject the identity the client currently holds once, as Libby does
+    /// when it has rotated it, and hand out a new one from /chip.
+    pub(crate) async fn expire_identity(&self) {
+        Mock::given(method("GET"))
+            .and(header("authorization", "Bearer fresh-identity"))
+            .respond_with(ResponseTemplate::new(401))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&self.server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/chip"))
+            .respond_with(json(fixture!("chip_rotated.json")))
+            .with_priority(1)
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Endpoints with all three services pointed at the fake server.
+    pub(crate) fn endpoints(&self) -> LibbyEndpoints {
+        LibbyEndpoints {
+            sentry_base: self.server.uri(),
+            vandal_base: self.server.uri(),
+            thunder_base: self.server.uri(),
+        }
+    }
+
+    pub(crate) async fn client(&self) -> LibbyClient {
+        LibbyClient::new_with_endpoints(
+            self.conf_file.clone(),
+            CARD_ID.to_string(),
+            self.endpoints(),
+        )
+        .await
+        .expect("create client against fake libby")
+    }
+}
+
+/// Browse arguments for the export fixture, writing under `out_dir`.
+pub(crate) fn browse_args(out_dir: &Path, libby_endpoints: LibbyEndpoints) -> BrowseArgs {
+    BrowseArgs {
+        goodreads_export_csv: concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/goodreads_export.csv"
+        )
+        .into(),
+        card_id: CARD_ID.to_string(),
+        goodreads_shelf: "to-read".to_string(),
+        book_type: BookType::Ebook,
+        tags: vec![],
+        min_pages: None,
+        max_pages: None,
+        min_hours: None,
+        max_hours: None,
+        format: BrowseFormat::Html,
+        output: Some(out_dir.join("browse.html")),
+        cache_file: out_dir.join("browse_cache.json"),
+        request_policy: RequestPolicy::default(),
+        libby_endpoints,
+        hoopla_base: None,
+        hoopla_kind: HooplaKind::Ebook,
+        openlibrary_base: None,
+        openlibrary_cache_file: out_dir.join("openlibrary_cache.json"),
+        serve: None,
+        history_file: None,
+        available_only: false,
+        kindle_only: false,
+    }
+}
+
+impl Drop for FakeLibby {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.conf_file);
+    }
+}
@@ -5,9 +5,11 @@ use std::path::PathBuf;
 use anyhow::Result;
 use itertools::Itertools;
 use serde::Deserialize;
+use serde::Serialize;
 use tracing::debug;
 
-#[derive(Debug)]
+/// A book from a Goodreads export.
+#[derive(Debug, Clone)]
 pub struct BookInfo {
     pub title: String,
     pub author: String,
@@ -56,56 +58,69 @@ impl From<GoodReadsExportRecord> for BookInfo {
 }
 #[allow(dead_code)]
 #[allow(non_snake_case)]
-#[derive(Deserialize, Debug, Clone)]
-struct GoodReadsExportRecord {
-    #[serde(alias = "Book Id")]
-    book_id: i64,
-    #[serde(alias = "Title")]
-    title: String,
-    #[serde(alias = "Author")]
-    author: String,
-    #[serde(alias = "Author l-f")]
-    author_l_f: String,
-    #[serde(alias = "Additional Authors")]
-    additional_authors: String,
-    ISBN: String,
-    ISBN13: String,
-    #[serde(alias = "My Rating")]
-    my_rating: Option<String>,
-    #[serde(alias = "Average Rating")]
-    average_rating: String,
-    #[serde(alias = "Publisher")]
-    publisher: String,
-    #[serde(alias = "Binding")]
-    binding: String,
-    #[serde(alias = "Number of Pages")]
-    number_of_pages: Option<i64>,
-    #[serde(alias = "Year Published")]
-    year_published: Option<i16>,
-    #[serde(alias = "Original Publication Year")]
-    original_publication_year: Option<i16>,
-    #[serde(alias = "Date Read")]
-    date_read: Option<String>,
-    #[serde(alias = "Date Added")]
-    date_added: String,
-    #[serde(alias = "Bookshelves")]
-    bookshelves: String,
-    #[serde(alias = "Bookshelves with positions")]
-    bookshelves_with_positions: String,
-    #[serde(alias = "Exclusive Shelf")]
-    exclusive_shelf: String,
-    #[serde(alias = "My Review")]
-    my_review: Option<String>,
-    #[serde(alias = "Spoiler")]
-    spoiler: Option<String>,
-    #[serde(alias = "Private Notes")]
-    private_notes: Option<String>,
-    #[serde(alias = "Read Count")]
-    read_count: i64,
-    #[serde(alias = "Owned Copies")]
-    owned_copies: i64,
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct GoodReadsExportRecord {
+    #[serde(rename(serialize = "Book Id"), alias = "Book Id")]
+    pub(crate) book_id: i64,
+    #[serde(rename(serialize = "Title"), alias = "Title")]
+    pub(crate) title: String,
+    #[serde(rename(serialize = "Author"), alias = "Author")]
+    pub(crate) author: String,
+    #[serde(rename(serialize = "Author l-f"), alias = "Author l-f")]
+    pub(crate) author_l_f: String,
+    #[serde(rename(serialize = "Additional Authors"), alias = "Additional Authors")]
+    pub(crate) additional_authors: String,
+    pub(crate) ISBN: String,
+    pub(crate) ISBN13: String,
+    #[serde(rename(serialize = "My Rating"), alias = "My Rating")]
+    pub(crate) my_rating: Option<String>,
+    #[serde(rename(serialize = "Average Rating"), alias = "Average Rating")]
+    pub(crate) average_rating: String,
+    #[serde(rename(serialize = "Publisher"), alias = "Publisher")]
+    pub(crate) publisher: String,
+    #[serde(rename(serialize = "Binding"), alias = "Binding")]
+    pub(crate) binding: String,
+    #[serde(rename(serialize = "Number of Pages"), alias = "Number of Pages")]
+    pub(crate) number_of_pages: Option<i64>,
+    #[serde(rename(serialize = "Year Published"), alias = "Year Published")]
+    pub(crate) year_published: Option<i16>,
+    #[serde(
+        rename(serialize = "Original Publication Year"),
+        alias = "Original Publication Year"
+    )]
+    pub(crate) original_publication_year: Option<i16>,
+    #[serde(rename(serialize = "Date Read"), alias = "Date Read")]
+    pub(crate) date_read: Option<String>,
+    #[serde(rename(serialize = "Date Added"), alias = "Date Added")]
+    pub(crate) date_added: String,
+    #[serde(rename(serialize = "Bookshelves"), alias = "Bookshelves")]
+    pub(crate) bookshelves: String,
+    #[serde(
+        rename(serialize = "Bookshelves with positions"),
+        alias = "Bookshelves with positions"
+    )]
+    pub(crate) bookshelves_with_positions: String,
+    #[serde(rename(serialize = "Exclusive Shelf"), alias = "Exclusive Shelf")]
+    pub(crate) exclusive_shelf: String,
+    #[serde(rename(serialize = "My Review"), alias = "My Review")]
+    pub(crate) my_review: Option<String>,
+    #[serde(rename(serialize = "Spoiler"), alias = "Spoiler")]
+    pub(crate) spoiler: Option<String>,
+    #[serde(rename(serialize = "Private Notes"), alias = "Private Notes")]
+    pub(crate) private_notes: Option<String>,
+    #[serde(rename(serialize = "Read Count"), alias = "Read Count")]
+    pub(crate) read_count: i64,
+    #[serde(rename(serialize = "Owned Copies"), alias = "Owned Copies")]
+    pub(crate) owned_copies: i64,
 }
 
+/// Strip the `="..."` a Goodreads export wraps ISBNs in.
+pub fn clean_isbn(isbn: &str) -> Option<&str> {
+    let isbn = isbn.trim_start_matches('=').trim_matches('"').trim();
+    (!isbn.is_empty()).then_some(isbn)
+}
+
+/// Books on one exclusive shelf (read, currently-reading, to-read) of an export CSV.
 pub async fn get_book_titles_from_goodreads_shelf(
     file_path: PathBuf,
     shelf_name: &str,
@@ -124,6 +139,7 @@ pub async fn get_book_titles_from_goodreads_shelf(
         .collect())
 }
 
+/// All books in an export CSV, grouped by exclusive shelf.
 pub async fn get_book_titles_from_goodreads(
     file_path: PathBuf,
 ) -> Result<HashMap<String, Vec<BookInfo>>> {
@@ -1,29 +1,123 @@
+use std::collections::BTreeMap;
+use std::collections::HashSet;
 use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use anyhow::Context;
 use anyhow::Result;
 use anyhow::bail;
 use reqwest::header;
+use scraper::ElementRef;
 use scraper::Html;
 use scraper::Selector;
 use serde::Deserialize;
+use serde::Serialize;
 use tokio::time::Duration;
 use tracing::debug;
 use tracing::info;
+use tracing::warn;
+
+use crate::goodreads::GoodReadsExportRecord;
 
 const USER_AGENT: &str =
     "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:137.0) Gecko/20100101 Firefox/137.0";
-const GOODREADS_BASE: &str = "https://www.goodreads.com";
-
-#[derive(Debug, Deserialize)]
+pub const GOODREADS_BASE: &str = "https://www.goodreads.com";
+/// Cookies that make up the signed in session; the rest are tracking/preferences.
+const SESSION_COOKIES: [&str; 6] = [
+    "_session_id2",
+    "at-main",
+    "sess-at-main",
+    "session-id",
+    "ubid-main",
+    "x-main",
+];
+/// Largest page size the review list accepts.
+const REVIEWS_PER_PAGE: usize = 100;
+/// Upper bound on review list pages, in case pagination never runs dry.
+const MAX_REVIEW_PAGES: usize = 200;
+const EXCLUSIVE_SHELVES: [&str; 3] = ["read", "currently-reading", "to-read"];
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct GoodreadsConfig {
     pub user_id: String,
     pub cookies: String,
+    /// Unix time each cookie expires at, when known (written by goodreads-login)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub cookie_expires: BTreeMap<String, i64>,
+}
+impl GoodreadsConfig {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Goodreads refused the CSV export or it never became ready. Unlike
+/// network errors, scraping the shelves can stand in for it.
+#[derive(Debug)]
+pub struct ExportUnavailable(String);
+
+impl std::fmt::Display for ExportUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ExportUnavailable {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionStatus {
+    Valid,
+    ExpiringSoon,
+    Expired,
+}
+
+impl SessionStatus {
+    /// Exit code for `gr-export check`, so scheduled jobs can alert on it.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Valid => 0,
+            Self::ExpiringSoon => 2,
+            Self::Expired => 3,
+        }
+    }
+}
+
+impl std::fmt::Display for SessionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Valid => write!(f, "valid"),
+            Self::ExpiringSoon => write!(f, "expiring soon"),
+            Self::Expired => write!(f, "expired"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SessionReport {
+    pub status: SessionStatus,
+    /// Whether Goodreads accepted the cookies for an authenticated page
+    pub signed_in: bool,
+    /// Seconds until the first session cookie expires, when expiries are known
+    pub remaining_secs: Option<i64>,
+    /// Seconds left for each known session cookie
+    pub cookies: Vec<(String, i64)>,
+}
+
+pub fn format_remaining(secs: i64) -> String {
+    if secs <= 0 {
+        "expired".to_string()
+    } else if secs >= 86400 {
+        format!("{}d {}h", secs / 86400, (secs % 86400) / 3600)
+    } else {
+        format!("{}h {}m", secs / 3600, (secs % 3600) / 60)
+    }
 }
 
 pub struct GoodreadsExporter {
     client: reqwest::Client,
     config: GoodreadsConfig,
+    base: String,
 }
 
 impl GoodreadsExporter {
@@ -51,11 +145,23 @@ impl GoodreadsExporter {
             .build()
             .context("building reqwest client")?;
 
-        Ok(Self { client, config })
+        Ok(Self {
+            client,
+            config,
+            base: GOODREADS_BASE.to_string(),
+        })
+    }
+
+    /// Talk to Goodreads at `base` instead of www.goodreads.com.
+    pub fn with_base(mut self, base: impl Into<String>) -> Self {
+        self.base = base.into().trim_end_matches('/').to_string();
+        self
     }
 
-    async fn scrape_csrf_token(&self) -> Result<String> {
-        let url = format!("{}/review/import", GOODREADS_BASE);
+    /// Fetch the import page, which needs a signed in session. Returns `None`
+    /// when Goodreads redirects to sign-in instead.
+    async fn fetch_import_page(&self) -> Result<Option<String>> {
+        let url = format!("{}/review/import", self.base);
         let resp = self
             .client
             .get(&url)
@@ -74,14 +180,14 @@ impl GoodreadsExporter {
         }
 
         if final_url.contains("sign_in") {
-            bail!(
-                "redirected to sign-in page ({}). Your cookies are expired or invalid — re-run the cookie extraction script.",
-                final_url
-            );
+            return Ok(None);
         }
 
-        let body = resp.text().await.context("reading import page body")?;
-        let document = Html::parse_document(&body);
+        Ok(Some(resp.text().await.context("reading import page body")?))
+    }
+
+    fn find_csrf_token(body: &str) -> Option<String> {
+        let document = Html::parse_document(body);
         let selector = Selector::parse(r#"meta[name="csrf-token"]"#).expect("valid CSS selector");
 
         document
@@ -89,15 +195,56 @@ impl GoodreadsExporter {
             .next()
             .and_then(|el| el.value().attr("content"))
             .map(String::from)
+    }
+
+    pub async fn scrape_csrf_token(&self) -> Result<String> {
+        let body = self.fetch_import_page().await?.context(
+            "redirected to sign-in page. Your cookies are expired or invalid — re-run goodreads-login.",
+        )?;
+        Self::find_csrf_token(&body)
             .context("could not find CSRF token on page — your session may have expired")
     }
 
+    /// Validate the session cookies without starting an export: checks the
+    /// recorded cookie expiries and that an authenticated page still loads.
+    /// Sessions with less than `warn_within_secs` left are reported as expiring soon.
+    pub async fn check_session(&self, warn_within_secs: i64) -> Result<SessionReport> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs() as i64;
+        let cookies: Vec<(String, i64)> = self
+            .config
+            .cookie_expires
+            .iter()
+            .filter(|(name, _)| SESSION_COOKIES.contains(&name.as_str()))
+            .map(|(name, expires)| (name.clone(), expires - now))
+            .collect();
+        let remaining_secs = cookies.iter().map(|(_, secs)| *secs).min();
+
+        let signed_in = self
+            .fetch_import_page()
+            .await?
+            .and_then(|body| Self::find_csrf_token(&body))
+            .is_some();
+
+        let status = match remaining_secs {
+            _ if !signed_in => SessionStatus::Expired,
+            Some(secs) if secs <= 0 => SessionStatus::Expired,
+            Some(secs) if secs < warn_within_secs => SessionStatus::ExpiringSoon,
+            _ => SessionStatus::Valid,
+        };
+        Ok(SessionReport {
+            status,
+            signed_in,
+            remaining_secs,
+            cookies,
+        })
+    }
+
     async fn request_export(&self, csrf_token: &str) -> Result<()> {
-        let url = format!(
-            "{}/review_porter/export/{}",
-            GOODREADS_BASE, self.config.user_id
-        );
-        let referer = format!("{}/review/import", GOODREADS_BASE);
+        let url = format!("{}/review_porter/export/{}", self.base, self.config.user_id);
+        let referer = format!("{}/review/import", self.base);
         let resp = self
             .client
             .post(&url)
@@ -105,7 +252,7 @@ impl GoodreadsExporter {
             .header("X-Requested-With", "XMLHttpRequest")
             .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
             .header(header::REFERER, &referer)
-            .header(header::ORIGIN, GOODREADS_BASE)
+            .header(header::ORIGIN, &self.base)
             .header(header::ACCEPT, "*/*")
             .body("format=json")
             .send()
@@ -115,21 +262,87 @@ impl GoodreadsExporter {
         if !resp.status().is_success() {
             let status = resp.status();
             let body = resp.text().await.unwrap_or_default();
-            bail!(
+            return Err(ExportUnavailable(format!(
                 "export request failed (status {}): {}. Your cookies may have expired.",
+                status, body
+            ))
+            .into());
+        }
+
+        info!("export request accepted");
+        Ok(())
+    }
+
+    async fn post_shelf_change(
+        &self,
+        csrf_token: &str,
+        book_id: i64,
+        shelf: &str,
+        remove: bool,
+    ) -> Result<()> {
+        let url = format!("{}/shelf/add_to_shelf", self.base);
+        let book_id = book_id.to_string();
+        let mut form = vec![
+            ("book_id", book_id.as_str()),
+            ("name", shelf),
+            ("authenticity_token", csrf_token),
+        ];
+        if remove {
+            form.push(("a", "remove"));
+        }
+        let resp = self
+            .client
+            .post(&url)
+            .header("X-CSRF-Token", csrf_token)
+            .header("X-Requested-With", "XMLHttpRequest")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(header::ORIGIN, &self.base)
+            .body(serde_urlencoded::to_string(&form)?)
+            .send()
+            .await
+            .context("updating shelf")?;
+
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let body = resp.text().await.unwrap_or_default();
+            bail!(
+                "shelf update for book {} on '{}' failed (status {}): {}",
+                book_id,
+                shelf,
                 status,
                 body
             );
         }
-
-        info!("export request accepted");
+        debug!(
+            "{} book {} {} shelf '{}'",
+            if remove { "removed" } else { "added" },
+            book_id,
+            if remove { "from" } else { "to" },
+            shelf
+        );
         Ok(())
     }
 
+    /// Add a book to one of our shelves. Goodreads creates the shelf if needed.
+    pub async fn add_to_shelf(&self, csrf_token: &str, book_id: i64, shelf: &str) -> Result<()> {
+        self.post_shelf_change(csrf_token, book_id, shelf, false)
+            .await
+    }
+
+    pub async fn remove_from_shelf(
+        &self,
+        csrf_token: &str,
+        book_id: i64,
+        shelf: &str,
+    ) -> Result<()> {
+        self.post_shelf_change(csrf_token, book_id, shelf, true)
+            .await
+    }
+
     fn csv_url(&self) -> String {
         format!(
             "{}/review_porter/export/{}/goodreads_export.csv",
-            GOODREADS_BASE, self.config.user_id
+            self.base, self.config.user_id
         )
     }
 
@@ -166,11 +379,12 @@ impl GoodreadsExporter {
             tokio::time::sleep(interval).await;
         }
 
-        bail!(
+        Err(ExportUnavailable(format!(
             "export not ready after {} attempts ({}s total). Try increasing --max-poll-attempts.",
             max_attempts,
             max_attempts as u64 * interval.as_secs()
-        );
+        ))
+        .into())
     }
 
     async fn download_csv(&self, output: &PathBuf) -> Result<()> {
@@ -195,11 +409,103 @@ impl GoodreadsExporter {
         Ok(())
     }
 
+    async fn scrape_shelf(
+        &self,
+        shelf: &str,
+        page_delay: Duration,
+    ) -> Result<Vec<GoodReadsExportRecord>> {
+        let url = format!("{}/review/list/{}", self.base, self.config.user_id);
+        let per_page = REVIEWS_PER_PAGE.to_string();
+        let mut records = Vec::new();
+        for page in 1..=MAX_REVIEW_PAGES {
+            let page_param = page.to_string();
+            let page_url = reqwest::Url::parse_with_params(
+                &url,
+                &[
+                    ("shelf", shelf),
+                    ("view", "table"),
+                    ("per_page", &per_page),
+                    ("page", &page_param),
+                ],
+            )?;
+            let resp = self
+                .client
+                .get(page_url)
+                .send()
+                .await
+                .with_context(|| format!("fetching page {} of shelf '{}'", page, shelf))?;
+
+            if resp.url().as_str().contains("sign_in") {
+                bail!(
+                    "redirected to sign-in page. Your cookies are expired or invalid — re-run goodreads-login."
+                );
+            }
+            if !resp.status().is_success() {
+                bail!(
+                    "failed to fetch page {} of shelf '{}' (status {})",
+                    page,
+                    shelf,
+                    resp.status()
+                );
+            }
+
+            let body = resp.text().await.context("reading review list body")?;
+            let page_records = parse_review_list(&body, shelf);
+            debug!(
+                "shelf '{}' page {}: {} books",
+                shelf,
+                page,
+                page_records.len()
+            );
+            let last_page = page_records.len() < REVIEWS_PER_PAGE;
+            records.extend(page_records);
+            if last_page {
+                break;
+            }
+            eprint!(".");
+            tokio::time::sleep(page_delay).await;
+        }
+        info!("scraped {} books from shelf '{}'", records.len(), shelf);
+        Ok(records)
+    }
+
+    /// Fallback for when the CSV export is disabled or never becomes ready:
+    /// pages through the review list HTML of each shelf and writes a CSV with
+    /// the same columns as the Goodreads export. Columns the list does not
+    /// show (reviews, notes, publisher, ...) are left empty.
+    pub async fn scrape_shelves_to_csv(
+        &self,
+        shelves: &[String],
+        output: &PathBuf,
+        page_delay: Duration,
+    ) -> Result<()> {
+        let mut seen = HashSet::new();
+        let mut wtr = csv::Writer::from_path(output)
+            .with_context(|| format!("creating CSV {}", output.display()))?;
+        for shelf in shelves {
+            eprintln!("Scraping shelf '{}'...", shelf);
+            for record in self.scrape_shelf(shelf, page_delay).await? {
+                if seen.insert(record.book_id) {
+                    wtr.serialize(record).context("writing CSV record")?;
+                }
+            }
+        }
+        wtr.flush().context("flushing CSV")?;
+        info!("wrote {} books to {}", seen.len(), output.display());
+        Ok(())
+    }
+
+    /// Export the library as CSV. If Goodreads refuses the export or it
+    /// never becomes ready and `fallback_shelves` is not empty, those shelves
+    /// are scraped instead, `scrape_page_delay` apart. Other errors, such as
+    /// network failures, are returned as is.
     pub async fn export(
         &self,
         output: PathBuf,
         poll_interval: Duration,
         max_poll_attempts: u32,
+        fallback_shelves: &[String],
+        scrape_page_delay: Duration,
     ) -> Result<()> {
         eprintln!("Scraping CSRF token...");
         let csrf_token = self.scrape_csrf_token().await?;
@@ -209,17 +515,374 @@ impl GoodreadsExporter {
         );
 
         eprintln!("Requesting export for user {}...", self.config.user_id);
-        self.request_export(&csrf_token).await?;
-
-        eprint!("Waiting for export to be ready");
-        self.poll_until_ready(poll_interval, max_poll_attempts)
-            .await?;
-        eprintln!();
+        let exported = match self.request_export(&csrf_token).await {
+            Ok(()) => {
+                eprint!("Waiting for export to be ready");
+                let ready = self
+                    .poll_until_ready(poll_interval, max_poll_attempts)
+                    .await;
+                eprintln!();
+                ready
+            }
+            Err(e) => Err(e),
+        };
 
-        eprintln!("Downloading CSV...");
-        self.download_csv(&output).await?;
+        match exported {
+            Ok(()) => {
+                eprintln!("Downloading CSV...");
+                self.download_csv(&output).await?;
+            }
+            Err(e)
+                if !fallback_shelves.is_empty()
+                    && e.downcast_ref::<ExportUnavailable>().is_some() =>
+            {
+                warn!("CSV export unavailable: {:?}", e);
+                eprintln!("CSV export unavailable ({}), scraping shelves instead", e);
+                self.scrape_shelves_to_csv(fallback_shelves, &output, scrape_page_delay)
+                    .await?;
+                eprintln!(
+                    "Warning: {} is a partial export with only the shelves {}. \
+                     Runs that need other shelves will not find them.",
+                    output.display(),
+                    fallback_shelves.join(", ")
+                );
+            }
+            Err(e) => return Err(e),
+        }
 
         eprintln!("Export saved to {}", output.display());
         Ok(())
     }
 }
+
+fn element_text(el: ElementRef) -> String {
+    el.text()
+        .flat_map(|t| t.split_whitespace())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Text of the `div.value` in the review row's `td.field.<field>` cell.
+fn field_text(row: ElementRef, field: &str) -> Option<String> {
+    let selector = Selector::parse(&format!("td.field.{} div.value", field)).ok()?;
+    row.select(&selector)
+        .next()
+        .map(element_text)
+        .filter(|t| !t.is_empty())
+}
+
+fn parse_leading_number<T: std::str::FromStr>(text: &str) -> Option<T> {
+    text.split(|c: char| !c.is_ascii_digit())
+        .find(|t| !t.is_empty())
+        .and_then(|t| t.parse().ok())
+}
+
+fn parse_year(text: &str) -> Option<i16> {
+    text.split(|c: char| !c.is_ascii_digit())
+        .rfind(|t| t.len() == 4)
+        .and_then(|t| t.parse().ok())
+}
+
+/// Convert the review list's "Mar 03, 2023" dates to the export's "2023/03/03".
+fn parse_list_date(text: &str) -> Option<String> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let mut parts = text
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|p| !p.is_empty());
+    let month_name = parts.next()?.to_lowercase();
+    let month = MONTHS.iter().position(|m| month_name.starts_with(m))? + 1;
+    let day: u32 = parts.next()?.parse().ok()?;
+    let year: u32 = parts.next()?.parse().ok()?;
+    Some(format!("{:04}/{:02}/{:02}", year, month, day))
+}
+
+/// Wrap ISBNs the way the export does, to keep spreadsheets from mangling them.
+fn export_isbn(isbn: Option<String>) -> String {
+    isbn.map(|i| format!("=\"{}\"", i))
+        .unwrap_or_else(|| "=\"\"".to_string())
+}
+
+fn parse_review_row(row: ElementRef, scraped_shelf: &str) -> Option<GoodReadsExportRecord> {
+    let title_selector = Selector::parse("td.field.title a").expect("valid CSS selector");
+    let author_selector = Selector::parse("td.field.author a").expect("valid CSS selector");
+    let shelf_selector = Selector::parse("td.field.shelves a").expect("valid CSS selector");
+    let added_selector =
+        Selector::parse("td.field.date_added div.value span").expect("valid CSS selector");
+
+    let title_link = row.select(&title_selector).next()?;
+    let book_id = title_link
+        .value()
+        .attr("href")?
+        .split("/book/show/")
+        .nth(1)
+        .and_then(parse_leading_number)?;
+    let title = element_text(title_link);
+
+    // The list shows "Last, First"; the export has both forms
+    let author_l_f = row
+        .select(&author_selector)
+        .next()
+        .map(element_text)
+        .unwrap_or_default();
+    let author = match author_l_f.split_once(", ") {
+        Some((last, first)) => format!("{} {}", first, last),
+        None => author_l_f.clone(),
+    };
+
+    let shelves: Vec<String> = row.select(&shelf_selector).map(element_text).collect();
+    let exclusive_shelf = shelves
+        .iter()
+        .find(|s| EXCLUSIVE_SHELVES.contains(&s.as_str()))
+        .cloned()
+        .unwrap_or_else(|| scraped_shelf.to_string());
+    let bookshelves = shelves
+        .iter()
+        .filter(|s| **s != exclusive_shelf)
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let date_added = row
+        .select(&added_selector)
+        .next()
+        .and_then(|el| {
+            el.value()
+                .attr("title")
+                .and_then(parse_list_date)
+                .or_else(|| parse_list_date(&element_text(el)))
+        })
+        .unwrap_or_default();
+    let original_publication_year = field_text(row, "date_pub").and_then(|t| parse_year(&t));
+
+    Some(GoodReadsExportRecord {
+        book_id,
+        title,
+        author,
+        author_l_f,
+        additional_authors: String::new(),
+        ISBN: export_isbn(field_text(row, "isbn")),
+        ISBN13: export_isbn(field_text(row, "isbn13")),
+        my_rating: None,
+        average_rating: field_text(row, "avg_rating").unwrap_or_default(),
+        publisher: String::new(),
+        binding: String::new(),
+        number_of_pages: field_text(row, "num_pages").and_then(|t| parse_leading_number(&t)),
+        year_published: field_text(row, "date_pub_edition")
+            .and_then(|t| parse_year(&t))
+            .or(original_publication_year),
+        original_publication_year,
+        date_read: None,
+        date_added,
+        bookshelves_with_positions: bookshelves.clone(),
+        bookshelves,
+        read_count: i64::from(exclusive_shelf == "read"),
+        exclusive_shelf,
+        my_review: None,
+        spoiler: None,
+        private_notes: None,
+        owned_copies: 0,
+    })
+}
+
+/// Parse the books on one page of `/review/list/<user_id>` (table view).
+fn parse_review_list(body: &str, scraped_shelf: &str) -> Vec<GoodReadsExportRecord> {
+    let document = Html::parse_document(body);
+    let row_selector = Selector::parse("tr.bookalike.review").expect("valid CSS selector");
+    document
+        .select(&row_selector)
+        .filter_map(|row| parse_review_row(row, scraped_shelf))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fake_goodreads;
+    use crate::fake_goodreads::ExportBehavior;
+    use crate::fake_goodreads::FakeGoodreads;
+
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    fn output_path(fake: &FakeGoodreads) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "gr2libby-export-test-{}-{}.csv",
+            std::process::id(),
+            fake.server.address().port()
+        ))
+    }
+
+    const REVIEW_LIST_PAGE: &str = r##"<html><body><table id="books"><tbody>
+<tr id="review_1" class="bookalike review">
+  <td class="field isbn"><div class="value">0316129089</div></td>
+  <td class="field isbn13"><div class="value">9780316129084</div></td>
+  <td class="field title"><div class="value"><a title="Leviathan Wakes" href="/book/show/8855321-leviathan-wakes">
+    Leviathan Wakes <span class="darkGreyText">(The Expanse, #1)</span></a></div></td>
+  <td class="field author"><div class="value"><a href="/author/show/4192148.James_S_A_Corey">Corey, James S.A.</a></div></td>
+  <td class="field num_pages"><div class="value"><nobr>592 <span class="greyText">pp</span></nobr></div></td>
+  <td class="field avg_rating"><div class="value">4.26</div></td>
+  <td class="field date_pub"><div class="value">Jun 02, 2011</div></td>
+  <td class="field shelves"><div class="value"><a class="shelfLink" href="#">to-read</a>, <a class="shelfLink" href="#">od-f</a></div></td>
+  <td class="field date_added"><div class="value"><span title="March 3, 2023">Mar 03, 2023</span></div></td>
+</tr>
+<tr id="review_2" class="bookalike review">
+  <td class="field title"><div class="value"><a href="/book/show/42.Dune">Dune</a></div></td>
+  <td class="field author"><div class="value"><a href="#">Herbert, Frank</a></div></td>
+  <td class="field num_pages"><div class="value"></div></td>
+</tr>
+</tbody></table></body></html>"##;
+
+    #[tokio::test]
+    async fn test_scraped_review_list_round_trips_through_export_csv() {
+        let records = parse_review_list(REVIEW_LIST_PAGE, "to-read");
+        assert_eq!(records.len(), 2);
+
+        let csv_path =
+            std::env::temp_dir().join(format!("gr2libby-scrape-test-{}.csv", std::process::id()));
+        let mut wtr = csv::Writer::from_path(&csv_path).expect("create csv");
+        for record in records {
+            wtr.serialize(record).expect("write record");
+        }
+        wtr.flush().expect("flush");
+
+        let books =
+            crate::goodreads::get_book_titles_from_goodreads_shelf(csv_path.clone(), "to-read")
+                .await
+                .expect("read csv");
+        std::fs::remove_file(&csv_path).expect("remove csv");
+
+        assert_eq!(books.len(), 2);
+        let expanse = &books[0];
+        assert_eq!(expanse.book_id, 8855321);
+        assert_eq!(expanse.title, "Leviathan Wakes (The Expanse, #1)");
+        assert_eq!(expanse.author, "James S.A. Corey");
+        assert_eq!(expanse.number_of_pages, Some(592));
+        assert_eq!(expanse.average_rating, Some(4.26));
+        assert_eq!(expanse.year_published, Some(2011));
+        assert_eq!(expanse.date_added, "2023/03/03");
+        assert_eq!(expanse.bookshelves, vec!["od-f".to_string()]);
+
+        let dune = &books[1];
+        assert_eq!(dune.book_id, 42);
+        assert_eq!(dune.author, "Frank Herbert");
+        assert_eq!(dune.number_of_pages, None);
+    }
+
+    #[tokio::test]
+    async fn test_export_polls_until_ready_and_downloads() {
+        let fake = FakeGoodreads::start(true, ExportBehavior::ReadyAfter(2)).await;
+        let exporter = fake.exporter().await;
+        assert_eq!(
+            exporter.scrape_csrf_token().await.expect("csrf token"),
+            fake_goodreads::CSRF_TOKEN
+        );
+
+        let output = output_path(&fake);
+        exporter
+            .export(output.clone(), POLL_INTERVAL, 5, &[], POLL_INTERVAL)
+            .await
+            .expect("export");
+        let csv = std::fs::read_to_string(&output).expect("read csv");
+        std::fs::remove_file(&output).expect("remove csv");
+        assert_eq!(csv, fake_goodreads::EXPORT_CSV);
+        assert_eq!(fake.polls().await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_export_signed_out() {
+        let fake = FakeGoodreads::start(false, ExportBehavior::ReadyAfter(0)).await;
+        let exporter = fake.exporter().await;
+        let err = exporter
+            .export(
+                output_path(&fake),
+                POLL_INTERVAL,
+                5,
+                &["to-read".to_string()],
+                POLL_INTERVAL,
+            )
+            .await
+            .expect_err("signed out");
+        assert!(err.to_string().contains("sign-in"), "{err}");
+
+        let report = exporter.check_session(0).await.expect("check session");
+        assert!(!report.signed_in);
+        assert_eq!(report.status, SessionStatus::Expired);
+    }
+
+    #[tokio::test]
+    async fn test_export_refused_with_expired_cookies() {
+        let fake = FakeGoodreads::start(true, ExportBehavior::Refused).await;
+        let exporter = fake.exporter().await;
+        let err = exporter
+            .export(output_path(&fake), POLL_INTERVAL, 5, &[], POLL_INTERVAL)
+            .await
+            .expect_err("refused");
+        assert!(
+            err.to_string().contains("cookies may have expired"),
+            "{err}"
+        );
+        assert_eq!(fake.polls().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_export_poll_timeout() {
+        let fake = FakeGoodreads::start(true, ExportBehavior::NeverReady).await;
+        let exporter = fake.exporter().await;
+        let err = exporter
+            .export(output_path(&fake), POLL_INTERVAL, 3, &[], POLL_INTERVAL)
+            .await
+            .expect_err("never ready");
+        assert!(
+            err.to_string().contains("not ready after 3 attempts"),
+            "{err}"
+        );
+        assert_eq!(fake.polls().await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_export_falls_back_to_scraping_shelves() {
+        let fake = FakeGoodreads::start(true, ExportBehavior::NeverReady).await;
+        let exporter = fake.exporter().await;
+        let output = output_path(&fake);
+        exporter
+            .export(
+                output.clone(),
+                POLL_INTERVAL,
+                2,
+                &["to-read".to_string()],
+                POLL_INTERVAL,
+            )
+            .await
+            .expect("export with fallback");
+
+        let books =
+            crate::goodreads::get_book_titles_from_goodreads_shelf(output.clone(), "to-read")
+                .await
+                .expect("read csv");
+        std::fs::remove_file(&output).expect("remove csv");
+        assert_eq!(books.len(), 1);
+        assert_eq!(books[0].title, "Project Hail Mary");
+        assert_eq!(books[0].author, "Andy Weir");
+    }
+
+    #[tokio::test]
+    async fn test_export_does_not_fall_back_on_other_errors() {
+        let fake = FakeGoodreads::start(true, ExportBehavior::PollFails).await;
+        let exporter = fake.exporter().await;
+        let output = output_path(&fake);
+        let err = exporter
+            .export(
+                output.clone(),
+                POLL_INTERVAL,
+                3,
+                &["to-read".to_string()],
+                POLL_INTERVAL,
+            )
+            .await
+            .expect_err("poll failed");
+        assert!(err.to_string().contains("unexpected status"), "{err}");
+        // Not silently replaced by a to-read only scrape
+        assert!(!output.exists());
+    }
+}
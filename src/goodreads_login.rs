@@ -0,0 +1,359 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use aes::cipher::BlockDecryptMut;
+use aes::cipher::KeyIvInit;
+use aes::cipher::block_padding::Pkcs7;
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::anyhow;
+use anyhow::bail;
+use rusqlite::Connection;
+use rusqlite::OpenFlags;
+use tracing::debug;
+use tracing::info;
+
+use crate::goodreads_export::GoodreadsConfig;
+
+/// Cookies for other subdomains (help.*, etc) are not needed for the export.
+const GOODREADS_HOSTS: [&str; 2] = [".goodreads.com", "www.goodreads.com"];
+
+/// Password Chromium uses on Linux when no keyring is available (v10 cookies).
+const CHROMIUM_LINUX_PASSWORD: &[u8] = b"peanuts";
+const CHROMIUM_SALT: &[u8] = b"saltysalt";
+/// Cookie DB version from which Chromium prefixes values with a SHA256 of the host.
+const CHROMIUM_DOMAIN_HASH_VERSION: i64 = 24;
+/// Seconds between the Windows epoch Chromium uses (1601-01-01) and the Unix epoch.
+const CHROMIUM_EPOCH_OFFSET_SECS: i64 = 11_644_473_600;
+
+#[derive(clap::ValueEnum, Clone, Debug, Copy)]
+pub enum Browser {
+    Firefox,
+    Chromium,
+}
+
+impl std::fmt::Display for Browser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Firefox => write!(f, "firefox"),
+            Self::Chromium => write!(f, "chromium"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Cookie {
+    name: String,
+    value: String,
+    /// Unix time the cookie expires at, `None` for session cookies
+    expires: Option<i64>,
+}
+
+/// Find cookie databases for the browser, most recently modified first.
+fn find_cookie_dbs(browser: Browser) -> Result<Vec<PathBuf>> {
+    let home = PathBuf::from(std::env::var("HOME").context("HOME is not set")?);
+    let (roots, file_name) = match browser {
+        Browser::Firefox => (
+            vec![
+                home.join(".mozilla/firefox"),
+                home.join("snap/firefox/common/.mozilla/firefox"),
+                home.join(".var/app/org.mozilla.firefox/.mozilla/firefox"),
+                home.join("Library/Application Support/Firefox"),
+            ],
+            "cookies.sqlite",
+        ),
+        Browser::Chromium => (
+            vec![
+                home.join(".config/chromium"),
+                home.join(".config/google-chrome"),
+                home.join(".config/BraveSoftware/Brave-Browser"),
+                home.join("snap/chromium/common/chromium"),
+            ],
+            "Cookies",
+        ),
+    };
+
+    let mut found = Vec::new();
+    for root in roots.iter().filter(|r| r.is_dir()) {
+        find_files(root, file_name, 3, &mut found);
+    }
+    found.sort_by_key(|p| {
+        std::cmp::Reverse(
+            p.metadata()
+                .and_then(|m| m.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH),
+        )
+    });
+    debug!("{} cookie DBs: {:#?}", browser, found);
+    Ok(found)
+}
+
+fn find_files(dir: &Path, file_name: &str, depth: usize, found: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            if depth > 0 {
+                find_files(&path, file_name, depth - 1, found);
+            }
+        } else if entry.file_name() == file_name {
+            found.push(path);
+        }
+    }
+}
+
+/// Remove the copy of a cookie DB along with the WAL and shared memory files
+/// SQLite keeps next to it.
+fn remove_cookie_db_copy(scratch: &Path) {
+    let _ = std::fs::remove_file(scratch);
+    for suffix in ["-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", scratch.display()));
+    }
+}
+
+/// Open a private copy of the cookie DB, since the browser keeps it locked while running.
+fn open_cookie_db(db_path: &Path, scratch: &Path) -> Result<Connection> {
+    // A WAL left behind by an earlier copy would be replayed into this one
+    remove_cookie_db_copy(scratch);
+    std::fs::copy(db_path, scratch)
+        .with_context(|| format!("copying cookie DB {}", db_path.display()))?;
+    // Recently written cookies may still be in the write-ahead log
+    let wal = PathBuf::from(format!("{}-wal", db_path.display()));
+    if wal.exists() {
+        std::fs::copy(&wal, format!("{}-wal", scratch.display())).context("copying cookie WAL")?;
+    }
+    Connection::open_with_flags(scratch, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("opening cookie DB {}", db_path.display()))
+}
+
+fn read_firefox_cookies(conn: &Connection) -> Result<Vec<Cookie>> {
+    let mut stmt = conn.prepare(
+        "SELECT name, value, expiry FROM moz_cookies WHERE host IN (?1, ?2) ORDER BY name",
+    )?;
+    let cookies = stmt
+        .query_map(GOODREADS_HOSTS, |row| {
+            // Newer Firefox versions store the expiry in milliseconds
+            let expiry: i64 = row.get(2)?;
+            Ok(Cookie {
+                name: row.get(0)?,
+                value: row.get(1)?,
+                expires: Some(if expiry > 100_000_000_000 {
+                    expiry / 1000
+                } else {
+                    expiry
+                }),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .context("reading moz_cookies")?;
+    Ok(cookies)
+}
+
+fn read_chromium_cookies(conn: &Connection) -> Result<Vec<Cookie>> {
+    let version: i64 = conn
+        .query_row("SELECT value FROM meta WHERE key = 'version'", [], |row| {
+            row.get::<_, String>(0)
+        })
+        .context("reading Chromium cookie DB version")?
+        .parse()
+        .context("parsing Chromium cookie DB version")?;
+    let strip_domain_hash = version >= CHROMIUM_DOMAIN_HASH_VERSION;
+
+    let mut stmt = conn.prepare(
+        "SELECT name, value, encrypted_value, expires_utc FROM cookies WHERE host_key IN (?1, ?2) ORDER BY name",
+    )?;
+    let rows = stmt
+        .query_map(GOODREADS_HOSTS, |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Vec<u8>>(2)?,
+                row.get::<_, i64>(3)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .context("reading Chromium cookies")?;
+
+    rows.into_iter()
+        .map(|(name, value, encrypted_value, expires_utc)| {
+            let value = if encrypted_value.is_empty() {
+                value
+            } else {
+                decrypt_chromium_value(&encrypted_value, strip_domain_hash)
+                    .with_context(|| format!("decrypting cookie '{}'", name))?
+            };
+            // Microseconds since 1601, zero for session cookies
+            let expires =
+                (expires_utc > 0).then(|| expires_utc / 1_000_000 - CHROMIUM_EPOCH_OFFSET_SECS);
+            Ok(Cookie {
+                name,
+                value,
+                expires,
+            })
+        })
+        .collect()
+}
+
+fn chromium_key(password: &[u8]) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    pbkdf2::pbkdf2_hmac::<sha1::Sha1>(password, CHROMIUM_SALT, 1, &mut key);
+    key
+}
+
+fn decrypt_chromium_value(encrypted: &[u8], strip_domain_hash: bool) -> Result<String> {
+    let (ciphertext, passwords): (&[u8], &[&[u8]]) =
+        if let Some(ciphertext) = encrypted.strip_prefix(b"v10") {
+            (ciphertext, &[CHROMIUM_LINUX_PASSWORD])
+        } else if let Some(ciphertext) = encrypted.strip_prefix(b"v11") {
+            // v11 is keyed from the desktop keyring, which falls back to an
+            // empty password when the keyring is unavailable
+            (ciphertext, &[b"", CHROMIUM_LINUX_PASSWORD])
+        } else {
+            bail!("unsupported Chromium cookie encryption");
+        };
+
+    let mut plaintext = passwords
+        .iter()
+        .find_map(|password| {
+            let mut buf = ciphertext.to_vec();
+            cbc::Decryptor::<aes::Aes128>::new(&chromium_key(password).into(), &[b' '; 16].into())
+                .decrypt_padded_mut::<Pkcs7>(&mut buf)
+                .ok()
+                .map(|p| p.to_vec())
+        })
+        .context(
+            "unable to decrypt with the default Linux key. If the browser uses a keyring, \
+             start it with --password-store=basic and log in to Goodreads again",
+        )?;
+    if strip_domain_hash {
+        if plaintext.len() < 32 {
+            bail!("decrypted cookie is shorter than its domain hash");
+        }
+        plaintext.drain(..32);
+    }
+    let value =
+        String::from_utf8(plaintext).map_err(|e| anyhow!("decrypted cookie is not UTF-8: {e}"))?;
+    // A wrong key can still leave valid padding, so check it looks like a
+    // cookie value before sending it anywhere
+    if !value.chars().all(|c| c.is_ascii_graphic() && c != ';') {
+        bail!("decrypted cookie is not a printable ASCII cookie value");
+    }
+    Ok(value)
+}
+
+fn read_goodreads_cookies(browser: Browser, db_path: &Path) -> Result<Vec<Cookie>> {
+    let scratch =
+        std::env::temp_dir().join(format!("gr2libby-cookies-{}.sqlite", std::process::id()));
+    let cookies = open_cookie_db(db_path, &scratch).and_then(|conn| match browser {
+        Browser::Firefox => read_firefox_cookies(&conn),
+        Browser::Chromium => read_chromium_cookies(&conn),
+    });
+    remove_cookie_db_copy(&scratch);
+    cookies
+}
+
+/// Build a `GoodreadsConfig` from the Goodreads session cookies of a local
+/// browser profile. When `cookie_db` is not given, the browser's profiles are
+/// searched and the most recently used one with Goodreads cookies wins.
+pub fn extract_goodreads_config(
+    browser: Browser,
+    cookie_db: Option<PathBuf>,
+    user_id: Option<String>,
+) -> Result<GoodreadsConfig> {
+    let candidates = match cookie_db {
+        Some(path) => vec![path],
+        None => find_cookie_dbs(browser)?,
+    };
+    if candidates.is_empty() {
+        bail!("no {} cookie databases found", browser);
+    }
+
+    let mut cookies = Vec::new();
+    for db_path in candidates {
+        cookies = read_goodreads_cookies(browser, &db_path)?;
+        if !cookies.is_empty() {
+            info!(
+                "Found {} Goodreads cookies in {}",
+                cookies.len(),
+                db_path.display()
+            );
+            break;
+        }
+        debug!("No Goodreads cookies in {}", db_path.display());
+    }
+    if cookies.is_empty() {
+        bail!(
+            "no Goodreads cookies found. Make sure you're logged in to goodreads.com in {}.",
+            browser
+        );
+    }
+
+    // The 'u' cookie carries the user id
+    let user_id = user_id
+        .or_else(|| {
+            cookies
+                .iter()
+                .find(|c| c.name == "u" && !c.value.is_empty())
+                .map(|c| c.value.clone())
+        })
+        .context(
+            "could not determine your Goodreads user id from the cookies. Pass --user-id; \
+             it is the number in the export URL on https://www.goodreads.com/review/import",
+        )?;
+
+    Ok(GoodreadsConfig {
+        user_id,
+        cookies: cookies
+            .iter()
+            .map(|c| format!("{}={}", c.name, c.value))
+            .collect::<Vec<_>>()
+            .join("; "),
+        cookie_expires: cookies
+            .iter()
+            .filter_map(|c| c.expires.map(|e| (c.name.clone(), e)))
+            .collect::<BTreeMap<_, _>>(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use aes::cipher::BlockEncryptMut;
+
+    use super::*;
+
+    fn encrypt(password: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; plaintext.len() + 16];
+        let ciphertext =
+            cbc::Encryptor::<aes::Aes128>::new(&chromium_key(password).into(), &[b' '; 16].into())
+                .encrypt_padded_b2b_mut::<Pkcs7>(plaintext, &mut buf)
+                .expect("encrypt")
+                .to_vec();
+        [b"v10".as_slice(), &ciphertext].concat()
+    }
+
+    #[test]
+    fn test_decrypt_chromium_value() {
+        let encrypted = encrypt(CHROMIUM_LINUX_PASSWORD, b"session-id=abc");
+        assert_eq!(
+            decrypt_chromium_value(&encrypted, false).expect("decrypt"),
+            "session-id=abc"
+        );
+
+        let hashed = [[7u8; 32].as_slice(), b"abc"].concat();
+        let encrypted = encrypt(CHROMIUM_LINUX_PASSWORD, &hashed);
+        assert_eq!(
+            decrypt_chromium_value(&encrypted, true).expect("decrypt"),
+            "abc"
+        );
+
+        assert!(decrypt_chromium_value(b"v20garbage", false).is_err());
+        for garbage in [b"\xff\xfe\x00abc".as_slice(), b"abc\r\ndef", b"a;b"] {
+            let encrypted = encrypt(CHROMIUM_LINUX_PASSWORD, garbage);
+            assert!(decrypt_chromium_value(&encrypted, false).is_err());
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! Availability of Libby titles over time. Each `browse` run appends a
+//! snapshot of every Libby result to a SQLite file, which the
+//! `history-report` command and the browse page's trend column read back.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use anyhow::Result;
+use colored::Colorize;
+use rusqlite::Connection;
+use rusqlite::params;
+
+use crate::browse::BrowseResult;
+use crate::browse::BrowseSource;
+
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// One title's availability at one time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    /// Seconds since the epoch
+    pub taken_at: i64,
+    pub is_available: bool,
+    pub holds_count: Option<i64>,
+    pub estimated_wait_days: Option<i64>,
+    pub owned_copies: Option<i64>,
+    pub available_copies: Option<i64>,
+}
+
+impl Snapshot {
+    /// Days to wait, zero when available. None when Libby gave no estimate.
+    pub fn wait_days(&self) -> Option<i64> {
+        if self.is_available {
+            Some(0)
+        } else {
+            self.estimated_wait_days
+        }
+    }
+}
+
+/// How a title's availability has changed across its snapshots.
+#[derive(Debug, Clone)]
+pub struct TitleTrend {
+    pub libby_id: String,
+    pub title: String,
+    /// Oldest first
+    pub snapshots: Vec<Snapshot>,
+}
+
+impl TitleTrend {
+    /// Known waits, oldest first.
+    pub fn waits(&self) -> Vec<i64> {
+        self.snapshots
+            .iter()
+            .filter_map(|s| s.wait_days())
+            .collect()
+    }
+
+    /// Latest wait minus the first one; negative when it is shrinking.
+    pub fn wait_change(&self) -> Option<i64> {
+        let waits = self.waits();
+        Some(waits.last()? - waits.first()?)
+    }
+
+    /// Copies the library has added since the first snapshot.
+    pub fn copies_added(&self) -> i64 {
+        let owned: Vec<i64> = self
+            .snapshots
+            .iter()
+            .filter_map(|s| s.owned_copies)
+            .collect();
+        match (owned.first(), owned.last()) {
+            (Some(first), Some(last)) if last > first => last - first,
+            _ => 0,
+        }
+    }
+}
+
+/// A bar per value, scaled between the smallest and largest.
+pub fn sparkline(values: &[i64]) -> String {
+    let (Some(min), Some(max)) = (values.iter().min(), values.iter().max()) else {
+        return String::new();
+    };
+    let range = (max - min).max(1);
+    values
+        .iter()
+        .map(|v| SPARKS[((v - min) * (SPARKS.len() as i64 - 1) / range) as usize])
+        .collect()
+}
+
+pub struct AvailabilityHistory {
+    conn: Connection,
+}
+
+impl AvailabilityHistory {
+    /// Open the history file, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("opening history {}", path.display()))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS snapshots (
+                libby_id TEXT NOT NULL,
+                taken_at INTEGER NOT NULL,
+                title TEXT NOT NULL,
+                is_available INTEGER NOT NULL,
+                holds_count INTEGER,
+                estimated_wait_days INTEGER,
+                owned_copies INTEGER,
+                available_copies INTEGER,
+                PRIMARY KEY (libby_id, taken_at)
+            )",
+        )
+        .context("creating history table")?;
+        Ok(Self { conn })
+    }
+
+    /// Append the Libby results as taken now. Returns how many were stored.
+    pub fn record(&mut self, results: &[BrowseResult]) -> Result<usize> {
+        let taken_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs() as i64;
+        self.record_at(results, taken_at)
+    }
+
+    fn record_at(&mut self, results: &[BrowseResult], taken_at: i64) -> Result<usize> {
+        let tx = self.conn.transaction()?;
+        let mut recorded = 0;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO snapshots (libby_id, taken_at, title, is_available,
+                    holds_count, estimated_wait_days, owned_copies, available_copies)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?;
+            for r in results.iter().filter(|r| r.source == BrowseSource::Libby) {
+                stmt.execute(params![
+                    r.source_id,
+                    taken_at,
+                    r.title,
+                    r.is_available,
+                    r.holds_count,
+                    r.estimated_wait_days,
+                    r.owned_copies,
+                    r.available_copies,
+                ])?;
+                recorded += 1;
+            }
+        }
+        tx.commit().context("saving snapshots")?;
+        Ok(recorded)
+    }
+
+    /// Every title's snapshots, by Libby id.
+    pub fn trends(&self) -> Result<Vec<TitleTrend>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT libby_id, title, taken_at, is_available, holds_count,
+                estimated_wait_days, owned_copies, available_copies
+             FROM snapshots ORDER BY libby_id, taken_at",
+        )?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    Snapshot {
+                        taken_at: row.get(2)?,
+                        is_available: row.get(3)?,
+                        holds_count: row.get(4)?,
+                        estimated_wait_days: row.get(5)?,
+                        owned_copies: row.get(6)?,
+                        available_copies: row.get(7)?,
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("reading snapshots")?;
+
+        let mut trends: BTreeMap<String, TitleTrend> = BTreeMap::new();
+        for (libby_id, title, snapshot) in rows {
+            let trend = trends
+                .entry(libby_id.clone())
+                .or_insert_with(|| TitleTrend {
+                    libby_id,
+                    title: String::new(),
+                    snapshots: vec![],
+                });
+            // The latest title wins
+            trend.title = title;
+            trend.snapshots.push(snapshot);
+        }
+        Ok(trends.into_values().collect())
+    }
+
+    /// Store the results, then give each Libby result its wait history.
+    pub fn record_and_annotate(&mut self, results: &mut [BrowseResult]) -> Result<()> {
+        self.record(results)?;
+        let trends: BTreeMap<String, TitleTrend> = self
+            .trends()?
+            .into_iter()
+            .map(|t| (t.libby_id.clone(), t))
+            .collect();
+        for r in results
+            .iter_mut()
+            .filter(|r| r.source == BrowseSource::Libby)
+        {
+            if let Some(trend) = trends.get(&r.source_id) {
+                r.wait_history = trend.waits();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Titles whose wait is shrinking, titles with new copies, then every title
+/// with more than one snapshot and its wait sparkline.
+pub fn render_report(trends: &[TitleTrend]) -> String {
+    let mut out = String::new();
+
+    let mut shrinking: Vec<(&TitleTrend, i64)> = trends
+        .iter()
+        .filter_map(|t| t.wait_change().filter(|c| *c < 0).map(|c| (t, c)))
+        .collect();
+    shrinking.sort_by_key(|(_, change)| *change);
+    out.push_str(&format!("{}\n", "Waits shrinking".bold()));
+    if shrinking.is_empty() {
+        out.push_str("  none\n");
+    }
+    for (trend, change) in shrinking {
+        let waits = trend.waits();
+        out.push_str(&format!(
+            "  {} {} ({}d -> {}d, {})\n",
+            sparkline(&waits),
+            trend.title,
+            waits.first().unwrap_or(&0),
+            waits.last().unwrap_or(&0),
+            format!("{}d", change).green()
+        ));
+    }
+
+    let added: Vec<&TitleTrend> = trends.iter().filter(|t| t.copies_added() > 0).collect();
+    out.push_str(&format!("\n{}\n", "New copies".bold()));
+    if added.is_empty() {
+        out.push_str("  none\n");
+    }
+    for trend in added {
+        out.push_str(&format!(
+            "  {} {}\n",
+            format!("+{}", trend.copies_added()).green(),
+            trend.title
+        ));
+    }
+
+    out.push_str(&format!("\n{}\n", "Wait trends".bold()));
+    for trend in trends.iter().filter(|t| t.snapshots.len() > 1) {
+        let waits = trend.waits();
+        let latest = waits
+            .last()
+            .map(|w| format!("{}d", w))
+            .unwrap_or_else(|| "?".to_string());
+        out.push_str(&format!(
+            "  {:<12} {:>5}  {}\n",
+            sparkline(&waits),
+            latest,
+            trend.title
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn result(libby_id: &str, wait: Option<i64>, owned: i64) -> BrowseResult {
+        BrowseResult {
+            title: format!("Title {libby_id}"),
+            author: "Someone".to_string(),
+            pages: None,
+            goodreads_shelves: vec![],
+            source: BrowseSource::Libby,
+            source_id: libby_id.to_string(),
+            also_on: vec![],
+            goodreads_id: 1,
+            is_available: wait.is_none(),
+            estimated_wait_days: wait,
+            holds_count: None,
+            owned_copies: Some(owned),
+            available_copies: None,
+            has_kindle: None,
+            duration_minutes: None,
+            subjects: vec![],
+            average_rating: None,
+            year_published: None,
+            date_added: String::new(),
+            private_notes: None,
+            wait_history: vec![],
+        }
+    }
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[]), "");
+        assert_eq!(sparkline(&[0, 7, 14]), "▁▄█");
+        assert_eq!(sparkline(&[5, 5]), "▁▁");
+    }
+
+    #[test]
+    fn test_history_trends() {
+        let path =
+            std::env::temp_dir().join(format!("gr2libby-history-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut history = AvailabilityHistory::open(&path).expect("open");
+        history
+            .record_at(&[result("1", Some(30), 1), result("2", Some(7), 2)], 100)
+            .expect("record");
+        history
+            .record_at(&[result("1", Some(14), 2), result("2", Some(10), 2)], 200)
+            .expect("record");
+
+        let mut results = [result("1", None, 2)];
+        history.record_and_annotate(&mut results).expect("annotate");
+        assert_eq!(results[0].wait_history, vec![30, 14, 0]);
+
+        let trends = history.trends().expect("trends");
+        assert_eq!(trends.len(), 2);
+        assert_eq!(trends[0].wait_change(), Some(-30));
+        assert_eq!(trends[0].copies_added(), 1);
+        assert_eq!(trends[1].wait_change(), Some(3));
+        assert_eq!(trends[1].copies_added(), 0);
+
+        colored::control::set_override(false);
+        let report = render_report(&trends);
+        assert!(report.contains("Title 1 (30d -> 0d, -30d)"));
+        assert!(report.contains("+1 Title 1"));
+        assert!(!report.contains("Title 2 ("));
+        let _ = std::fs::remove_file(&path);
+    }
+}
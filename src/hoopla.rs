@@ -0,0 +1,356 @@
+use std::collections::HashSet;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use reqwest::header::HeaderMap;
+use reqwest::header::HeaderValue;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::debug;
+
+use crate::goodreads::clean_isbn;
+use crate::normalize::authors_match;
+use crate::normalize::normalize_title;
+use crate::normalize::search_queries;
+use crate::request_policy::RequestLayer;
+use crate::request_policy::RequestPolicy;
+
+pub const HOOPLA_BASE: &str = "https://patron-api-gateway.hoopladigital.com";
+const SEARCH_PAGE_SIZE: usize = 20;
+const SEARCH_QUERY: &str = "query FilterSearch($criteria: SearchCriteria!) { \
+    search(criteria: $criteria) { found titles { id title subtitle isbn year pages \
+    kind { name } primaryArtist { name } } } }";
+
+/// Hoopla formats we look for. Everything Hoopla has can be borrowed
+/// instantly, there are no holds.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HooplaKind {
+    Audiobook,
+    Ebook,
+    Comic,
+}
+
+impl HooplaKind {
+    /// Hoopla's id for the kind in search criteria.
+    fn kind_id(&self) -> i64 {
+        match self {
+            Self::Ebook => 5,
+            Self::Audiobook => 8,
+            Self::Comic => 10,
+        }
+    }
+}
+
+impl std::fmt::Display for HooplaKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Audiobook => write!(f, "audiobook"),
+            Self::Ebook => write!(f, "ebook"),
+            Self::Comic => write!(f, "comic"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HooplaName {
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HooplaTitle {
+    pub id: String,
+    pub title: String,
+    pub subtitle: Option<String>,
+    pub isbn: Option<String>,
+    pub year: Option<i16>,
+    pub pages: Option<i64>,
+    pub kind: Option<HooplaName>,
+    pub primary_artist: Option<HooplaName>,
+}
+
+impl HooplaTitle {
+    pub fn author(&self) -> &str {
+        self.primary_artist
+            .as_ref()
+            .map(|a| a.name.as_str())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchResult {
+    #[allow(dead_code)]
+    found: i64,
+    titles: Vec<HooplaTitle>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchData {
+    search: SearchResult,
+}
+
+#[derive(Deserialize, Debug)]
+struct GraphQlResponse {
+    data: Option<SearchData>,
+    #[serde(default)]
+    errors: Vec<serde_json::Value>,
+}
+
+/// Whether a result is the wanted title, compared as normalized titles with
+/// and without the subtitles on either side, so "Dune" is not taken for
+/// "Dune Messiah".
+fn title_matches(wanted: &str, title: &HooplaTitle) -> bool {
+    let main = |t: &str| t.split_once(':').map_or(t, |(main, _)| main).to_string();
+    let wanted = [normalize_title(wanted), normalize_title(&main(wanted))];
+    let mut found = vec![normalize_title(&title.title)];
+    if let Some(subtitle) = &title.subtitle {
+        found.push(normalize_title(&format!("{}: {}", title.title, subtitle)));
+    }
+    found.iter().any(|f| !f.is_empty() && wanted.contains(f))
+}
+
+fn author_matches(authors: &HashSet<String>, title: &HooplaTitle) -> bool {
+    authors.is_empty() || authors.iter().any(|a| authors_match(a, title.author()))
+}
+
+/// Search client for Hoopla's public catalog. No login is needed to search.
+pub struct HooplaClient {
+    client: reqwest::Client,
+    base: String,
+    requests: RequestLayer,
+}
+
+impl HooplaClient {
+    pub fn new(base: impl Into<String>) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        headers.insert("app", HeaderValue::from_static("WWW"));
+        headers.insert(
+            "apollographql-client-name",
+            HeaderValue::from_static("hoopla-www"),
+        );
+        headers.insert("Accept", HeaderValue::from_static("application/json"));
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .context("building reqwest client")?;
+        Ok(Self {
+            client,
+            base: base.into().trim_end_matches('/').to_string(),
+            requests: RequestLayer::new(RequestPolicy::default()),
+        })
+    }
+
+    /// Replace the default rate limits and retry behaviour.
+    pub fn with_request_policy(mut self, policy: RequestPolicy) -> Self {
+        self.requests = RequestLayer::new(policy);
+        self
+    }
+
+    /// Search the catalog by title, author or ISBN.
+    pub async fn search(&self, query: &str, kind: HooplaKind) -> Result<Vec<HooplaTitle>> {
+        let url = format!("{}/graphql", self.base);
+        let payload = json!({
+            "operationName": "FilterSearch",
+            "query": SEARCH_QUERY,
+            "variables": {
+                "criteria": {
+                    "q": query,
+                    "kindId": kind.kind_id(),
+                    "availability": "ALL_TITLES",
+                    "pagination": { "page": 1, "pageSize": SEARCH_PAGE_SIZE },
+                },
+            },
+        });
+        let resp = self
+            .requests
+            .send(|| self.client.post(&url).json(&payload))
+            .await
+            .context("hoopla search request")?;
+        if !resp.status().is_success() {
+            bail!("hoopla search failed (status {})", resp.status());
+        }
+        let body = resp.text().await.context("reading hoopla response")?;
+        let response: GraphQlResponse = serde_json::from_str(&body).map_err(|e| {
+            debug!("Unparsable hoopla response: '{}'", body);
+            anyhow::anyhow!("unexpected hoopla response: {e}")
+        })?;
+        if !response.errors.is_empty() {
+            bail!("hoopla search errors: {:?}", response.errors);
+        }
+        Ok(response.data.map(|d| d.search.titles).unwrap_or_default())
+    }
+
+    /// Find a book by ISBN when there is one, otherwise by title, keeping
+    /// the first result by one of `authors`.
+    pub async fn find_book(
+        &self,
+        title: &str,
+        authors: &HashSet<String>,
+        isbn: Option<&str>,
+        kind: HooplaKind,
+    ) -> Result<Option<HooplaTitle>> {
+        if let Some(isbn) = isbn.and_then(clean_isbn) {
+            let found = self.search(isbn, kind).await?;
+            if let Some(t) = found.into_iter().find(|t| author_matches(authors, t)) {
+                return Ok(Some(t));
+            }
+        }
+        // Like Libby, the search does not cope well with series, edition
+        // notes or subtitles
+        for query in search_queries(title) {
+            if let Some(t) = self
+                .search(&query, kind)
+                .await?
+                .into_iter()
+                .find(|t| author_matches(authors, t) && title_matches(title, t))
+            {
+                return Ok(Some(t));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use wiremock::Mock;
+    use wiremock::MockServer;
+    use wiremock::ResponseTemplate;
+    use wiremock::matchers::body_partial_json;
+    use wiremock::matchers::method;
+    use wiremock::matchers::path;
+
+    use super::*;
+
+    const SEARCH_RESPONSE: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/hoopla/search_project_hail_mary.json"
+    ));
+
+    #[tokio::test]
+    async fn test_find_book_skips_shorter_titles() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/graphql"))
+            .and(body_partial_json(
+                json!({ "variables": { "criteria": { "q": "Dune Messiah" } } }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                include_str!(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/tests/fixtures/hoopla/search_dune_messiah.json"
+                )),
+                "application/json",
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/graphql"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                r#"{"data":{"search":{"found":0,"titles":[]}}}"#,
+                "application/json",
+            ))
+            .with_priority(10)
+            .mount(&server)
+            .await;
+        let hoopla = HooplaClient::new(server.uri()).expect("client");
+        let authors = HashSet::from(["Frank Herbert".to_string()]);
+
+        // Searched without the series, and "Dune" comes first but is another
+        // book
+        let found = hoopla
+            .find_book(
+                "Dune Messiah (Dune, #2)",
+                &authors,
+                None,
+                HooplaKind::Audiobook,
+            )
+            .await
+            .expect("search")
+            .expect("found");
+        assert_eq!(found.id, "11500002");
+        assert!(
+            hoopla
+                .find_book(
+                    "Dune Messiah Revisited",
+                    &authors,
+                    None,
+                    HooplaKind::Audiobook
+                )
+                .await
+                .expect("search")
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_find_book() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/graphql"))
+            .and(body_partial_json(
+                json!({ "variables": { "criteria": { "q": "Project Hail Mary", "kindId": 8 } } }),
+            ))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_raw(SEARCH_RESPONSE, "application/json"),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/graphql"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                r#"{"data":{"search":{"found":0,"titles":[]}}}"#,
+                "application/json",
+            ))
+            .with_priority(10)
+            .mount(&server)
+            .await;
+        let hoopla = HooplaClient::new(server.uri()).expect("client");
+
+        let authors = HashSet::from(["Andy Weir".to_string()]);
+        let found = hoopla
+            .find_book(
+                "Project Hail Mary: A Novel",
+                &authors,
+                Some("=\"\""),
+                HooplaKind::Audiobook,
+            )
+            .await
+            .expect("search")
+            .expect("found");
+        assert_eq!(found.id, "14288513");
+        assert_eq!(found.author(), "Andy Weir");
+
+        // Names are compared like Libby's, in any order
+        let reordered = HashSet::from(["Weir, Andy".to_string()]);
+        let found = hoopla
+            .find_book("Project Hail Mary", &reordered, None, HooplaKind::Audiobook)
+            .await
+            .expect("search")
+            .expect("found reordered");
+        assert_eq!(found.id, "14288513");
+
+        let other_author = HashSet::from(["Someone Else".to_string()]);
+        assert!(
+            hoopla
+                .find_book(
+                    "Project Hail Mary",
+                    &other_author,
+                    None,
+                    HooplaKind::Audiobook
+                )
+                .await
+                .expect("search")
+                .is_none()
+        );
+        assert!(
+            hoopla
+                .find_book("Project Hail Mary", &authors, None, HooplaKind::Ebook)
+                .await
+                .expect("search")
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,62 @@
+//! State and caches kept between runs as pretty-printed JSON files.
+
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::Context;
+use anyhow::Result;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tracing::warn;
+
+/// Read the state in `path`, starting from the default when there is none
+/// yet. A file that can't be read or parsed is reported and also replaced
+/// by the default.
+pub async fn load<T: DeserializeOwned + Default>(path: &Path) -> T {
+    let data = match tokio::fs::read_to_string(path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return T::default(),
+        Err(e) => {
+            warn!("Reading {} failed, starting over: {}", path.display(), e);
+            return T::default();
+        }
+    };
+    serde_json::from_str(&data).unwrap_or_else(|e| {
+        warn!("{} is not valid, starting over: {}", path.display(), e);
+        T::default()
+    })
+}
+
+/// Write the state to `path`.
+pub async fn save<T: Serialize>(state: &T, path: &Path) -> Result<()> {
+    let data = serde_json::to_string_pretty(state)?;
+    tokio::fs::write(path, data)
+        .await
+        .with_context(|| format!("writing {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_load_falls_back_to_default() {
+        let path =
+            std::env::temp_dir().join(format!("gr2libby-json-state-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let missing: BTreeMap<String, i64> = load(&path).await;
+        assert!(missing.is_empty());
+
+        let state = BTreeMap::from([("a".to_string(), 1)]);
+        save(&state, &path).await.expect("save");
+        assert_eq!(load::<BTreeMap<String, i64>>(&path).await, state);
+
+        std::fs::write(&path, "{ not json").expect("corrupt");
+        let corrupt: BTreeMap<String, i64> = load(&path).await;
+        assert!(corrupt.is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+}
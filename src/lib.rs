@@ -0,0 +1,83 @@
+//! Libby and Goodreads clients behind the `gr2libby` command line tool.
+//!
+//! The pieces the CLI is built from are usable on their own:
+//!
+//! - [`libby::login`] turns a Libby "copy to another device" code into a
+//!   [`libby::LibbyConfig`], and [`libby::get_cards`] lists the cards on it.
+//! - [`libby::LibbyClient`] searches a card's library and adds or removes
+//!   books on Libby tags. Failures are reported as [`libby::LibbyError`].
+//! - [`goodreads`] reads a Goodreads export CSV into [`goodreads::BookInfo`]s,
+//!   and [`goodreads_export::GoodreadsExporter`] downloads that CSV (or
+//!   scrapes the shelves) with a browser session from [`goodreads_login`].
+//! - [`catalog`] has the traits the tagging and browsing code is written
+//!   against, implemented by `LibbyClient` and an in-memory library.
+//! - [`history::AvailabilityHistory`] keeps each browse's availability in
+//!   SQLite and reports how waits change.
+//! - [`hoopla::HooplaClient`] searches Hoopla's catalog.
+//! - [`json_state`] loads and saves the JSON files commands keep between
+//!   runs.
+//! - [`normalize`] turns titles into comparison keys and search queries,
+//!   and compares author names across scripts and transliterations.
+//! - [`openlibrary::OpenLibraryClient`] fills in page counts, years and ISBNs
+//!   missing from an export.
+//! - [`browse::browse`] writes an HTML page of a Goodreads shelf as found in
+//!   the library (and optionally on Hoopla), or serves it with buttons that
+//!   tag, hold or borrow from each row.
+//! - [`missing::track_missing`] remembers shelf books the library doesn't
+//!   own and reports the ones it has since bought.
+//! - [`recommend`] ranks the shelf's books the library could buy, and can
+//!   send them to it as purchase recommendations.
+//! - [`series`] reads series and numbers from Goodreads titles and OverDrive
+//!   metadata, and picks the next unread book of each series.
+//! - [`watch::watch`] keeps checking a shelf's titles and notifies when one
+//!   can be borrowed.
+//!
+//! ```no_run
+//! use std::collections::HashSet;
+//!
+//! use gr2libby::libby::BookType;
+//! use gr2libby::libby::LibbyClient;
+//! use gr2libby::libby::SearchOptions;
+//!
+//! # async fn run() -> Result<(), gr2libby::libby::LibbyError> {
+//! let client = LibbyClient::new("libby_config.json".into(), "1234567".into()).await?;
+//! let tag = client.get_existing_tag_by_name("🎧").await?;
+//! let authors = HashSet::from(["Andy Weir".to_string()]);
+//! let book = client
+//!     .search_for_book_by_title(
+//!         SearchOptions {
+//!             book_type: BookType::Audiobook,
+//!             deep_search: false,
+//!             max_results: 24,
+//!         },
+//!         "Project Hail Mary",
+//!         Some(&authors),
+//!     )
+//!     .await?;
+//! client.tag_book_by_overdrive_id(&tag, &book.libby_id).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod browse;
+mod browse_server;
+pub mod catalog;
+pub mod goodreads;
+pub mod goodreads_export;
+pub mod goodreads_login;
+pub mod history;
+pub mod hoopla;
+pub mod json_state;
+pub mod libby;
+pub mod missing;
+pub mod normalize;
+pub mod openlibrary;
+pub mod recommend;
+pub mod request_policy;
+pub mod series;
+pub mod watch;
+
+#[cfg(test)]
+mod fake_goodreads;
+#[cfg(test)]
+mod fake_libby;
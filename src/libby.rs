@@ -1,5 +1,8 @@
 use std::collections::HashSet;
+use std::path::Path;
 use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::Duration;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
@@ -10,6 +13,7 @@ use base64::Engine;
 use clap::Parser;
 use itertools::Itertools;
 use reqwest::IntoUrl;
+use reqwest::StatusCode;
 use reqwest::header::HeaderMap;
 use reqwest::header::HeaderValue;
 use serde::Deserialize;
@@ -17,10 +21,65 @@ use serde::Serialize;
 use serde_json::json;
 use tracing::debug;
 
+use crate::normalize;
+use crate::request_policy::RequestLayer;
+use crate::request_policy::RequestPolicy;
+use crate::request_policy::RequestStats;
+use crate::request_policy::retry_after;
+
 const C: &str = "d:18.4.0";
 const V: &str = "eb643ccd";
 const S: &str = "0";
 
+const SENTRY_BASE: &str = "https://sentry.libbyapp.com";
+const VANDAL_BASE: &str = "https://vandal.libbyapp.com";
+const THUNDER_BASE: &str = "https://thunder.api.overdrive.com";
+
+/// Base URLs of the Libby (sentry, vandal) and OverDrive (thunder) services.
+/// Overridable so the client can be pointed at a local fake server.
+#[derive(clap::Args, Debug, Clone)]
+pub struct LibbyEndpoints {
+    /// Base URL of the Libby identity and card service
+    #[clap(long, env = "LIBBY_SENTRY_BASE", default_value = SENTRY_BASE, global = true, hide = true)]
+    pub sentry_base: String,
+
+    /// Base URL of the Libby tag service
+    #[clap(long, env = "LIBBY_VANDAL_BASE", default_value = VANDAL_BASE, global = true, hide = true)]
+    pub vandal_base: String,
+
+    /// Base URL of the OverDrive library catalog
+    #[clap(long, env = "LIBBY_THUNDER_BASE", default_value = THUNDER_BASE, global = true, hide = true)]
+    pub thunder_base: String,
+}
+
+impl Default for LibbyEndpoints {
+    fn default() -> Self {
+        Self {
+            sentry_base: SENTRY_BASE.to_string(),
+            vandal_base: VANDAL_BASE.to_string(),
+            thunder_base: THUNDER_BASE.to_string(),
+        }
+    }
+}
+
+impl LibbyEndpoints {
+    fn sentry(&self, path: &str) -> String {
+        format!("{}{}", self.sentry_base.trim_end_matches('/'), path)
+    }
+
+    fn vandal(&self, path: &str) -> String {
+        format!("{}{}", self.vandal_base.trim_end_matches('/'), path)
+    }
+
+    fn thunder(&self, path: &str) -> String {
+        format!("{}{}", self.thunder_base.trim_end_matches('/'), path)
+    }
+
+    fn chip_url(&self) -> String {
+        self.sentry(&format!("/chip?c={C}&s={S}&v={V}"))
+    }
+}
+
 #[derive(Clone, Debug, Parser)]
 pub struct LibbyUser {
     /// Card id as known by libbyapp
@@ -31,6 +90,7 @@ pub struct LibbyUser {
     pub library_advantage_key: Option<String>,
 }
 
+/// Saved login, as written to libby_config.json.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct LibbyConfig {
     bearer_token: String,
@@ -48,10 +108,11 @@ struct CodeClone {
     chip: String,
 }
 
-pub async fn login(code: String) -> Result<LibbyConfig> {
+/// Log in with a code from Libby's "copy to another device".
+pub async fn login(code: String, endpoints: &LibbyEndpoints) -> Result<LibbyConfig> {
     // Post to /chip to get identity
     let client = LibbyClient::reqwest_client()?;
-    let url = format!("https://sentry.libbyapp.com/chip?c={C}&s={S}&v={V}");
+    let url = endpoints.chip_url();
     let chip: Chip = client
         .post(url)
         .send()
@@ -63,7 +124,7 @@ pub async fn login(code: String) -> Result<LibbyConfig> {
 
     // post to /code with json like: {"code":"12345678"} to do login
     let payload = json!({"code": code});
-    let url = "https://sentry.libbyapp.com/chip/clone/code";
+    let url = endpoints.sentry("/chip/clone/code");
     let code_clone: CodeClone = client
         .post(url)
         .bearer_auth(&chip.identity)
@@ -79,7 +140,7 @@ pub async fn login(code: String) -> Result<LibbyConfig> {
     }
 
     // Post to chip again to get signed in identity
-    let url = format!("https://sentry.libbyapp.com/chip?c={C}&s={S}&v={V}");
+    let url = endpoints.chip_url();
     let chip: Chip = client
         .post(url)
         .bearer_auth(&chip.identity)
@@ -94,29 +155,180 @@ pub async fn login(code: String) -> Result<LibbyConfig> {
     })
 }
 
-async fn chip(client: &reqwest::Client, identity: &str) -> Result<Chip> {
-    let url = format!("https://sentry.libbyapp.com/chip?c={C}&s={S}&v={V}");
-    let chip_resp = client
-        .post(url)
+/// Failures talking to Libby and OverDrive, typed so callers can decide which
+/// ones to skip, retry or abort on.
+#[derive(Debug)]
+pub enum LibbyError {
+    /// Libby rejected the identity, and refreshing it did not help
+    Unauthorized(String),
+    CardNotFound(String),
+    TagNotFound(String),
+    /// HTTP 429, with the server's Retry-After when it sent one
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    /// A search without a matching book
+    NotFound(String),
+    /// HTTP 404 from the given URL, usually a wrong endpoint or base URL
+    HttpNotFound(String),
+    UnexpectedStatus {
+        status: StatusCode,
+        body: String,
+    },
+    /// The response body did not have the shape we expected
+    SchemaMismatch {
+        context: String,
+        source: serde_json::Error,
+    },
+    /// Libby answered, but with a result other than the one we asked for
+    UnexpectedResult(String),
+    Request(reqwest::Error),
+    Other(anyhow::Error),
+}
+
+impl std::fmt::Display for LibbyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unauthorized(detail) => write!(
+                f,
+                "Libby session is no longer valid ({detail}). Run `gr2libby login --code <CODE>` again"
+            ),
+            Self::CardNotFound(card_id) => write!(f, "card '{card_id}' not found on this account"),
+            Self::TagNotFound(name) => write!(f, "tag '{name}' not found"),
+            Self::RateLimited {
+                retry_after: Some(retry_after),
+            } => write!(f, "rate limited, retry after {}s", retry_after.as_secs()),
+            Self::RateLimited { retry_after: None } => write!(f, "rate limited"),
+            Self::NotFound(what) => write!(f, "{what} not found"),
+            Self::HttpNotFound(url) => write!(f, "{url} returned 404"),
+            Self::UnexpectedStatus { status, body } => {
+                write!(f, "unexpected status {status}: {body}")
+            }
+            Self::SchemaMismatch { context, source } => {
+                write!(f, "unexpected response for {context}: {source}")
+            }
+            Self::UnexpectedResult(result) => write!(f, "unexpected result: {result}"),
+            Self::Request(e) => write!(f, "request failed: {e}"),
+            Self::Other(e) => write!(f, "{e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for LibbyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::SchemaMismatch { source, .. } => Some(source),
+            Self::Request(e) => Some(e),
+            Self::Other(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for LibbyError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Request(e)
+    }
+}
+
+/// Map non-success statuses to the matching `LibbyError`.
+async fn check_response(resp: reqwest::Response) -> Result<reqwest::Response, LibbyError> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(LibbyError::Unauthorized(format!(
+            "status {} from {}",
+            status,
+            resp.url()
+        ))),
+        StatusCode::TOO_MANY_REQUESTS => Err(LibbyError::RateLimited {
+            retry_after: retry_after(resp.headers()),
+        }),
+        StatusCode::NOT_FOUND => Err(LibbyError::HttpNotFound(resp.url().to_string())),
+        _ => Err(LibbyError::UnexpectedStatus {
+            status,
+            body: resp.text().await.unwrap_or_default(),
+        }),
+    }
+}
+
+async fn parse_response<T: serde::de::DeserializeOwned>(
+    resp: reqwest::Response,
+    context: &str,
+) -> Result<T, LibbyError> {
+    let body = check_response(resp).await?.text().await?;
+    serde_json::from_str(&body).map_err(|source| {
+        debug!("Unparsable {} response: '{}'", context, body);
+        LibbyError::SchemaMismatch {
+            context: context.to_string(),
+            source,
+        }
+    })
+}
+
+async fn chip(
+    client: &reqwest::Client,
+    endpoints: &LibbyEndpoints,
+    identity: &str,
+) -> Result<Chip, LibbyError> {
+    let resp = client
+        .post(endpoints.chip_url())
         .bearer_auth(identity)
         .send()
+        .await?;
+    parse_response(resp, "chip").await
+}
+
+fn is_unauthorized(resp: &reqwest::Response) -> bool {
+    matches!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+    )
+}
+
+fn relogin_error(libby_conf_file: &Path, cause: LibbyError) -> LibbyError {
+    match cause {
+        LibbyError::Unauthorized(detail) => LibbyError::Unauthorized(format!(
+            "{}; token in {}",
+            detail,
+            libby_conf_file.display()
+        )),
+        other => other,
+    }
+}
+
+/// Exchange the saved token for a fresh identity via /chip, writing it back
+/// to the config file when Libby rotated it.
+async fn refresh_saved_identity(
+    client: &reqwest::Client,
+    endpoints: &LibbyEndpoints,
+    libby_conf_file: &Path,
+) -> Result<Chip, LibbyError> {
+    let config = LibbyClient::load_config(libby_conf_file).await?;
+    let chip = chip(client, endpoints, &config.bearer_token)
         .await
-        .context("libby post request")?
-        .text()
-        .await
-        .context("get resp")?;
-    debug!("Resp: '{}'", chip_resp);
-    let chip: Chip = serde_json::from_str(&chip_resp).context("libby post response")?;
+        .map_err(|e| relogin_error(libby_conf_file, e))?;
+    if chip.identity != config.bearer_token {
+        LibbyClient::save_identity(libby_conf_file, &chip.identity).await?;
+    }
     Ok(chip)
 }
 
-pub async fn get_cards(libby_conf_file: PathBuf) -> Result<Vec<LibbyCard>> {
+/// Library cards linked to the saved login.
+pub async fn get_cards(
+    libby_conf_file: PathBuf,
+    endpoints: &LibbyEndpoints,
+) -> Result<Vec<LibbyCard>, LibbyError> {
     let client = LibbyClient::reqwest_client()?;
-    let libby_config = LibbyClient::load_config(libby_conf_file).await?;
-    let cards = LibbyClient::get_cards(&client, &libby_config.bearer_token).await?;
-    Ok(cards)
+    let chip = refresh_saved_identity(&client, endpoints, &libby_conf_file).await?;
+    LibbyClient::get_cards(&client, endpoints, &chip.identity)
+        .await
+        .map_err(|e| relogin_error(&libby_conf_file, e))
 }
 
+/// A Libby tag, from `LibbyClient::get_existing_tag_by_name`.
 #[derive(Debug)]
 pub struct TagInfo {
     pub uuid: String,
@@ -124,10 +336,13 @@ pub struct TagInfo {
     pub total_tagged: i64,
 }
 
+/// A title in the card's library.
 #[derive(Debug)]
 pub struct BookInfo {
     pub libby_id: String,
     pub title: String,
+    /// Whether a copy can be borrowed now, when known (search results only)
+    pub is_available: Option<bool>,
 }
 
 #[allow(dead_code)]
@@ -147,6 +362,35 @@ impl std::fmt::Display for BookType {
     }
 }
 
+/// What the media endpoint says about a title.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MediaDetails {
+    /// Format ids, e.g. "ebook-kindle"
+    pub formats: Vec<String>,
+    /// Length of the longest audiobook format
+    pub duration_minutes: Option<i64>,
+}
+
+/// Current copies and holds of a title at the card's library.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Availability {
+    pub is_available: bool,
+    pub owned_copies: Option<i64>,
+    pub available_copies: Option<i64>,
+    pub holds_count: Option<i64>,
+    pub estimated_wait_days: Option<i64>,
+}
+
+/// Minutes in an OverDrive duration such as "16:10:55".
+fn parse_duration_minutes(duration: &str) -> Option<i64> {
+    let mut parts = duration.split(':').map(|p| p.trim().parse::<i64>());
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(hours)), Some(Ok(minutes)), _) => Some(hours * 60 + minutes),
+        _ => None,
+    }
+}
+
 fn encode_name(name: &str) -> String {
     base64::engine::general_purpose::STANDARD
         .encode(name.encode_utf16().map(|b| format!("%u{:02X}", b)).join(""))
@@ -179,7 +423,7 @@ struct LibbyCardSync {
 
 #[allow(dead_code)]
 #[derive(Deserialize, Debug, Clone)]
-pub(crate) struct LibbyBookType {
+pub struct LibbyBookType {
     pub id: String,
     pub name: String,
 }
@@ -187,7 +431,7 @@ pub(crate) struct LibbyBookType {
 #[allow(dead_code)]
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct LibbySearchResultItem {
+pub struct LibbySearchResultItem {
     pub is_available: bool,
     pub is_owned: Option<bool>,
     pub owned_copies: Option<i64>,
@@ -201,6 +445,8 @@ pub(crate) struct LibbySearchResultItem {
     pub book_type: LibbyBookType,
     #[serde(default, deserialize_with = "deserialize_subjects")]
     pub subjects: Vec<LibbySubject>,
+    #[serde(default)]
+    pub detailed_series: Option<LibbySeries>,
 }
 
 fn deserialize_subjects<'de, D>(deserializer: D) -> std::result::Result<Vec<LibbySubject>, D::Error>
@@ -245,10 +491,18 @@ struct LibbyTagQuery {
 }
 #[allow(dead_code)]
 #[derive(Deserialize, Debug, Clone)]
-pub(crate) struct LibbySubject {
+pub struct LibbySubject {
     pub id: String,
     pub name: String,
 }
+/// The series a title is in, as OverDrive lists it.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LibbySeries {
+    pub series_name: String,
+    /// Usually a number, e.g. "1" or "2.5"
+    pub reading_order: Option<String>,
+}
 #[allow(dead_code)]
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -276,27 +530,18 @@ struct LibbyTagList {
 }
 
 fn fuzzy_author_compare(haystack: &HashSet<String>, needle: &str) -> bool {
-    println!("    {} in {:?}?", needle, haystack);
-    let lower_haystack = haystack
-        .iter()
-        .map(|auth| auth.to_lowercase())
-        .collect::<HashSet<String>>();
-    let lower_needle = needle.to_lowercase();
-    lower_haystack
+    debug!("{} in {:?}?", needle, haystack);
+    haystack
         .iter()
-        .map(|x| edit_distance::edit_distance(x, &lower_needle))
-        .min()
-        .unwrap_or(usize::MAX)
-        < 3
-    // TOOD: Something fancy
-    // lower_haystack.contains(&lower_needle)
+        .any(|author| normalize::authors_match(author, needle))
 }
 
 fn url_for_query(
+    endpoints: &LibbyEndpoints,
     library_advantage_key: &str,
     search_opts: SearchOptions,
     title: &str,
-) -> Result<reqwest::Url> {
+) -> Result<reqwest::Url, LibbyError> {
     let book_type = search_opts.book_type.to_string();
     let max_results = search_opts.max_results.to_string();
     let mut url_params = vec![
@@ -312,12 +557,10 @@ fn url_for_query(
         url_params.push(("show", "all"));
     }
     let url = reqwest::Url::parse_with_params(
-        &format!(
-            "https://thunder.api.overdrive.com/v2/libraries/{}/media",
-            library_advantage_key
-        ),
+        &endpoints.thunder(&format!("/v2/libraries/{}/media", library_advantage_key)),
         &url_params,
-    )?;
+    )
+    .map_err(|e| LibbyError::Other(e.into()))?;
     debug!("uri: {:?}", url);
     Ok(url)
 }
@@ -342,41 +585,138 @@ pub struct Chip {
 #[allow(dead_code)]
 pub struct LibbyClient {
     client: reqwest::Client,
-    config: LibbyConfig,
+    endpoints: LibbyEndpoints,
+    libby_conf_file: PathBuf,
     chip: Chip,
+    /// Current bearer identity, replaced when Libby rejects the old one
+    identity: RwLock<String>,
+    /// Serializes identity refreshes between concurrent requests
+    refresh_lock: tokio::sync::Mutex<()>,
+    requests: RequestLayer,
     card: LibbyCard,
 }
 impl LibbyClient {
     /// Create a new Libby client
-    pub async fn new(libby_conf_file: PathBuf, card_id: String) -> Result<Self> {
-        let config = Self::load_config(libby_conf_file)
-            .await
-            .context("load config")?;
+    pub async fn new(libby_conf_file: PathBuf, card_id: String) -> Result<Self, LibbyError> {
+        Self::new_with_endpoints(libby_conf_file, card_id, LibbyEndpoints::default()).await
+    }
+
+    /// Create a new Libby client talking to the given services
+    pub async fn new_with_endpoints(
+        libby_conf_file: PathBuf,
+        card_id: String,
+        endpoints: LibbyEndpoints,
+    ) -> Result<Self, LibbyError> {
         let client = Self::reqwest_client()?;
-        let chip = chip(&client, &config.bearer_token).await.context("Chip")?;
-        let card = Self::get_library_card(&client, &chip.identity, &card_id)
+        let chip = refresh_saved_identity(&client, &endpoints, &libby_conf_file).await?;
+        let card = Self::get_library_card(&client, &endpoints, &chip.identity, &card_id)
             .await
-            .context("get_library_card")?;
+            .map_err(|e| relogin_error(&libby_conf_file, e))?;
         Ok(Self {
             client,
-            config,
+            endpoints,
+            libby_conf_file,
+            identity: RwLock::new(chip.identity.clone()),
+            refresh_lock: tokio::sync::Mutex::new(()),
+            requests: RequestLayer::new(RequestPolicy::default()),
             chip,
             card,
         })
     }
 
-    async fn load_config(libby_conf_file: PathBuf) -> Result<LibbyConfig> {
+    /// Replace the default rate limits and retry behaviour.
+    pub fn with_request_policy(mut self, policy: RequestPolicy) -> Self {
+        self.requests = RequestLayer::new(policy);
+        self
+    }
+
+    /// Retries and failures seen by the request layer so far.
+    pub fn request_stats(&self) -> RequestStats {
+        self.requests.stats()
+    }
+
+    async fn save_identity(libby_conf_file: &Path, identity: &str) -> Result<(), LibbyError> {
+        let config = LibbyConfig {
+            bearer_token: identity.to_string(),
+        };
+        tokio::fs::write(
+            libby_conf_file,
+            config.to_json().map_err(LibbyError::Other)?,
+        )
+        .await
+        .context("writing libby config file")
+        .map_err(LibbyError::Other)?;
+        debug!("Saved refreshed identity to {}", libby_conf_file.display());
+        Ok(())
+    }
+
+    fn identity(&self) -> String {
+        self.identity
+            .read()
+            .expect("identity lock poisoned")
+            .clone()
+    }
+
+    /// Refresh the identity via /chip after `stale_identity` was rejected,
+    /// unless a concurrent request already did.
+    async fn refresh_identity(&self, stale_identity: &str) -> Result<(), LibbyError> {
+        let _guard = self.refresh_lock.lock().await;
+        if self.identity() != stale_identity {
+            return Ok(());
+        }
+        let chip = chip(&self.client, &self.endpoints, stale_identity)
+            .await
+            .map_err(|e| relogin_error(&self.libby_conf_file, e))?;
+        Self::save_identity(&self.libby_conf_file, &chip.identity).await?;
+        *self.identity.write().expect("identity lock poisoned") = chip.identity;
+        Ok(())
+    }
+
+    /// Send a request built with the current identity. If Libby says it is
+    /// unauthorized, refresh the identity and retry once.
+    async fn send_logged_in<F>(&self, build: F) -> Result<reqwest::Response, LibbyError>
+    where
+        F: Fn(&str) -> reqwest::RequestBuilder,
+    {
+        let identity = self.identity();
+        let resp = self.requests.send(|| build(&identity)).await?;
+        if !is_unauthorized(&resp) {
+            return Ok(resp);
+        }
+        debug!(
+            "Unauthorized ({}) from {}, refreshing identity",
+            resp.status(),
+            resp.url()
+        );
+        self.refresh_identity(&identity).await?;
+        let identity = self.identity();
+        let resp = self.requests.send(|| build(&identity)).await?;
+        if is_unauthorized(&resp) {
+            return Err(relogin_error(
+                &self.libby_conf_file,
+                LibbyError::Unauthorized(format!(
+                    "still {} after refreshing identity",
+                    resp.status()
+                )),
+            ));
+        }
+        Ok(resp)
+    }
+
+    async fn load_config(libby_conf_file: &Path) -> Result<LibbyConfig, LibbyError> {
         let config: LibbyConfig = serde_json::from_str(
             &tokio::fs::read_to_string(libby_conf_file)
                 .await
-                .context("reading libby config file")?,
+                .context("reading libby config file")
+                .map_err(LibbyError::Other)?,
         )
-        .context("parsing libby config")?;
+        .context("parsing libby config")
+        .map_err(LibbyError::Other)?;
         Ok(config)
     }
 
     /// Helper to create reqwest client with some common defaults
-    fn reqwest_client() -> Result<reqwest::Client> {
+    fn reqwest_client() -> Result<reqwest::Client, LibbyError> {
         let mut headers = HeaderMap::new();
         headers.insert("Origin", HeaderValue::from_static("https://libbyapp.com"));
         headers.insert("Referer", HeaderValue::from_static("https://libbyapp.com/"));
@@ -391,52 +731,63 @@ impl LibbyClient {
         Ok(client)
     }
 
-    pub async fn tag_book_by_overdrive_id(&self, tag_info: &TagInfo, title_id: &str) -> Result<()> {
+    /// Add the title to the tag.
+    pub async fn tag_book_by_overdrive_id(
+        &self,
+        tag_info: &TagInfo,
+        title_id: &str,
+    ) -> Result<(), LibbyError> {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards")
             .as_secs();
 
-        let url = format!(
-            "https://vandal.libbyapp.com/tag/{}/{}/tagging/{}?enc=1",
+        let url = self.endpoints.vandal(&format!(
+            "/tag/{}/{}/tagging/{}?enc=1",
             tag_info.uuid,
             encode_name(&tag_info.name),
             title_id
-        );
+        ));
         let data = json!({ "tagging": { "cardId": self.card.card_id, "createTime": now, "titleId": title_id, "websiteId": self.card.library.website_id } });
         let response: LibbyResult = self.make_logged_in_libby_post_request(url, &data).await?;
         if response.result != "created" {
-            bail!("Unable to tag book: {response:?}");
+            return Err(LibbyError::UnexpectedResult(format!(
+                "unable to tag book: {response:?}"
+            )));
         }
         debug!("{:#?}", response);
         Ok(())
     }
+    /// Remove the title from the tag.
     pub async fn untag_book_by_overdrive_id(
         &self,
         tag_info: &TagInfo,
         title_id: &str,
-    ) -> Result<()> {
-        let url = format!(
-            "https://vandal.libbyapp.com/tag/{}/{}/tagging/{}?enc=1",
+    ) -> Result<(), LibbyError> {
+        let url = self.endpoints.vandal(&format!(
+            "/tag/{}/{}/tagging/{}?enc=1",
             tag_info.uuid,
             encode_name(&tag_info.name),
             title_id
-        );
+        ));
         let response: LibbyResult = self.make_logged_in_libby_delete_request(url).await?;
         if response.result != "taggings_destroyed" {
-            bail!("Unable to untag book: {response:?}");
+            return Err(LibbyError::UnexpectedResult(format!(
+                "unable to untag book: {response:?}"
+            )));
         }
         debug!("{:#?}", response);
         Ok(())
     }
 
-    pub async fn get_books_for_tag(&self, tag_info: &TagInfo) -> Result<Vec<BookInfo>> {
-        let url = format!(
-            "https://vandal.libbyapp.com/tag/{}/{}?enc=1&sort=newest&range=0...{}",
+    /// Titles currently on the tag.
+    pub async fn get_books_for_tag(&self, tag_info: &TagInfo) -> Result<Vec<BookInfo>, LibbyError> {
+        let url = self.endpoints.vandal(&format!(
+            "/tag/{}/{}?enc=1&sort=newest&range=0...{}",
             tag_info.uuid,
             encode_name(&tag_info.name),
             tag_info.total_tagged,
-        );
+        ));
 
         let response = self
             .make_logged_in_libby_get_request::<LibbyTagQuery, _>(url)
@@ -451,26 +802,28 @@ impl LibbyClient {
             .map(|tag| BookInfo {
                 libby_id: tag.title_id.clone(),
                 title: tag.sort_title.clone(),
+                is_available: None,
             })
             .collect::<Vec<BookInfo>>())
     }
 
-    async fn get_cards(client: &reqwest::Client, identity: &str) -> Result<Vec<LibbyCard>> {
-        let url = "https://sentry.libbyapp.com/chip/sync";
+    async fn get_cards(
+        client: &reqwest::Client,
+        endpoints: &LibbyEndpoints,
+        identity: &str,
+    ) -> Result<Vec<LibbyCard>, LibbyError> {
+        let url = endpoints.sentry("/chip/sync");
 
-        let card_sync: LibbyCardSync = client
-            .get(url)
-            .bearer_auth(identity)
-            .send()
-            .await
-            .context("libby request")?
-            .json()
-            .await
-            .context("libby request parsing")?;
+        let resp = client.get(url).bearer_auth(identity).send().await?;
+        let card_sync: LibbyCardSync = parse_response(resp, "chip/sync").await?;
 
         debug!("{:#?}", card_sync);
         if card_sync.result != "synchronized" {
-            bail!("Unable to sync card: {card_sync:?}");
+            // An identity that is no longer linked to the account comes back unsynced
+            return Err(LibbyError::Unauthorized(format!(
+                "chip/sync result '{}'",
+                card_sync.result
+            )));
         }
 
         Ok(card_sync.cards)
@@ -478,14 +831,15 @@ impl LibbyClient {
 
     async fn get_library_card(
         client: &reqwest::Client,
+        endpoints: &LibbyEndpoints,
         identity: &str,
         card_id: &str,
-    ) -> Result<LibbyCard> {
-        let cards = Self::get_cards(client, identity).await?;
+    ) -> Result<LibbyCard, LibbyError> {
+        let cards = Self::get_cards(client, endpoints, identity).await?;
         cards
             .into_iter()
             .find(|card| card.card_id == card_id)
-            .context("Unable to sync card")
+            .ok_or_else(|| LibbyError::CardNotFound(card_id.to_string()))
     }
 
     async fn search_items(
@@ -493,84 +847,144 @@ impl LibbyClient {
         search_opts: SearchOptions,
         title: &str,
         authors: Option<&HashSet<String>>,
-    ) -> Result<Option<LibbySearchResultItem>> {
-        let url = url_for_query(&self.card.advantage_key, search_opts.clone(), title)?;
-        let mut response = self
-            .make_libby_library_get_request::<LibbySearchResult, _>(url)
-            .await?;
-
-        debug!("{:#?}", response);
-        // Library search does not handle subtitles well, if we found nothing, lets
-        // try with any part of title leading to ':'
-        if response.items.is_empty()
-            && title.contains(':')
-            && let Some(t2) = title.split_once(':').map(|(t2, _)| t2)
-        {
-            let url = url_for_query(&self.card.advantage_key, search_opts, t2)?;
-            response = self
+    ) -> Result<Option<LibbySearchResultItem>, LibbyError> {
+        // Library search does not handle series, edition notes or subtitles
+        // well, so try the cleaned title and then shorter forms of it
+        for query in normalize::search_queries(title) {
+            let url = url_for_query(
+                &self.endpoints,
+                &self.card.advantage_key,
+                search_opts.clone(),
+                &query,
+            )?;
+            let response = self
                 .make_libby_library_get_request::<LibbySearchResult, _>(url)
                 .await?;
+            debug!("{:#?}", response);
+            if let Some(item) = response.items.into_iter().find(|b| {
+                authors.is_none() || fuzzy_author_compare(authors.unwrap(), &b.first_creator_name)
+            }) {
+                return Ok(Some(item));
+            }
         }
-
-        Ok(response.items.into_iter().find(|b| {
-            authors.is_none() || fuzzy_author_compare(authors.unwrap(), &b.first_creator_name)
-        }))
+        Ok(None)
     }
 
+    /// Search the library for a title, keeping the first result by one of
+    /// `authors` when given.
     pub async fn search_for_book_by_title(
         &self,
         search_opts: SearchOptions,
         title: &str,
         authors: Option<&HashSet<String>>,
-    ) -> Result<BookInfo> {
+    ) -> Result<BookInfo, LibbyError> {
         self.search_items(search_opts, title, authors)
             .await?
             .map(|b| BookInfo {
                 title: b.sort_title.to_string(),
                 libby_id: b.id.to_string(),
+                is_available: Some(b.is_available),
             })
-            .context(format!("Book '{}' not found", title))
+            .ok_or_else(|| LibbyError::NotFound(format!("Book '{}'", title)))
     }
 
-    pub(crate) async fn search_for_book_details(
+    pub async fn search_for_book_details(
         &self,
         search_opts: SearchOptions,
         title: &str,
         authors: Option<&HashSet<String>>,
-    ) -> Result<LibbySearchResultItem> {
+    ) -> Result<LibbySearchResultItem, LibbyError> {
         self.search_items(search_opts, title, authors)
             .await?
-            .context(format!("Book '{}' not found", title))
+            .ok_or_else(|| LibbyError::NotFound(format!("Book '{}'", title)))
+    }
+
+    pub async fn get_book_formats(&self, libby_id: &str) -> Result<Vec<String>, LibbyError> {
+        Ok(self.get_book_details(libby_id).await?.formats)
     }
 
-    pub(crate) async fn get_book_formats(&self, libby_id: &str) -> Result<Vec<String>> {
-        let url = format!(
-            "https://thunder.api.overdrive.com/v2/libraries/{}/media/{}",
+    /// Formats and, for audiobooks, length of a title.
+    pub async fn get_book_details(&self, libby_id: &str) -> Result<MediaDetails, LibbyError> {
+        let url = self.endpoints.thunder(&format!(
+            "/v2/libraries/{}/media/{}",
             self.card.advantage_key, libby_id
-        );
+        ));
         let response: serde_json::Value = self.make_libby_library_get_request(url).await?;
         let formats = response
             .get("formats")
             .and_then(|f| f.as_array())
-            .map(|arr| {
-                arr.iter()
-                    .filter_map(|f| f.get("id").and_then(|id| id.as_str()).map(String::from))
-                    .collect()
-            })
+            .map(|arr| arr.as_slice())
             .unwrap_or_default();
-        Ok(formats)
+        Ok(MediaDetails {
+            formats: formats
+                .iter()
+                .filter_map(|f| f.get("id").and_then(|id| id.as_str()).map(String::from))
+                .collect(),
+            duration_minutes: formats
+                .iter()
+                .filter_map(|f| f.get("duration").and_then(|d| d.as_str()))
+                .filter_map(parse_duration_minutes)
+                .max(),
+        })
     }
 
-    pub async fn get_existing_tag_by_name(&self, name: &str) -> Result<TagInfo> {
+    /// Copies and holds of a title right now.
+    pub async fn get_availability(&self, libby_id: &str) -> Result<Availability, LibbyError> {
+        let url = self.endpoints.thunder(&format!(
+            "/v2/libraries/{}/media/{}/availability",
+            self.card.advantage_key, libby_id
+        ));
+        self.make_libby_library_get_request(url).await
+    }
+
+    /// Place a hold on the title with the card.
+    pub async fn place_hold(&self, libby_id: &str) -> Result<(), LibbyError> {
+        let url = self
+            .endpoints
+            .sentry(&format!("/card/{}/hold/{}", self.card.card_id, libby_id));
+        let data = json!({ "days_to_suspend": 0, "email_address": "" });
+        let response: serde_json::Value =
+            self.make_logged_in_libby_post_request(url, &data).await?;
+        debug!("{:#?}", response);
+        Ok(())
+    }
+
+    /// Borrow the title with the card, for the library's default period.
+    pub async fn borrow(&self, libby_id: &str, book_type: BookType) -> Result<(), LibbyError> {
+        let url = self
+            .endpoints
+            .sentry(&format!("/card/{}/loan/{}", self.card.card_id, libby_id));
+        let data = json!({ "period": 14, "units": "days", "lucky_day": null, "title_format": book_type.to_string() });
+        let response: serde_json::Value =
+            self.make_logged_in_libby_post_request(url, &data).await?;
+        debug!("{:#?}", response);
+        Ok(())
+    }
+
+    /// Ask the library to buy a title it lists but doesn't own.
+    pub async fn recommend(&self, libby_id: &str) -> Result<(), LibbyError> {
+        let url = self.endpoints.sentry(&format!(
+            "/card/{}/recommendation/{}",
+            self.card.card_id, libby_id
+        ));
+        let response: serde_json::Value = self
+            .make_logged_in_libby_post_request(url, &json!({}))
+            .await?;
+        debug!("{:#?}", response);
+        Ok(())
+    }
+
+    /// Look up one of the account's tags. Tags are not created here.
+    pub async fn get_existing_tag_by_name(&self, name: &str) -> Result<TagInfo, LibbyError> {
         let response = self
-            .make_libby_library_get_request::<LibbyTagList, _>("https://vandal.libbyapp.com/tags")
+            .make_libby_library_get_request::<LibbyTagList, _>(self.endpoints.vandal("/tags"))
             .await?;
         let found = response
             .tags
             .iter()
             .find(|t| t.name == name)
             .cloned()
-            .context("Unable to find tag by name");
+            .ok_or_else(|| LibbyError::TagNotFound(name.to_string()));
         found.map(|lt| TagInfo {
             name: lt.name,
             uuid: lt.uuid,
@@ -581,64 +995,51 @@ impl LibbyClient {
     async fn make_logged_in_libby_get_request<T: serde::de::DeserializeOwned, U: IntoUrl>(
         &self,
         url: U,
-    ) -> Result<T> {
-        self.client
-            .get(url)
-            .bearer_auth(&self.chip.identity)
-            .body("")
-            .send()
-            .await
-            .context("libby request")?
-            .json::<T>()
-            .await
-            .context("libby request parsing")
+    ) -> Result<T, LibbyError> {
+        let url = url.into_url()?;
+        let resp = self
+            .send_logged_in(|identity| self.client.get(url.clone()).bearer_auth(identity).body(""))
+            .await?;
+        parse_response(resp, "libby request").await
     }
 
     async fn make_logged_in_libby_post_request<T: serde::de::DeserializeOwned, U: IntoUrl>(
         &self,
         url: U,
         data: &serde_json::Value,
-    ) -> Result<T> {
-        self.client
-            .post(url)
-            .bearer_auth(&self.chip.identity)
-            .json(&data)
-            .send()
-            .await
-            .context("libby post request")?
-            .json::<T>()
-            .await
-            .context("libby post response")
+    ) -> Result<T, LibbyError> {
+        let url = url.into_url()?;
+        let resp = self
+            .send_logged_in(|identity| {
+                self.client
+                    .post(url.clone())
+                    .bearer_auth(identity)
+                    .json(&data)
+            })
+            .await?;
+        parse_response(resp, "libby post").await
     }
 
     async fn make_logged_in_libby_delete_request<T: serde::de::DeserializeOwned, U: IntoUrl>(
         &self,
         url: U,
-    ) -> Result<T> {
-        self.client
-            .delete(url)
-            .bearer_auth(&self.chip.identity)
-            .send()
-            .await
-            .context("libby post request")?
-            .json::<T>()
-            .await
-            .context("libby post response")
+    ) -> Result<T, LibbyError> {
+        let url = url.into_url()?;
+        let resp = self
+            .send_logged_in(|identity| self.client.delete(url.clone()).bearer_auth(identity))
+            .await?;
+        parse_response(resp, "libby delete").await
     }
 
     async fn make_libby_library_get_request<T: serde::de::DeserializeOwned, U: IntoUrl>(
         &self,
         url: U,
-    ) -> Result<T> {
-        self.client
-            .get(url)
-            .bearer_auth(&self.chip.identity)
-            .send()
-            .await
-            .context("library request")?
-            .json::<T>()
-            .await
-            .context("library request parsing")
+    ) -> Result<T, LibbyError> {
+        let url = url.into_url()?;
+        let resp = self
+            .send_logged_in(|identity| self.client.get(url.clone()).bearer_auth(identity))
+            .await?;
+        parse_response(resp, "library request").await
     }
 }
 
@@ -659,6 +1060,9 @@ impl std::fmt::Display for LibbyClient {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::fake_libby;
+    use crate::fake_libby::FakeLibby;
+
     fn libby_config_path() -> PathBuf {
         std::env::var("LIBBY_CONFIG")
             .expect("Set LIBBY_CONFIG env var")
@@ -676,6 +1080,120 @@ mod test {
         assert_eq!(encode_name("🔔"), "JXVEODNEJXVERDE0");
     }
 
+    #[test]
+    fn test_parse_duration_minutes() {
+        assert_eq!(parse_duration_minutes("16:10:55"), Some(970));
+        assert_eq!(parse_duration_minutes("00:45"), Some(45));
+        assert_eq!(parse_duration_minutes(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_fake_login() {
+        let fake = FakeLibby::start().await;
+        let config = login("12345678".to_string(), &fake.endpoints())
+            .await
+            .expect("login");
+        assert_eq!(config.bearer_token, "fresh-identity");
+
+        let cards = get_cards(fake.conf_file.clone(), &fake.endpoints())
+            .await
+            .expect("get cards");
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].card_id, fake_libby::CARD_ID);
+        // The rotated identity from /chip is saved back to the config
+        let saved = LibbyClient::load_config(&fake.conf_file)
+            .await
+            .expect("load config");
+        assert_eq!(saved.bearer_token, "fresh-identity");
+    }
+
+    #[tokio::test]
+    async fn test_fake_refreshes_rejected_identity() {
+        let fake = FakeLibby::start().await;
+        let libby_client = fake.client().await;
+        fake.expire_identity().await;
+
+        let tag_info = libby_client
+            .get_existing_tag_by_name(fake_libby::TAG_NAME)
+            .await
+            .expect("retried with a refreshed identity");
+        assert_eq!(tag_info.name, fake_libby::TAG_NAME);
+        assert_eq!(libby_client.identity(), "rotated-identity");
+        let saved = LibbyClient::load_config(&fake.conf_file)
+            .await
+            .expect("load config");
+        assert_eq!(saved.bearer_token, "rotated-identity");
+    }
+
+    #[tokio::test]
+    async fn test_fake_unknown_card() {
+        let fake = FakeLibby::start().await;
+        let result =
+            LibbyClient::new_with_endpoints(fake.conf_file.clone(), "999".into(), fake.endpoints())
+                .await;
+        assert!(matches!(result, Err(LibbyError::CardNotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_fake_search_and_tag() {
+        let fake = FakeLibby::start().await;
+        let libby_client = fake.client().await;
+        let search_opts = SearchOptions {
+            book_type: BookType::Ebook,
+            deep_search: false,
+            max_results: 24,
+        };
+
+        let tag_info = libby_client
+            .get_existing_tag_by_name(fake_libby::TAG_NAME)
+            .await
+            .expect("load tag");
+        let existing_books = libby_client
+            .get_books_for_tag(&tag_info)
+            .await
+            .expect("tag as books");
+        assert_eq!(existing_books.len(), 1);
+        assert!(matches!(
+            libby_client.get_existing_tag_by_name("no such tag").await,
+            Err(LibbyError::TagNotFound(_))
+        ));
+
+        let authors = HashSet::from_iter(["Andy Weir".to_owned()]);
+        let book = libby_client
+            .search_for_book_by_title(search_opts.clone(), fake_libby::FOUND_TITLE, Some(&authors))
+            .await
+            .expect("search");
+        assert_eq!(book.libby_id, fake_libby::FOUND_ID);
+        assert_eq!(book.is_available, Some(true));
+        assert!(matches!(
+            libby_client
+                .search_for_book_by_title(search_opts, "Not In This Library", None)
+                .await,
+            Err(LibbyError::NotFound(_))
+        ));
+
+        libby_client
+            .tag_book_by_overdrive_id(&tag_info, &book.libby_id)
+            .await
+            .expect("tag");
+        libby_client
+            .untag_book_by_overdrive_id(&tag_info, &book.libby_id)
+            .await
+            .expect("untag");
+        assert_eq!(
+            libby_client
+                .get_book_formats(&book.libby_id)
+                .await
+                .expect("formats"),
+            vec!["ebook-overdrive", "ebook-kindle"]
+        );
+        // A 404 is not the same as a search miss
+        assert!(matches!(
+            libby_client.get_availability("9999").await,
+            Err(LibbyError::HttpNotFound(_))
+        ));
+    }
+
     // sentry.libbyapp.com
     #[tokio::test]
     #[ignore]
@@ -6,19 +6,34 @@ use clap::Parser;
 use clap::Subcommand;
 use colored::Colorize;
 use futures::StreamExt;
+use gr2libby::browse;
+use gr2libby::catalog::LibraryCatalog;
+use gr2libby::catalog::TagStore;
+use gr2libby::goodreads;
+use gr2libby::goodreads::get_book_titles_from_goodreads;
+use gr2libby::goodreads::get_book_titles_from_goodreads_shelf;
+use gr2libby::goodreads_export;
+use gr2libby::goodreads_login;
+use gr2libby::history;
+use gr2libby::hoopla;
+use gr2libby::json_state;
+use gr2libby::libby;
+use gr2libby::libby::BookType;
+use gr2libby::libby::LibbyClient;
+use gr2libby::libby::LibbyError;
+use gr2libby::libby::TagInfo;
+use gr2libby::missing;
+use gr2libby::normalize::normalize_title;
+use gr2libby::openlibrary;
+use gr2libby::recommend;
+use gr2libby::request_policy;
+use gr2libby::series;
+use gr2libby::watch;
+use serde::Deserialize;
+use serde::Serialize;
 use tracing::debug;
 use tracing::info;
 
-pub mod browse;
-pub mod goodreads;
-pub mod goodreads_export;
-pub mod libby;
-
-use goodreads::get_book_titles_from_goodreads;
-use goodreads::get_book_titles_from_goodreads_shelf;
-use libby::BookType;
-use libby::LibbyClient;
-
 #[derive(Subcommand, Debug)]
 #[clap(name = "Goodreads shelves to Libby tag")]
 enum Commands {
@@ -30,8 +45,25 @@ enum Commands {
     ListCards,
     /// Download Goodreads export CSV using browser session cookies
     GrExport(GrExportArgs),
+    /// Extract Goodreads session cookies from a local browser into a config file
+    GoodreadsLogin(GoodreadsLoginArgs),
     /// Browse Goodreads to-read list as ebooks available in Libby
     Browse(BrowseArgs),
+    /// Show how waits and copies have changed across browse runs
+    HistoryReport(HistoryReportArgs),
+    /// Notify when titles from a Goodreads shelf become available in Libby
+    Watch(WatchArgs),
+    /// Track books the library doesn't own and report the ones it has since added
+    Missing(MissingArgs),
+    /// List books the library could buy, best rated first, and optionally recommend them
+    Recommend(RecommendArgs),
+}
+
+#[derive(Parser, Debug, Clone)]
+struct HistoryReportArgs {
+    /// History file written by `browse --history-file`
+    #[clap(long)]
+    history_file: PathBuf,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -54,8 +86,8 @@ struct GR2LibbyArgs {
     /// Path to local file with a goodreads exported csv.
     /// For information on how to export, see this article:
     ///   https://help.goodreads.com/s/article/How-do-I-import-or-export-my-books-1553870934590
-    #[clap(long)]
-    goodreads_export_csv: PathBuf,
+    #[clap(long, required_unless_present = "retry_failures")]
+    goodreads_export_csv: Option<PathBuf>,
 
     /// When set the tagging will be done on the intersection of titles on both
     /// the goodreaeds-export-csv and this second
@@ -81,20 +113,54 @@ struct GR2LibbyArgs {
     #[clap(long)]
     include_unavailable: bool,
 
+    /// Only tag the next unread book of each series started on --read-shelf
+    #[clap(long)]
+    next_in_series: bool,
+
+    /// The name of the shelf in good reads with books already read, for
+    /// --next-in-series
+    #[clap(long, default_value = "read")]
+    read_shelf: String,
+
     /// Does all the work with the exception of writing the tags to libby
     #[clap(long)]
     dry_run: bool,
+
+    /// Path to goodreads config JSON. When set, searched books are also put on
+    /// Goodreads shelves named after their Libby status
+    /// (<prefix>-available, <prefix>-waitlist, <prefix>-missing)
+    #[clap(long)]
+    write_goodreads_shelves: Option<PathBuf>,
+
+    /// Prefix for the Goodreads status shelves
+    #[clap(long, default_value = "libby")]
+    goodreads_status_shelf_prefix: String,
+
+    /// Write tag updates that failed to this JSON file, for --retry-failures.
+    /// When an expired session stops the run, books not yet searched are
+    /// not in it, so the run has to be repeated in full.
+    #[clap(long)]
+    failures_file: Option<PathBuf>,
+
+    /// Only retry the tag updates recorded in a --failures-file from an
+    /// earlier run (with the same --tag), instead of reading Goodreads
+    #[clap(long)]
+    retry_failures: Option<PathBuf>,
+
+    #[clap(flatten)]
+    request_policy: request_policy::RequestPolicy,
 }
 
 #[derive(Parser, Debug, Clone)]
+#[command(subcommand_negates_reqs = true)]
 struct GrExportArgs {
     /// Path to goodreads config JSON with user_id and cookies
-    #[clap(long, default_value = "./goodreads_config.json")]
+    #[clap(long, default_value = "./goodreads_config.json", global = true)]
     goodreads_conf_file: PathBuf,
 
     /// Where to save the exported CSV
-    #[clap(long)]
-    output: PathBuf,
+    #[clap(long, required = true)]
+    output: Option<PathBuf>,
 
     /// Seconds between poll attempts while waiting for export
     #[clap(long, default_value = "5")]
@@ -103,6 +169,57 @@ struct GrExportArgs {
     /// Maximum number of poll attempts before giving up
     #[clap(long, default_value = "60")]
     max_poll_attempts: u32,
+
+    /// Shelves to scrape from the review list when the CSV export is
+    /// unavailable (comma-separated)
+    #[clap(long, value_delimiter = ',', default_value = "to-read")]
+    scrape_shelves: Vec<String>,
+
+    /// Milliseconds between review list pages while scraping shelves
+    #[clap(long, default_value = "1000")]
+    scrape_page_delay_ms: u64,
+
+    /// Fail instead of scraping shelves when the CSV export is unavailable
+    #[clap(long)]
+    no_scrape_fallback: bool,
+
+    #[command(subcommand)]
+    command: Option<GrExportCommands>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum GrExportCommands {
+    /// Check the Goodreads cookies without exporting. Exits with 0 when the
+    /// session is healthy, 2 when it expires within --warn-days and 3 when it
+    /// has expired.
+    Check(GrCheckArgs),
+}
+
+#[derive(Parser, Debug, Clone)]
+struct GrCheckArgs {
+    /// Report the session as expiring soon when it has fewer days left
+    #[clap(long, default_value = "7")]
+    warn_days: i64,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct GoodreadsLoginArgs {
+    /// Browser whose cookies to read
+    #[clap(long, default_value = "firefox")]
+    browser: goodreads_login::Browser,
+
+    /// Path to a specific cookie database (Firefox cookies.sqlite or Chromium
+    /// Cookies). By default the browser's profiles are searched.
+    #[clap(long)]
+    cookie_db: Option<PathBuf>,
+
+    /// Your Goodreads user id, if it cannot be found in the cookies
+    #[clap(long)]
+    user_id: Option<String>,
+
+    /// Where to write the goodreads config JSON
+    #[clap(long, default_value = "./goodreads_config.json")]
+    goodreads_conf_file: PathBuf,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -131,13 +248,180 @@ struct BrowseArgs {
     #[clap(long)]
     max_pages: Option<i64>,
 
-    /// Output HTML file path
-    #[clap(long, default_value = "browse.html")]
-    output: PathBuf,
+    /// The kind of title to look for in Libby
+    #[clap(long, default_value = "ebook")]
+    book_type: BookType,
+
+    /// Minimum audiobook length in hours
+    #[clap(long)]
+    min_hours: Option<f64>,
+
+    /// Maximum audiobook length in hours
+    #[clap(long)]
+    max_hours: Option<f64>,
+
+    /// Output format
+    #[clap(long, default_value = "html")]
+    format: browse::BrowseFormat,
+
+    /// Only list titles that can be borrowed now
+    #[clap(long, conflicts_with = "serve")]
+    available_only: bool,
+
+    /// Only list titles that can be sent to a Kindle
+    #[clap(long, conflicts_with = "serve")]
+    kindle_only: bool,
+
+    /// Output file path [default: browse.html for html, stdout otherwise]
+    #[clap(long)]
+    output: Option<PathBuf>,
+
+    /// Serve the page locally, with buttons to refresh, tag, hold and borrow
+    #[clap(long, conflicts_with_all = ["format", "output"])]
+    serve: bool,
+
+    /// Address to serve on, which must be a loopback address
+    #[clap(long, default_value = "127.0.0.1:8080")]
+    listen: std::net::SocketAddr,
+
+    /// Record each run's availability in this SQLite file, for wait trends
+    #[clap(long)]
+    history_file: Option<PathBuf>,
 
     /// Format cache file path (for Kindle detection)
     #[clap(long, default_value = "browse_cache.json")]
     cache_file: PathBuf,
+
+    /// Also search Hoopla, where everything can be borrowed instantly
+    #[clap(long)]
+    hoopla: bool,
+
+    /// The kind of title to look for on Hoopla
+    #[clap(long, default_value = "ebook")]
+    hoopla_kind: hoopla::HooplaKind,
+
+    /// Base URL of the Hoopla API
+    #[clap(long, env = "HOOPLA_BASE", default_value = hoopla::HOOPLA_BASE, hide = true)]
+    hoopla_base: String,
+
+    /// Fill in missing page counts, years and ISBNs from Open Library before
+    /// the page filters and the Hoopla ISBN match
+    #[clap(long)]
+    enrich: bool,
+
+    /// Open Library lookup cache file path
+    #[clap(long, default_value = "openlibrary_cache.json")]
+    openlibrary_cache_file: PathBuf,
+
+    /// Base URL of the Open Library API
+    #[clap(long, env = "OPENLIBRARY_BASE", default_value = openlibrary::OPENLIBRARY_BASE, hide = true)]
+    openlibrary_base: String,
+
+    #[clap(flatten)]
+    request_policy: request_policy::RequestPolicy,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct WatchArgs {
+    /// Path to local file with a goodreads exported CSV
+    #[clap(long)]
+    goodreads_export_csv: PathBuf,
+
+    /// The card id in Libby
+    #[clap(long)]
+    card_id: String,
+
+    /// The name of the shelf in Goodreads to watch
+    #[clap(long, default_value = "to-read")]
+    goodreads_shelf: String,
+
+    /// The kind of title to look for in Libby
+    #[clap(long, default_value = "ebook")]
+    book_type: BookType,
+
+    /// Minutes between checks
+    #[clap(long, default_value = "30")]
+    interval_minutes: u64,
+
+    /// Check once and exit
+    #[clap(long)]
+    once: bool,
+
+    /// Where to send notifications: stdout, desktop, webhook=<url> or
+    /// email=<address>. May be repeated.
+    #[clap(long = "notify", default_value = "stdout")]
+    sinks: Vec<watch::NotifySink>,
+
+    #[clap(flatten)]
+    smtp: watch::SmtpConfig,
+
+    /// Matched titles and their last seen availability
+    #[clap(long, default_value = "watch_state.json")]
+    state_file: PathBuf,
+
+    #[clap(flatten)]
+    request_policy: request_policy::RequestPolicy,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct MissingArgs {
+    /// Path to local file with a goodreads exported CSV
+    #[clap(long)]
+    goodreads_export_csv: PathBuf,
+
+    /// The card id in Libby
+    #[clap(long)]
+    card_id: String,
+
+    /// The name of the shelf in Goodreads to check
+    #[clap(long, default_value = "to-read")]
+    goodreads_shelf: String,
+
+    /// The type of book (audiobook or ebook) to look for
+    #[clap(long, default_value = "audiobook")]
+    book_type: BookType,
+
+    /// Books not owned by the library, as of the last run
+    #[clap(long, default_value = "missing_books.json")]
+    missing_file: PathBuf,
+
+    /// Put books the library has since added on this Libby tag
+    #[clap(short, long = "tag")]
+    tag_name: Option<String>,
+
+    #[clap(flatten)]
+    request_policy: request_policy::RequestPolicy,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct RecommendArgs {
+    /// Path to local file with a goodreads exported CSV
+    #[clap(long)]
+    goodreads_export_csv: PathBuf,
+
+    /// The card id in Libby
+    #[clap(long)]
+    card_id: String,
+
+    /// The name of the shelf in Goodreads to check
+    #[clap(long, default_value = "to-read")]
+    goodreads_shelf: String,
+
+    /// The type of book (audiobook or ebook) to look for
+    #[clap(long, default_value = "ebook")]
+    book_type: BookType,
+
+    /// Send up to this many of the best ranked titles to the library as
+    /// purchase recommendations
+    #[clap(long)]
+    submit: Option<usize>,
+
+    /// Titles already recommended, which are not sent again
+    #[clap(long, default_value = "recommended_titles.json")]
+    recommended_file: PathBuf,
+
+    #[clap(flatten)]
+    request_policy: request_policy::RequestPolicy,
 }
 
 #[derive(Debug, Parser)]
@@ -150,16 +434,16 @@ struct CommandArgs {
     #[clap(long, default_value = "./libby_config.json", global = true)]
     libby_conf_file: PathBuf,
 
+    #[clap(flatten)]
+    libby_endpoints: libby::LibbyEndpoints,
+
+    /// Base URL of Goodreads
+    #[clap(long, env = "GOODREADS_BASE", default_value = goodreads_export::GOODREADS_BASE, global = true, hide = true)]
+    goodreads_base: String,
+
     #[command(subcommand)]
     command: Commands,
 }
-fn normalize_title(input: &str) -> String {
-    input
-        .chars()
-        .filter(|&c| c.is_alphanumeric() || c.is_whitespace())
-        .collect::<String>()
-        .to_lowercase()
-}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -168,14 +452,21 @@ async fn main() -> anyhow::Result<()> {
 
     match app_args.command {
         Commands::Login(login_args) => {
-            let lc = libby::login(login_args.code).await?;
+            let lc = libby::login(login_args.code, &app_args.libby_endpoints).await?;
             tokio::fs::write(&app_args.libby_conf_file, lc.to_json()?).await?
         }
         Commands::Gr2lib(command_args) => {
-            gr2libby(command_args, app_args.libby_conf_file).await?;
+            gr2libby(
+                command_args,
+                app_args.libby_conf_file,
+                app_args.libby_endpoints,
+                &app_args.goodreads_base,
+            )
+            .await?;
         }
         Commands::ListCards => {
-            let cards = libby::get_cards(app_args.libby_conf_file).await?;
+            let cards =
+                libby::get_cards(app_args.libby_conf_file, &app_args.libby_endpoints).await?;
             println!("Cards: {:#?}", cards);
         }
         Commands::Browse(args) => {
@@ -184,40 +475,555 @@ async fn main() -> anyhow::Result<()> {
                     goodreads_export_csv: args.goodreads_export_csv,
                     card_id: args.card_id,
                     goodreads_shelf: args.goodreads_shelf,
+                    book_type: args.book_type,
                     tags: args.tags,
                     min_pages: args.min_pages,
                     max_pages: args.max_pages,
+                    min_hours: args.min_hours,
+                    max_hours: args.max_hours,
+                    format: args.format,
                     output: args.output,
                     cache_file: args.cache_file,
+                    request_policy: args.request_policy,
+                    libby_endpoints: app_args.libby_endpoints,
+                    hoopla_base: args.hoopla.then_some(args.hoopla_base),
+                    hoopla_kind: args.hoopla_kind,
+                    openlibrary_base: args.enrich.then_some(args.openlibrary_base),
+                    openlibrary_cache_file: args.openlibrary_cache_file,
+                    serve: args.serve.then_some(args.listen),
+                    history_file: args.history_file,
+                    available_only: args.available_only,
+                    kindle_only: args.kindle_only,
                 },
                 app_args.libby_conf_file,
             )
             .await?;
         }
+        Commands::HistoryReport(args) => {
+            let history = history::AvailabilityHistory::open(&args.history_file)?;
+            print!("{}", history::render_report(&history.trends()?));
+        }
+        Commands::Watch(args) => {
+            watch::watch(
+                watch::WatchArgs {
+                    goodreads_export_csv: args.goodreads_export_csv,
+                    card_id: args.card_id,
+                    goodreads_shelf: args.goodreads_shelf,
+                    book_type: args.book_type,
+                    interval: std::time::Duration::from_secs(args.interval_minutes * 60),
+                    once: args.once,
+                    state_file: args.state_file,
+                    sinks: args.sinks,
+                    smtp: args.smtp,
+                    request_policy: args.request_policy,
+                    libby_endpoints: app_args.libby_endpoints,
+                },
+                app_args.libby_conf_file,
+            )
+            .await?;
+        }
+        Commands::Missing(args) => {
+            track_missing(args, app_args.libby_conf_file, app_args.libby_endpoints).await?;
+        }
+        Commands::Recommend(args) => {
+            let libby_client = LibbyClient::new_with_endpoints(
+                app_args.libby_conf_file,
+                args.card_id,
+                app_args.libby_endpoints,
+            )
+            .await
+            .context("client creation")?
+            .with_request_policy(args.request_policy);
+            let books = get_book_titles_from_goodreads_shelf(
+                args.goodreads_export_csv,
+                &args.goodreads_shelf,
+            )
+            .await
+            .context("get_book_titles_from_goodreads_shelf")?;
+            let candidates =
+                recommend::find_candidates(&libby_client, args.book_type, &books).await?;
+            let mut recommended: recommend::Recommended =
+                json_state::load(&args.recommended_file).await;
+            if let Some(limit) = args.submit {
+                let sent =
+                    recommend::submit(&libby_client, &candidates, &mut recommended, limit).await;
+                // Keep what was sent even if a later recommendation failed
+                json_state::save(&recommended, &args.recommended_file).await?;
+                for title in sent? {
+                    println!("{:20} '{}'", "Recommended".green(), title);
+                }
+            }
+            print!("{}", recommend::render_report(&candidates, &recommended));
+            println!("Requests: {}.", libby_client.request_stats());
+        }
         Commands::GrExport(args) => {
-            let exporter =
-                goodreads_export::GoodreadsExporter::new(args.goodreads_conf_file).await?;
+            let exporter = goodreads_export::GoodreadsExporter::new(args.goodreads_conf_file)
+                .await?
+                .with_base(app_args.goodreads_base);
+            if let Some(GrExportCommands::Check(check_args)) = args.command {
+                let report = exporter.check_session(check_args.warn_days * 86400).await?;
+                for (name, secs) in &report.cookies {
+                    println!("{:20} {}", name, goodreads_export::format_remaining(*secs));
+                }
+                let status = match report.status {
+                    goodreads_export::SessionStatus::Valid => report.status.to_string().green(),
+                    goodreads_export::SessionStatus::ExpiringSoon => {
+                        report.status.to_string().yellow()
+                    }
+                    goodreads_export::SessionStatus::Expired => report.status.to_string().red(),
+                };
+                println!(
+                    "Session {} (signed in: {}, remaining: {})",
+                    status,
+                    report.signed_in,
+                    report
+                        .remaining_secs
+                        .map(goodreads_export::format_remaining)
+                        .unwrap_or_else(
+                            || "unknown, re-run goodreads-login to record expiries".to_string()
+                        ),
+                );
+                std::process::exit(report.status.exit_code());
+            }
             exporter
                 .export(
-                    args.output,
+                    args.output.context("--output is required")?,
                     tokio::time::Duration::from_secs(args.poll_interval_secs),
                     args.max_poll_attempts,
+                    if args.no_scrape_fallback {
+                        &[]
+                    } else {
+                        &args.scrape_shelves
+                    },
+                    tokio::time::Duration::from_millis(args.scrape_page_delay_ms),
                 )
                 .await?;
         }
+        Commands::GoodreadsLogin(args) => {
+            let config = goodreads_login::extract_goodreads_config(
+                args.browser,
+                args.cookie_db,
+                args.user_id,
+            )?;
+            tokio::fs::write(&args.goodreads_conf_file, config.to_json()?).await?;
+            eprintln!("Config written to {}", args.goodreads_conf_file.display());
+        }
     }
     Ok(())
 }
 
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum TagAction {
     Add,
     Remove,
 }
 
-async fn gr2libby(command_args: GR2LibbyArgs, libby_conf_file: PathBuf) -> anyhow::Result<()> {
-    let libby_client = LibbyClient::new(libby_conf_file, command_args.card_id)
+/// A tag update that failed, as written to --failures-file.
+#[derive(Debug, Serialize, Deserialize)]
+struct FailedTagging {
+    action: TagAction,
+    libby_id: String,
+    title: String,
+    error: String,
+}
+
+async fn apply_tag_action<T: TagStore>(
+    tag_store: &T,
+    tag_info: &TagInfo,
+    action: TagAction,
+    libby_id: &str,
+) -> Result<(), LibbyError> {
+    match action {
+        TagAction::Add => tag_store.tag_book_by_overdrive_id(tag_info, libby_id).await,
+        TagAction::Remove => {
+            tag_store
+                .untag_book_by_overdrive_id(tag_info, libby_id)
+                .await
+        }
+    }
+}
+
+/// Save the failures for a later --retry-failures run and fail the run if
+/// there were any.
+async fn report_failures(
+    failures: Vec<FailedTagging>,
+    failures_file: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    if let Some(failures_file) = &failures_file {
+        tokio::fs::write(failures_file, serde_json::to_string_pretty(&failures)?)
+            .await
+            .with_context(|| format!("writing {}", failures_file.display()))?;
+    }
+    if !failures.is_empty() {
+        anyhow::bail!(
+            "{} tag updates failed{}",
+            failures.len(),
+            failures_file
+                .map(|f| format!(", retry them with --retry-failures {}", f.display()))
+                .unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+/// Report the failures, then fail with `aborted` when the run stopped early
+/// so the failures so far are still written.
+async fn finish_tagging(
+    failures: Vec<FailedTagging>,
+    failures_file: Option<PathBuf>,
+    aborted: Option<LibbyError>,
+) -> anyhow::Result<()> {
+    let reported = report_failures(failures, failures_file).await;
+    match aborted {
+        Some(e) => {
+            if let Err(report) = reported {
+                eprintln!("{:#}", report);
+            }
+            Err(e).context("tagging stopped early")
+        }
+        None => reported,
+    }
+}
+
+async fn retry_failed_taggings<T: TagStore>(
+    tag_store: &T,
+    tag_info: &TagInfo,
+    retry_file: &PathBuf,
+    failures_file: Option<PathBuf>,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let previous: Vec<FailedTagging> = serde_json::from_str(
+        &tokio::fs::read_to_string(retry_file)
+            .await
+            .with_context(|| format!("reading {}", retry_file.display()))?,
+    )
+    .context("parsing failures file")?;
+
+    let mut failures = Vec::new();
+    let mut retried_ct = 0;
+    let mut aborted = None;
+    for failed in &previous {
+        retried_ct += 1;
+        let verb = match failed.action {
+            TagAction::Add => "Tagging",
+            TagAction::Remove => "Removing",
+        };
+        println!("{:20} '{}'", verb.green(), failed.title);
+        if dry_run {
+            continue;
+        }
+        match apply_tag_action(tag_store, tag_info, failed.action, &failed.libby_id).await {
+            Ok(()) => {}
+            Err(e) => {
+                println!("{:20} '{}' -- {}", "Failed".red(), failed.title, e);
+                failures.push(FailedTagging {
+                    action: failed.action,
+                    libby_id: failed.libby_id.clone(),
+                    title: failed.title.clone(),
+                    error: e.to_string(),
+                });
+                if let LibbyError::Unauthorized(_) = e {
+                    aborted = Some(e);
+                    break;
+                }
+            }
+        }
+    }
+    if aborted.is_some() {
+        // Keep the ones not retried yet for the next attempt
+        failures.extend(previous.into_iter().skip(retried_ct));
+    }
+
+    println!(
+        "Summary: Retried {}, Failed {}.",
+        retried_ct,
+        failures.len()
+    );
+    finish_tagging(failures, failures_file, aborted).await
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LibbyStatus {
+    Available,
+    Waitlist,
+    Missing,
+}
+
+impl LibbyStatus {
+    const ALL: [LibbyStatus; 3] = [Self::Available, Self::Waitlist, Self::Missing];
+
+    /// The status a search result means, or None when the search itself
+    /// failed. Deep searches also find titles the library doesn't own.
+    fn of(found: &Result<libby::LibbySearchResultItem, LibbyError>) -> Option<Self> {
+        match found {
+            Ok(item) if item.is_owned == Some(false) => Some(Self::Missing),
+            Ok(item) if item.is_available => Some(Self::Available),
+            Ok(_) => Some(Self::Waitlist),
+            Err(LibbyError::NotFound(_)) => Some(Self::Missing),
+            Err(_) => None,
+        }
+    }
+
+    fn shelf(&self, prefix: &str) -> String {
+        match self {
+            Self::Available => format!("{}-available", prefix),
+            Self::Waitlist => format!("{}-waitlist", prefix),
+            Self::Missing => format!("{}-missing", prefix),
+        }
+    }
+}
+
+/// Put each book on the Goodreads shelf for its Libby status, taking it off
+/// the shelves for the other statuses.
+async fn write_goodreads_status_shelves(
+    goodreads_conf_file: PathBuf,
+    goodreads_base: &str,
+    prefix: &str,
+    statuses: &[(&goodreads::BookInfo, LibbyStatus)],
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let exporter = goodreads_export::GoodreadsExporter::new(goodreads_conf_file)
+        .await?
+        .with_base(goodreads_base);
+    let csrf_token = exporter.scrape_csrf_token().await?;
+
+    let mut shelved_ct = 0;
+    let mut failed_ct = 0;
+    for (book, status) in statuses {
+        let shelf = status.shelf(prefix);
+        let stale_shelves: Vec<String> = LibbyStatus::ALL
+            .iter()
+            .filter(|s| *s != status)
+            .map(|s| s.shelf(prefix))
+            .filter(|s| book.bookshelves.contains(s))
+            .collect();
+        if book.bookshelves.contains(&shelf) && stale_shelves.is_empty() {
+            continue;
+        }
+
+        shelved_ct += 1;
+        println!("{:20} '{}' -> {}", "Shelving".green(), book.title, shelf);
+        if dry_run {
+            continue;
+        }
+        let mut result = exporter
+            .add_to_shelf(&csrf_token, book.book_id, &shelf)
+            .await;
+        for stale in &stale_shelves {
+            if result.is_ok() {
+                result = exporter
+                    .remove_from_shelf(&csrf_token, book.book_id, stale)
+                    .await;
+            }
+        }
+        if let Err(e) = result {
+            failed_ct += 1;
+            println!(
+                "{:20} '{}' -- {:?}",
+                "Could not shelve".red(),
+                book.title,
+                e
+            );
+        }
+    }
+
+    println!(
+        "Goodreads shelves: Updated {}, Failed {}.",
+        shelved_ct - failed_ct,
+        failed_ct
+    );
+    Ok(())
+}
+
+/// What one tagging run did.
+#[derive(Default)]
+struct TagRun<'a> {
+    existing_ct: usize,
+    newly_tagged_ct: usize,
+    not_found_ct: usize,
+    search_failed_ct: usize,
+    remove_ct: usize,
+    statuses: Vec<(&'a goodreads::BookInfo, LibbyStatus)>,
+    failures: Vec<FailedTagging>,
+    /// Why the run stopped before every book was handled. The books after
+    /// it are in neither `failures` nor the counts, as they were never
+    /// looked up in Libby.
+    aborted: Option<LibbyError>,
+}
+
+/// Search the library for each book, tagging `add_books` and untagging
+/// `remove_books`. With `with_statuses`, the Libby status of every book in
+/// `add_books` is recorded, including ones already on the tag. Only an
+/// unauthorized session stops the run early, as `TagRun::aborted`.
+async fn tag_books<'a, L: LibraryCatalog + TagStore>(
+    library: &L,
+    tag_info: &TagInfo,
+    search_opts: libby::SearchOptions,
+    add_books: &'a [goodreads::BookInfo],
+    remove_books: &'a [goodreads::BookInfo],
+    with_statuses: bool,
+    dry_run: bool,
+) -> anyhow::Result<TagRun<'a>> {
+    let existing_books = library
+        .get_books_for_tag(tag_info)
         .await
-        .context("client creation")?;
+        .context("get_books_for_tag")?;
+    let existing_book_titles: HashSet<String> = existing_books
+        .iter()
+        .map(|b| normalize_title(&b.title))
+        .collect();
+    let mut existing_book_ids: HashSet<String> =
+        existing_books.iter().map(|b| b.libby_id.clone()).collect();
+    info!(
+        "Found {} existing books ({} titles)",
+        existing_book_ids.len(),
+        existing_book_titles.len()
+    );
+
+    // Books already tagged by title are only searched for their status
+    let mut found_books = futures::stream::iter(
+        add_books
+            .iter()
+            .filter_map(|book| {
+                if !existing_book_titles.contains(&normalize_title(&book.title)) {
+                    return Some((Some(TagAction::Add), book));
+                }
+                println!(
+                    "{:20} '{}'",
+                    "Already tagged (title)".bright_yellow(),
+                    book.title
+                );
+                with_statuses.then_some((None, book))
+            })
+            .chain(
+                remove_books
+                    .iter()
+                    .filter(|goodreads::BookInfo { title, .. }| {
+                        // Only keep already tagged books
+                        existing_book_titles.contains(&normalize_title(title))
+                    })
+                    .map(|book| (Some(TagAction::Remove), book)),
+            ),
+    )
+    .map(|(action, book)| {
+        let search_opts = search_opts.clone();
+        async move {
+            let found_book = library
+                .search_for_book_details(search_opts, &book.title, Some(&book.authors))
+                .await;
+            (action, book, found_book)
+        }
+    })
+    .buffer_unordered(25);
+    let mut run = TagRun::default();
+
+    while let Some((action, book, found_book)) = found_books.next().await {
+        let title = &book.title;
+        if with_statuses
+            && !matches!(action, Some(TagAction::Remove))
+            && let Some(status) = LibbyStatus::of(&found_book)
+        {
+            run.statuses.push((book, status));
+        }
+        let Some(action) = action else {
+            continue;
+        };
+        match found_book {
+            Ok(item) => {
+                let book_info = libby::BookInfo {
+                    libby_id: item.id,
+                    title: item.sort_title,
+                    is_available: Some(item.is_available),
+                };
+                match (action, existing_book_ids.contains(&book_info.libby_id)) {
+                    (TagAction::Add, true) => {
+                        run.existing_ct += 1;
+                        println!(
+                            "{:20} '{}'",
+                            "Already tagged (id)".yellow(),
+                            book_info.title
+                        );
+                        continue;
+                    }
+                    (TagAction::Remove, false) => {
+                        println!(
+                            "{:20} '{}'",
+                            "Not tagged, skipping remove(id)".bright_yellow(),
+                            book_info.title
+                        );
+                        continue;
+                    }
+                    (TagAction::Add, false) => {
+                        println!("{:20}'{}'", "Tagging".green(), book_info.title);
+                    }
+                    (TagAction::Remove, true) => {
+                        println!("{:20} '{}'", "Removing".green(), book_info.title);
+                    }
+                }
+
+                let result = if dry_run {
+                    Ok(())
+                } else {
+                    apply_tag_action(library, tag_info, action, &book_info.libby_id).await
+                };
+                match result {
+                    Ok(()) => match action {
+                        TagAction::Add => {
+                            run.newly_tagged_ct += 1;
+                            existing_book_ids.insert(book_info.libby_id);
+                        }
+                        TagAction::Remove => {
+                            run.remove_ct += 1;
+                            existing_book_ids.remove(&book_info.libby_id);
+                        }
+                    },
+                    Err(e) => {
+                        println!("{:20} '{}' -- {}", "Failed".red(), book_info.title, e);
+                        run.failures.push(FailedTagging {
+                            action,
+                            libby_id: book_info.libby_id,
+                            title: book_info.title,
+                            error: e.to_string(),
+                        });
+                        // Every remaining update would fail the same way
+                        if let LibbyError::Unauthorized(_) = e {
+                            run.aborted = Some(e);
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(LibbyError::NotFound(_)) => {
+                run.not_found_ct += 1;
+                println!("{:20} '{}'", "Could not find".red(), title);
+            }
+            // Every remaining search would fail the same way
+            Err(e @ LibbyError::Unauthorized(_)) => {
+                run.aborted = Some(e);
+                break;
+            }
+            Err(e) => {
+                run.search_failed_ct += 1;
+                println!("{:20} '{}' -- {}", "Search failed".red(), title, e);
+            }
+        }
+    }
+
+    Ok(run)
+}
+
+async fn gr2libby(
+    command_args: GR2LibbyArgs,
+    libby_conf_file: PathBuf,
+    libby_endpoints: libby::LibbyEndpoints,
+    goodreads_base: &str,
+) -> anyhow::Result<()> {
+    let libby_client =
+        LibbyClient::new_with_endpoints(libby_conf_file, command_args.card_id, libby_endpoints)
+            .await
+            .context("client creation")?
+            .with_request_policy(command_args.request_policy);
 
     eprintln!("Client setup: {}", libby_client);
     eprintln!(
@@ -243,7 +1049,22 @@ async fn gr2libby(command_args: GR2LibbyArgs, libby_conf_file: PathBuf) -> anyho
         .await
         .context("get_existing_tag_by_name")?;
 
-    let mut all_goodread_books = get_book_titles_from_goodreads(command_args.goodreads_export_csv)
+    if let Some(retry_file) = &command_args.retry_failures {
+        return retry_failed_taggings(
+            &libby_client,
+            &tag_info,
+            retry_file,
+            command_args.failures_file,
+            command_args.dry_run,
+        )
+        .await;
+    }
+
+    let goodreads_export_csv = command_args
+        .goodreads_export_csv
+        .clone()
+        .context("--goodreads-export-csv is required")?;
+    let mut all_goodread_books = get_book_titles_from_goodreads(goodreads_export_csv)
         .await
         .context("get_book_titles_from_goodreads_shelf")?;
 
@@ -263,22 +1084,6 @@ async fn gr2libby(command_args: GR2LibbyArgs, libby_conf_file: PathBuf) -> anyho
         vec![]
     };
 
-    let existing_books = libby_client
-        .get_books_for_tag(&tag_info)
-        .await
-        .context("get_books_for_tag")?;
-    let existing_book_titles: HashSet<String> = existing_books
-        .iter()
-        .map(|b| normalize_title(&b.title))
-        .collect();
-    let mut existing_book_ids: HashSet<String> =
-        existing_books.iter().map(|b| b.libby_id.clone()).collect();
-    info!(
-        "Found {} existing books ({} titles)",
-        existing_book_ids.len(),
-        existing_book_titles.len()
-    );
-
     let goodread_books = if let Some(intersect_with_goodreads_export_csv) =
         command_args.intersect_with_goodreads_export_csv
     {
@@ -299,117 +1104,394 @@ async fn gr2libby(command_args: GR2LibbyArgs, libby_conf_file: PathBuf) -> anyho
         goodread_books
     };
 
-    debug!("books: {:#?}", goodread_books);
+    let goodread_books = if command_args.next_in_series {
+        let read_books = all_goodread_books
+            .remove(&command_args.read_shelf)
+            .with_context(|| {
+                format!(
+                    "shelf '{}' not found in goodreads export",
+                    command_args.read_shelf
+                )
+            })?;
+        let next = series::next_in_series(
+            &libby_client,
+            libby::SearchOptions {
+                book_type: command_args.book_type,
+                deep_search: command_args.include_unavailable,
+                max_results: 24,
+            },
+            &read_books,
+            &goodread_books,
+        )
+        .await?;
+        for (book, position) in &next {
+            println!(
+                "{:20} '{}' ({})",
+                "Next in series".green(),
+                book.title,
+                position
+            );
+        }
+        next.into_iter().map(|(book, _)| book).collect()
+    } else {
+        goodread_books
+    };
 
-    let lc = &libby_client;
-    let book_type = command_args.book_type;
-    let deep_search = command_args.include_unavailable;
+    debug!("books: {:#?}", goodread_books);
 
-    let mut found_books = futures::stream::iter(
-        goodread_books
-            .iter()
-            .filter(|goodreads::BookInfo { title, .. }| {
-                if existing_book_titles.contains(&normalize_title(title)) {
-                    println!(
-                        "{:20} '{}'",
-                        "Already tagged (title)".bright_yellow(),
-                        title
-                    );
-                    false
-                } else {
-                    true
-                }
-            })
-            .map(|book| (TagAction::Add, book))
-            .chain(
-                goodreads_remove_books
-                    .iter()
-                    .filter(|goodreads::BookInfo { title, .. }| {
-                        // Only keep already tagged books
-                        existing_book_titles.contains(&normalize_title(title))
-                    })
-                    .map(|book| (TagAction::Remove, book)),
-            ),
-    )
-    .map(
-        |(action, goodreads::BookInfo { title, authors, .. })| async move {
-            let found_book = lc
-                .search_for_book_by_title(
-                    libby::SearchOptions {
-                        book_type,
-                        deep_search,
-                        max_results: 24,
-                    },
-                    title,
-                    Some(authors),
-                )
-                .await;
-            (action, title, found_book)
+    let run = tag_books(
+        &libby_client,
+        &tag_info,
+        libby::SearchOptions {
+            book_type: command_args.book_type,
+            deep_search: command_args.include_unavailable,
+            max_results: 24,
         },
+        &goodread_books,
+        &goodreads_remove_books,
+        command_args.write_goodreads_shelves.is_some(),
+        command_args.dry_run,
     )
-    .buffer_unordered(25);
-    let mut existing_ct = 0;
-    let mut newly_tagged_ct = 0;
-    let mut not_found_ct = 0;
-    let mut remove_ct = 0;
+    .await?;
 
-    while let Some((action, title, found_book)) = found_books.next().await {
-        match found_book {
-            Ok(book_info) => {
-                if existing_book_ids.contains(&book_info.libby_id) {
-                    match action {
-                        TagAction::Add => {
-                            existing_ct += 1;
-                            println!(
-                                "{:20} '{}'",
-                                "Already tagged (id)".yellow(),
-                                book_info.title
-                            );
-                        }
-                        TagAction::Remove => {
-                            remove_ct += 1;
-                            println!("{:20} '{}'", "Removing".green(), book_info.title);
-                            if !command_args.dry_run {
-                                libby_client
-                                    .untag_book_by_overdrive_id(&tag_info, &book_info.libby_id)
-                                    .await?;
-                            }
-                            existing_book_ids.remove(&book_info.libby_id);
-                        }
-                    }
-                } else {
-                    match action {
-                        TagAction::Add => {
-                            newly_tagged_ct += 1;
-                            println!("{:20}'{}'", "Tagging".green(), book_info.title);
-                            if !command_args.dry_run {
-                                libby_client
-                                    .tag_book_by_overdrive_id(&tag_info, &book_info.libby_id)
-                                    .await?;
-                            }
-                            existing_book_ids.insert(book_info.libby_id);
-                        }
-                        TagAction::Remove => {
-                            println!(
-                                "{:20} '{}'",
-                                "Not tagged, skipping remove(id)".bright_yellow(),
-                                book_info.title
-                            );
-                        }
-                    }
-                }
-            }
-            Err(e) => {
-                not_found_ct += 1;
-                println!("{:20} '{}' -- {:?}", "Could not find".red(), title, e);
-            }
-        }
+    println!(
+        "Summary: Tagged {}, Existing {}, Not Found {}, Search Failed {}, Removed {}, Failed {}.",
+        run.newly_tagged_ct,
+        run.existing_ct,
+        run.not_found_ct,
+        run.search_failed_ct,
+        run.remove_ct,
+        run.failures.len()
+    );
+    println!("Requests: {}.", libby_client.request_stats());
+
+    if run.aborted.is_some()
+        && let Some(failures_file) = &command_args.failures_file
+    {
+        eprintln!(
+            "{} only has the updates tried before the run stopped, run it again in full to finish",
+            failures_file.display()
+        );
+    }
+    // Failures are written first, so a problem with Goodreads can't lose them
+    let finished = finish_tagging(run.failures, command_args.failures_file, run.aborted).await;
+    if let Some(goodreads_conf_file) = command_args.write_goodreads_shelves {
+        write_goodreads_status_shelves(
+            goodreads_conf_file,
+            goodreads_base,
+            &command_args.goodreads_status_shelf_prefix,
+            &run.statuses,
+            command_args.dry_run,
+        )
+        .await
+        .context("write_goodreads_status_shelves")?;
     }
+    finished
+}
+
+async fn track_missing(
+    command_args: MissingArgs,
+    libby_conf_file: PathBuf,
+    libby_endpoints: libby::LibbyEndpoints,
+) -> anyhow::Result<()> {
+    let libby_client =
+        LibbyClient::new_with_endpoints(libby_conf_file, command_args.card_id, libby_endpoints)
+            .await
+            .context("client creation")?
+            .with_request_policy(command_args.request_policy);
+    let tag_info = match &command_args.tag_name {
+        Some(name) => Some(
+            libby_client
+                .get_existing_tag_by_name(name)
+                .await
+                .context("get_existing_tag_by_name")?,
+        ),
+        None => None,
+    };
+    let books = get_book_titles_from_goodreads_shelf(
+        command_args.goodreads_export_csv,
+        &command_args.goodreads_shelf,
+    )
+    .await
+    .context("get_book_titles_from_goodreads_shelf")?;
+
+    let mut missing: missing::MissingBooks = json_state::load(&command_args.missing_file).await;
+    let run = missing::track_missing(
+        &libby_client,
+        command_args.book_type,
+        &books,
+        &mut missing,
+        tag_info.as_ref(),
+    )
+    .await?;
+    json_state::save(&missing, &command_args.missing_file).await?;
 
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs() as i64;
+    for acquired in &run.acquired {
+        println!(
+            "{:20} '{}' (missing for {} days){}",
+            "Now in library".green(),
+            acquired.title,
+            missing::days_between(acquired.missing_since, now),
+            if acquired.tagged { ", tagged" } else { "" }
+        );
+    }
+    for title in &run.newly_missing {
+        println!("{:20} '{}'", "Newly missing".red(), title);
+    }
     println!(
-        "Summary: Tagged {}, Existing {}, Not Found {}, Removed {}.",
-        newly_tagged_ct, existing_ct, not_found_ct, remove_ct
+        "Summary: Acquired {}, Newly Missing {}, Still Missing {}, Search Failed {}.",
+        run.acquired.len(),
+        run.newly_missing.len(),
+        missing.books.len() - run.newly_missing.len(),
+        run.search_failed_ct
     );
-
+    println!("Requests: {}.", libby_client.request_stats());
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use gr2libby::catalog::InMemoryLibrary;
+    use gr2libby::libby::LibbyBookType;
+    use gr2libby::libby::LibbySearchResultItem;
+
+    use super::*;
+
+    const TAG: &str = "🎧";
+
+    fn item(id: &str, title: &str, author: &str, is_available: bool) -> LibbySearchResultItem {
+        LibbySearchResultItem {
+            is_available,
+            is_owned: Some(true),
+            owned_copies: Some(1),
+            estimated_wait_days: None,
+            holds_count: None,
+            available_copies: Some(i64::from(is_available)),
+            id: id.to_string(),
+            first_creator_name: author.to_string(),
+            sort_title: title.to_string(),
+            book_type: LibbyBookType {
+                id: "audiobook".to_string(),
+                name: "Audiobook".to_string(),
+            },
+            subjects: vec![],
+            detailed_series: None,
+        }
+    }
+
+    fn goodreads_book(title: &str, author: &str) -> goodreads::BookInfo {
+        goodreads::BookInfo {
+            title: title.to_string(),
+            author: author.to_string(),
+            isbn: String::new(),
+            authors: HashSet::from([author.to_string()]),
+            shelf: "to-read".to_string(),
+            number_of_pages: None,
+            bookshelves: vec![],
+            average_rating: None,
+            book_id: 1,
+            year_published: None,
+            date_added: String::new(),
+            private_notes: None,
+        }
+    }
+
+    fn search_opts() -> libby::SearchOptions {
+        libby::SearchOptions {
+            book_type: BookType::Audiobook,
+            deep_search: false,
+            max_results: 24,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tag_books_adds_removes_and_skips() {
+        let library = InMemoryLibrary::new()
+            .with_item(item("1", "The Martian", "Andy Weir", true), &[])
+            .with_item(item("2", "Project Hail Mary", "Andy Weir", false), &[])
+            .with_item(item("3", "Artemis", "Andy Weir", true), &[])
+            .with_tag(TAG, &["1", "3"]);
+        let tag_info = library.get_existing_tag_by_name(TAG).await.expect("tag");
+        let add_books = [
+            goodreads_book("The Martian", "Andy Weir"),
+            goodreads_book("Project Hail Mary", "Andy Weir"),
+            goodreads_book("Not In The Library", "Nobody"),
+        ];
+        let remove_books = [goodreads_book("Artemis", "Andy Weir")];
+
+        let run = tag_books(
+            &library,
+            &tag_info,
+            search_opts(),
+            &add_books,
+            &remove_books,
+            true,
+            false,
+        )
+        .await
+        .expect("tag books");
+
+        assert_eq!(run.newly_tagged_ct, 1);
+        assert_eq!(run.remove_ct, 1);
+        assert_eq!(run.not_found_ct, 1);
+        assert!(run.failures.is_empty());
+        assert_eq!(library.tagged(TAG), vec!["1", "2"]);
+
+        assert_eq!(
+            statuses(&run),
+            vec![
+                ("Not In The Library", "libby-missing".to_string()),
+                ("Project Hail Mary", "libby-waitlist".to_string()),
+                ("The Martian", "libby-available".to_string()),
+            ]
+        );
+    }
+
+    fn statuses<'a>(run: &TagRun<'a>) -> Vec<(&'a str, String)> {
+        let mut statuses: Vec<_> = run
+            .statuses
+            .iter()
+            .map(|(book, status)| (book.title.as_str(), status.shelf("libby")))
+            .collect();
+        statuses.sort();
+        statuses
+    }
+
+    #[tokio::test]
+    async fn test_tag_books_statuses() {
+        let library = InMemoryLibrary::new()
+            .with_item(item("1", "The Martian", "Andy Weir", true), &[])
+            .with_item(item("2", "Project Hail Mary", "Andy Weir", false), &[])
+            .with_item(item("3", "Artemis", "Andy Weir", true), &[])
+            .with_item(
+                LibbySearchResultItem {
+                    is_owned: Some(false),
+                    owned_copies: Some(0),
+                    ..item("4", "Cheshire Crossing", "Andy Weir", false)
+                },
+                &[],
+            )
+            .with_tag(TAG, &["3"]);
+        let tag_info = library.get_existing_tag_by_name(TAG).await.expect("tag");
+        let add_books = [
+            goodreads_book("The Martian", "Andy Weir"),
+            goodreads_book("Project Hail Mary", "Andy Weir"),
+            goodreads_book("Artemis", "Andy Weir"),
+            goodreads_book("Cheshire Crossing", "Andy Weir"),
+        ];
+        let search_opts = libby::SearchOptions {
+            deep_search: true,
+            ..search_opts()
+        };
+
+        let run = tag_books(
+            &library,
+            &tag_info,
+            search_opts,
+            &add_books,
+            &[],
+            true,
+            true,
+        )
+        .await
+        .expect("tag books");
+
+        // Already tagged books get a status too, and a deep search hit the
+        // library doesn't own is missing rather than on the waitlist
+        assert_eq!(
+            statuses(&run),
+            vec![
+                ("Artemis", "libby-available".to_string()),
+                ("Cheshire Crossing", "libby-missing".to_string()),
+                ("Project Hail Mary", "libby-waitlist".to_string()),
+                ("The Martian", "libby-available".to_string()),
+            ]
+        );
+        assert_eq!(library.tagged(TAG), vec!["3"]);
+    }
+
+    #[tokio::test]
+    async fn test_tag_books_collects_failures() {
+        let library = InMemoryLibrary::new()
+            .with_item(item("1", "The Martian", "Andy Weir", true), &[])
+            .with_item(item("2", "Project Hail Mary", "Andy Weir", true), &[])
+            .with_failing_title("1")
+            .with_tag(TAG, &[]);
+        let tag_info = library.get_existing_tag_by_name(TAG).await.expect("tag");
+        let add_books = [
+            goodreads_book("The Martian", "Andy Weir"),
+            goodreads_book("Project Hail Mary", "Andy Weir"),
+        ];
+
+        let run = tag_books(
+            &library,
+            &tag_info,
+            search_opts(),
+            &add_books,
+            &[],
+            false,
+            false,
+        )
+        .await
+        .expect("tag books");
+
+        assert_eq!(run.newly_tagged_ct, 1);
+        assert_eq!(run.failures.len(), 1);
+        assert_eq!(run.failures[0].libby_id, "1");
+        assert_eq!(library.tagged(TAG), vec!["2"]);
+    }
+
+    #[tokio::test]
+    async fn test_failures_file_survives_expired_session_and_retries() {
+        let library = InMemoryLibrary::new()
+            .with_item(item("1", "The Martian", "Andy Weir", true), &[])
+            .with_item(item("2", "Project Hail Mary", "Andy Weir", true), &[])
+            .with_item(item("3", "Artemis", "Andy Weir", true), &[])
+            .with_failing_title("1")
+            .with_unauthorized_title("2")
+            .with_tag(TAG, &[]);
+        let tag_info = library.get_existing_tag_by_name(TAG).await.expect("tag");
+        let add_books = [
+            goodreads_book("The Martian", "Andy Weir"),
+            goodreads_book("Project Hail Mary", "Andy Weir"),
+            goodreads_book("Artemis", "Andy Weir"),
+        ];
+        let failures_file =
+            std::env::temp_dir().join(format!("gr2libby-failures-{}.json", std::process::id()));
+
+        let run = tag_books(
+            &library,
+            &tag_info,
+            search_opts(),
+            &add_books,
+            &[],
+            false,
+            false,
+        )
+        .await
+        .expect("tag books");
+        assert!(matches!(run.aborted, Some(LibbyError::Unauthorized(_))));
+        // Searches finish in any order
+        let mut failed: Vec<_> = run.failures.iter().map(|f| f.libby_id.as_str()).collect();
+        failed.sort();
+        assert_eq!(failed, vec!["1", "2"]);
+        let result = finish_tagging(run.failures, Some(failures_file.clone()), run.aborted).await;
+        assert!(result.is_err());
+
+        // Once the session is back, the written file is enough to finish up
+        let recovered = InMemoryLibrary::new()
+            .with_item(item("1", "The Martian", "Andy Weir", true), &[])
+            .with_item(item("2", "Project Hail Mary", "Andy Weir", true), &[])
+            .with_tag(TAG, &[]);
+        retry_failed_taggings(&recovered, &tag_info, &failures_file, None, false)
+            .await
+            .expect("retry");
+        let _ = std::fs::remove_file(&failures_file);
+        let mut tagged = recovered.tagged(TAG);
+        tagged.sort();
+        assert_eq!(tagged, vec!["1", "2"]);
+    }
+}
@@ -0,0 +1,209 @@
+//! Books on a Goodreads shelf that the library doesn't own, remembered
+//! between runs so the ones it has since bought can be reported (and
+//! tagged).
+
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::collections::btree_map::Entry;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::catalog::LibraryCatalog;
+use crate::catalog::TagStore;
+use crate::catalog::search_shelf;
+use crate::goodreads;
+use crate::libby::BookType;
+use crate::libby::LibbyError;
+use crate::libby::SearchOptions;
+use crate::libby::TagInfo;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MissingBook {
+    pub title: String,
+    pub author: String,
+    /// Seconds since the epoch of the run that first found it missing
+    pub missing_since: i64,
+}
+
+/// The missing books, by Goodreads book id.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MissingBooks {
+    pub books: BTreeMap<i64, MissingBook>,
+}
+
+/// A previously missing book the library now owns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Acquired {
+    pub libby_id: String,
+    pub title: String,
+    pub missing_since: i64,
+    /// Whether it was put on the tag, when one was given
+    pub tagged: bool,
+}
+
+/// What one tracking run found.
+#[derive(Debug, Default)]
+pub struct MissingRun {
+    pub acquired: Vec<Acquired>,
+    /// Titles missing for the first time
+    pub newly_missing: Vec<String>,
+    pub search_failed_ct: usize,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs() as i64
+}
+
+/// Search the library for every book on the shelf, updating `missing` with
+/// the ones it doesn't own. Books that were missing and are now owned are
+/// returned as acquired, and put on `tag` when given. Books no longer on
+/// the shelf are forgotten.
+pub async fn track_missing<L: LibraryCatalog + TagStore>(
+    library: &L,
+    book_type: BookType,
+    books: &[goodreads::BookInfo],
+    missing: &mut MissingBooks,
+    tag: Option<&TagInfo>,
+) -> Result<MissingRun> {
+    let on_shelf: HashSet<i64> = books.iter().map(|b| b.book_id).collect();
+    missing
+        .books
+        .retain(|book_id, _| on_shelf.contains(book_id));
+
+    // Search all titles, owned or not, to tell "not owned" from "not found"
+    let searches = search_shelf(
+        library,
+        SearchOptions {
+            book_type,
+            deep_search: true,
+            max_results: 24,
+        },
+        books,
+    )
+    .await;
+
+    let taken_at = now();
+    let mut run = MissingRun::default();
+    for (book, result) in searches {
+        let owned = match result {
+            Ok(item) if item.is_owned != Some(false) => Some(item),
+            Ok(_) | Err(LibbyError::NotFound(_)) => None,
+            // Every remaining search would fail the same way
+            Err(e @ LibbyError::Unauthorized(_)) => return Err(e).context("searching"),
+            Err(e) => {
+                warn!("Searching for '{}' failed: {:?}", book.title, e);
+                run.search_failed_ct += 1;
+                continue;
+            }
+        };
+        match owned {
+            Some(item) => {
+                if let Some(was_missing) = missing.books.remove(&book.book_id) {
+                    run.acquired.push(Acquired {
+                        libby_id: item.id,
+                        title: book.title.clone(),
+                        missing_since: was_missing.missing_since,
+                        tagged: false,
+                    });
+                }
+            }
+            None => {
+                if let Entry::Vacant(entry) = missing.books.entry(book.book_id) {
+                    run.newly_missing.push(book.title.clone());
+                    entry.insert(MissingBook {
+                        title: book.title.clone(),
+                        author: book.author.clone(),
+                        missing_since: taken_at,
+                    });
+                }
+            }
+        }
+    }
+
+    if let Some(tag) = tag {
+        for acquired in run.acquired.iter_mut() {
+            match library
+                .tag_book_by_overdrive_id(tag, &acquired.libby_id)
+                .await
+            {
+                Ok(()) => acquired.tagged = true,
+                Err(e @ LibbyError::Unauthorized(_)) => return Err(e).context("tagging"),
+                Err(e) => warn!("Tagging '{}' failed: {:?}", acquired.title, e),
+            }
+        }
+    }
+    Ok(run)
+}
+
+/// Whole days between two epoch times.
+pub fn days_between(from: i64, to: i64) -> i64 {
+    (to - from).max(0) / 86400
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::catalog::InMemoryLibrary;
+    use crate::fake_libby::book;
+    use crate::fake_libby::item;
+    use crate::fake_libby::unowned;
+
+    const TAG: &str = "new";
+
+    #[tokio::test]
+    async fn test_track_missing_reports_acquired() {
+        let shelf = [
+            book(1, "The Martian", "Andy Weir"),
+            book(2, "Project Hail Mary", "Andy Weir"),
+            book(3, "Artemis", "Andy Weir"),
+        ];
+        let mut missing = MissingBooks::default();
+        missing.books.insert(
+            4,
+            MissingBook {
+                title: "Since left the shelf".to_string(),
+                author: "Nobody".to_string(),
+                missing_since: 0,
+            },
+        );
+
+        // Artemis is in the catalog but not owned
+        let before = InMemoryLibrary::new()
+            .with_item(item("1", "The Martian", "Andy Weir"), &[])
+            .with_item(unowned(item("3", "Artemis", "Andy Weir")), &[])
+            .with_tag(TAG, &[]);
+        let run = track_missing(&before, BookType::Ebook, &shelf, &mut missing, None)
+            .await
+            .expect("first run");
+        assert!(run.acquired.is_empty());
+        let mut newly_missing = run.newly_missing.clone();
+        newly_missing.sort();
+        assert_eq!(newly_missing, vec!["Artemis", "Project Hail Mary"]);
+        assert_eq!(missing.books.keys().collect::<Vec<_>>(), vec![&2, &3]);
+
+        let after = InMemoryLibrary::new()
+            .with_item(item("1", "The Martian", "Andy Weir"), &[])
+            .with_item(item("2", "Project Hail Mary", "Andy Weir"), &[])
+            .with_item(unowned(item("3", "Artemis", "Andy Weir")), &[])
+            .with_tag(TAG, &[]);
+        let tag = after.get_existing_tag_by_name(TAG).await.expect("tag");
+        let run = track_missing(&after, BookType::Ebook, &shelf, &mut missing, Some(&tag))
+            .await
+            .expect("second run");
+        assert!(run.newly_missing.is_empty());
+        assert_eq!(run.acquired.len(), 1);
+        assert_eq!(run.acquired[0].title, "Project Hail Mary");
+        assert!(run.acquired[0].tagged);
+        assert_eq!(after.tagged(TAG), vec!["2"]);
+        assert_eq!(missing.books.keys().collect::<Vec<_>>(), vec![&3]);
+    }
+}
@@ -0,0 +1,299 @@
+//! Title and author normalization shared by comparisons and library
+//! searches. Goodreads, OverDrive and publishers disagree on series
+//! suffixes, "(Unabridged)", "A Novel", articles, accents, "&", spacing in
+//! Chinese and Japanese and how names are transliterated, none of which
+//! should stop two titles or authors from matching.
+
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+
+use crate::series;
+
+/// Words marking a parenthetical as being about the edition, not the title.
+const EDITION_WORDS: [&str; 10] = [
+    "unabridged",
+    "abridged",
+    "edition",
+    "novel",
+    "illustrated",
+    "annotated",
+    "reprint",
+    "anniversary",
+    "dramatized",
+    "book club",
+];
+
+const LEADING_ARTICLES: [&str; 3] = ["the ", "a ", "an "];
+
+fn is_edition_note(note: &str) -> bool {
+    let note = note.to_lowercase();
+    EDITION_WORDS.iter().any(|w| note.contains(w))
+}
+
+/// Drop one trailing "(Unabridged)", "[Illustrated Edition]" or ": A Novel",
+/// returning None when there was nothing to drop.
+fn strip_edition_suffix(title: &str) -> Option<&str> {
+    let title = title.trim_end();
+    for (open, close) in [('(', ')'), ('[', ']')] {
+        if title.ends_with(close)
+            && let Some(start) = title.rfind(open).filter(|start| *start > 0)
+            && is_edition_note(&title[start + 1..title.len() - 1])
+        {
+            return Some(title[..start].trim_end());
+        }
+    }
+    let lower = title.to_lowercase();
+    for suffix in [": a novel", " - a novel", ", a novel"] {
+        if lower.ends_with(suffix) && lower.len() > suffix.len() {
+            return Some(title[..title.len() - suffix.len()].trim_end());
+        }
+    }
+    None
+}
+
+/// The title without its series and edition suffixes, as it would be
+/// searched for. Case and accents are kept.
+pub fn clean_title(title: &str) -> &str {
+    let mut title = series::strip_series(title).trim();
+    while let Some(stripped) = strip_edition_suffix(title) {
+        title = series::strip_series(stripped).trim();
+    }
+    title
+}
+
+/// Scripts written without spaces between words (Chinese, Japanese), where
+/// each character is taken as a word.
+fn is_unspaced(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x3040..=0x30FF | 0x31F0..=0x31FF | 0x3400..=0x4DBF | 0x4E00..=0x9FFF
+            | 0xF900..=0xFAFF | 0x20000..=0x2FA1F
+    )
+}
+
+/// Latin, Greek and Cyrillic, whose accents can be dropped. Marks in other
+/// scripts (e.g. Devanagari vowel signs) are part of the letter.
+fn has_droppable_accents(c: char) -> bool {
+    matches!(c as u32, 0..=0x052F | 0x1E00..=0x1FFF)
+}
+
+/// Compatibility decomposition (so fullwidth letters and ligatures become
+/// plain ones), without Latin, Greek and Cyrillic accents, lowercased.
+fn fold(s: &str) -> String {
+    let mut folded = String::with_capacity(s.len());
+    let mut drop_marks = false;
+    for c in s.nfkd() {
+        if is_combining_mark(c) {
+            if !drop_marks {
+                folded.push(c);
+            }
+            continue;
+        }
+        drop_marks = has_droppable_accents(c);
+        folded.push(c);
+    }
+    folded.to_lowercase()
+}
+
+/// The words of `s`: runs of letters and digits, with each Chinese or
+/// Japanese character its own word.
+pub fn tokens(s: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut word = String::new();
+    for c in s.chars() {
+        if c.is_alphanumeric() && !is_unspaced(c) || is_combining_mark(c) {
+            word.push(c);
+            continue;
+        }
+        if !word.is_empty() {
+            tokens.push(std::mem::take(&mut word));
+        }
+        if is_unspaced(c) {
+            tokens.push(c.to_string());
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
+
+/// A key for comparing titles: cleaned, folded, without punctuation, "&"
+/// as "and" and without a leading article.
+pub fn normalize_title(title: &str) -> String {
+    let words = tokens(&fold(clean_title(title)).replace('&', " and ")).join(" ");
+    LEADING_ARTICLES
+        .iter()
+        .find_map(|article| words.strip_prefix(article))
+        .unwrap_or(&words)
+        .to_string()
+}
+
+/// A key for comparing author names: transliterated to ASCII, so
+/// "Фёдор Достоевский" is close to "Fyodor Dostoevsky", with the names
+/// sorted so "Murakami Haruki" equals "Haruki Murakami".
+pub fn author_key(name: &str) -> String {
+    let mut names = author_names(name);
+    names.sort();
+    names.join(" ")
+}
+
+fn author_names(name: &str) -> Vec<String> {
+    tokens(&deunicode::deunicode(&fold(name)).to_lowercase())
+}
+
+/// Name suffixes one listing may leave off.
+const AUTHOR_SUFFIXES: &[&str] = &["jr", "sr", "ii", "iii", "iv"];
+
+/// Whether two author names are the same person. The names are paired up
+/// in any order, each allowed about one edit in four letters, and at least
+/// two when either side was transliterated ("Фёдор" becomes "Fedor", not
+/// "Fyodor"), so "Stephen Baxter" is still not "Stephen Booker". One side
+/// may have initials or a suffix the other leaves off. Names split into a
+/// different number of words ("LeGuin", "Liu Ci Xin") are compared run
+/// together.
+pub fn authors_match(a: &str, b: &str) -> bool {
+    let transliterated = !a.is_ascii() || !b.is_ascii();
+    let (a, b) = (author_names(a), author_names(b));
+    if a.is_empty() || b.is_empty() {
+        return false;
+    }
+    if names_pair_up(&a, &b, transliterated) {
+        return true;
+    }
+    let allowed = if transliterated { 2 } else { 1 };
+    a.len() != b.len() && edit_distance::edit_distance(&a.concat(), &b.concat()) <= allowed
+}
+
+/// Whether every name on one side has a close name on the other, leaving
+/// only initials and suffixes on the longer side unpaired.
+fn names_pair_up(a: &[String], b: &[String], transliterated: bool) -> bool {
+    let close = |name: &String, other: &String| {
+        let allowed = name.len().min(other.len()) / 4;
+        let allowed = if transliterated {
+            allowed.max(2)
+        } else {
+            allowed
+        };
+        edit_distance::edit_distance(name, other) <= allowed
+    };
+    let optional =
+        |name: &String| name.chars().count() == 1 || AUTHOR_SUFFIXES.contains(&name.as_str());
+    let mut unpaired_b: Vec<&String> = b.iter().collect();
+    let mut unpaired_a = vec![];
+    let mut paired_full_name = false;
+    for name in a {
+        match unpaired_b.iter().position(|other| close(name, other)) {
+            Some(i) => {
+                paired_full_name |= !optional(name);
+                unpaired_b.swap_remove(i);
+            }
+            None => unpaired_a.push(name),
+        }
+    }
+    paired_full_name
+        && (unpaired_a.is_empty() || unpaired_b.is_empty())
+        && unpaired_a.into_iter().chain(unpaired_b).all(optional)
+}
+
+/// Queries to try in turn when searching a library for a title: the
+/// cleaned title, then the part before any subtitle.
+pub fn search_queries(title: &str) -> Vec<String> {
+    let cleaned = clean_title(title);
+    let mut queries = vec![cleaned.to_string()];
+    if let Some((main, _)) = cleaned.split_once(':')
+        && !main.trim().is_empty()
+    {
+        queries.push(main.trim().to_string());
+    }
+    queries.dedup();
+    queries
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize_title() {
+        for (a, b) in [
+            ("Leviathan Wakes (The Expanse, #1)", "Leviathan Wakes"),
+            ("Project Hail Mary (Unabridged)", "project hail mary"),
+            ("The Martian: A Novel", "Martian"),
+            ("Pride & Prejudice", "Pride and Prejudice"),
+            ("Les Misérables", "Les Miserables"),
+            (
+                "The Hobbit [75th Anniversary Edition] (Middle-earth, #0)",
+                "The Hobbit",
+            ),
+            ("A Wizard of Earthsea", "Wizard of Earthsea"),
+        ] {
+            let normalized = normalize_title(a);
+            assert_eq!(normalized, normalize_title(b), "{a} vs {b}");
+            assert!(!normalized.is_empty());
+        }
+        assert_eq!(normalize_title("Pride & Prejudice"), "pride and prejudice");
+        // Punctuation separates words
+        assert_eq!(normalize_title("Catch-22"), "catch 22");
+        assert_eq!(
+            normalize_title("Portrait of a Novel"),
+            "portrait of a novel"
+        );
+    }
+
+    #[test]
+    fn test_normalize_title_non_latin() {
+        for (a, b) in [
+            ("三体", "三 体"),
+            ("ノルウェイの森 (Unabridged)", "ノルウェイの森"),
+            ("Ｐｒｏｊｅｃｔ Ｈａｉｌ Ｍａｒｙ", "Project Hail Mary"),
+            ("Ёлка", "елка"),
+            ("Мастер и Маргарита", "МАСТЕР И МАРГАРИТА"),
+        ] {
+            assert_eq!(normalize_title(a), normalize_title(b), "{a} vs {b}");
+        }
+        assert_eq!(normalize_title("三体:黑暗森林"), "三 体 黑 暗 森 林");
+        // Devanagari vowel signs are letters, not accents
+        assert_ne!(normalize_title("कम"), normalize_title("काम"));
+        assert_eq!(tokens("iPhone手册"), vec!["iPhone", "手", "册"]);
+    }
+
+    #[test]
+    fn test_authors_match() {
+        for (a, b) in [
+            ("Andy Weir", "andy weir"),
+            ("Фёдор Достоевский", "Fyodor Dostoevsky"),
+            ("Fyodor Dostoyevsky", "Fyodor Dostoevsky"),
+            ("Murakami Haruki", "Haruki Murakami"),
+            ("村上 春樹", "村上春樹"),
+            ("Gabriel García Márquez", "Gabriel Garcia Marquez"),
+            ("刘慈欣", "Liu Cixin"),
+            ("Ursula K. LeGuin", "Ursula K. Le Guin"),
+            ("Ursula K. Le Guin", "Ursula Le Guin"),
+            ("Martin Luther King Jr.", "Martin Luther King"),
+        ] {
+            assert!(authors_match(a, b), "{a} vs {b}");
+        }
+        assert!(!authors_match("Stephen King", "Stephen Fry"));
+        assert!(!authors_match("Stephen Baxter", "Stephen Booker"));
+        assert!(!authors_match("Andy Weir", "Andy"));
+        assert!(!authors_match("J. King", "K. King"));
+        assert!(!authors_match("", "Andy Weir"));
+    }
+
+    #[test]
+    fn test_search_queries() {
+        assert_eq!(
+            search_queries("Abaddon's Gate (The Expanse, #3)"),
+            vec!["Abaddon's Gate"]
+        );
+        assert_eq!(
+            search_queries("Sapiens: A Brief History of Humankind (Unabridged)"),
+            vec!["Sapiens: A Brief History of Humankind", "Sapiens"]
+        );
+        assert_eq!(
+            search_queries("Project Hail Mary"),
+            vec!["Project Hail Mary"]
+        );
+    }
+}
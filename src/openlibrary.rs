@@ -0,0 +1,359 @@
+//! Fills in what Goodreads exports often leave empty (page counts, years,
+//! ISBNs) from Open Library, or anything serving the same API.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use futures::StreamExt;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::debug;
+use tracing::warn;
+
+use crate::goodreads;
+use crate::goodreads::clean_isbn;
+use crate::json_state;
+use crate::normalize::search_queries;
+use crate::request_policy::RequestLayer;
+use crate::request_policy::RequestPolicy;
+
+pub const OPENLIBRARY_BASE: &str = "https://openlibrary.org";
+
+/// What Open Library knows about a book.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BookMetadata {
+    pub pages: Option<i64>,
+    pub year: Option<i16>,
+    pub isbn: Option<String>,
+}
+
+impl BookMetadata {
+    fn or(self, other: Self) -> Self {
+        Self {
+            pages: self.pages.or(other.pages),
+            year: self.year.or(other.year),
+            isbn: self.isbn.or(other.isbn),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.pages.is_some() && self.year.is_some() && self.isbn.is_some()
+    }
+
+    /// Fill the fields `book` is missing, returning whether any were.
+    fn fill(&self, book: &mut goodreads::BookInfo) -> bool {
+        let mut filled = false;
+        if book.number_of_pages.is_none() && self.pages.is_some() {
+            book.number_of_pages = self.pages;
+            filled = true;
+        }
+        if book.year_published.is_none() && self.year.is_some() {
+            book.year_published = self.year;
+            filled = true;
+        }
+        if let Some(isbn) = &self.isbn
+            && clean_isbn(&book.isbn).is_none()
+        {
+            book.isbn = isbn.clone();
+            filled = true;
+        }
+        filled
+    }
+}
+
+/// Lookups by Goodreads book id. Books Open Library doesn't have are kept
+/// too, as empty metadata, so they aren't looked up again.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct MetadataCache {
+    entries: HashMap<i64, BookMetadata>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Edition {
+    number_of_pages: Option<i64>,
+    publish_date: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchDoc {
+    number_of_pages_median: Option<i64>,
+    first_publish_year: Option<i16>,
+    #[serde(default)]
+    isbn: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchResponse {
+    docs: Vec<SearchDoc>,
+}
+
+/// The year in a free-form date such as "May 4, 2021".
+fn parse_year(date: &str) -> Option<i16> {
+    date.split(|c: char| !c.is_ascii_digit())
+        .find(|part| part.len() == 4)
+        .and_then(|year| year.parse().ok())
+}
+
+fn needs_metadata(book: &goodreads::BookInfo) -> bool {
+    book.number_of_pages.is_none()
+        || book.year_published.is_none()
+        || clean_isbn(&book.isbn).is_none()
+}
+
+pub struct OpenLibraryClient {
+    client: reqwest::Client,
+    base: String,
+    requests: RequestLayer,
+}
+
+impl OpenLibraryClient {
+    pub fn new(base: impl Into<String>) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .build()
+            .context("building reqwest client")?;
+        Ok(Self {
+            client,
+            base: base.into().trim_end_matches('/').to_string(),
+            requests: RequestLayer::new(RequestPolicy::default()),
+        })
+    }
+
+    /// Replace the default rate limits and retry behaviour.
+    pub fn with_request_policy(mut self, policy: RequestPolicy) -> Self {
+        self.requests = RequestLayer::new(policy);
+        self
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<Option<T>> {
+        let resp = self
+            .requests
+            .send(|| self.client.get(url))
+            .await
+            .context("open library request")?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !resp.status().is_success() {
+            bail!("open library request failed (status {})", resp.status());
+        }
+        let body = resp.text().await.context("reading open library response")?;
+        serde_json::from_str(&body).map(Some).map_err(|e| {
+            debug!("Unparsable open library response: '{}'", body);
+            anyhow::anyhow!("unexpected open library response: {e}")
+        })
+    }
+
+    /// Metadata of the edition with this ISBN.
+    pub async fn lookup_isbn(&self, isbn: &str) -> Result<Option<BookMetadata>> {
+        let url = format!("{}/isbn/{}.json", self.base, isbn);
+        Ok(self
+            .get_json::<Edition>(&url)
+            .await?
+            .map(|edition| BookMetadata {
+                pages: edition.number_of_pages,
+                year: edition.publish_date.as_deref().and_then(parse_year),
+                isbn: Some(isbn.to_string()),
+            }))
+    }
+
+    /// Metadata of the best search match for a title and author.
+    pub async fn search(&self, title: &str, author: &str) -> Result<Option<BookMetadata>> {
+        // Like Libby, the search does not cope well with series, edition
+        // notes or subtitles
+        for query in search_queries(title) {
+            let url = reqwest::Url::parse_with_params(
+                &format!("{}/search.json", self.base),
+                &[
+                    ("title", query.as_str()),
+                    ("author", author),
+                    ("fields", "number_of_pages_median,first_publish_year,isbn"),
+                    ("limit", "1"),
+                ],
+            )?;
+            if let Some(doc) = self
+                .get_json::<SearchResponse>(url.as_str())
+                .await?
+                .and_then(|resp| resp.docs.into_iter().next())
+            {
+                return Ok(Some(BookMetadata {
+                    pages: doc.number_of_pages_median,
+                    year: doc.first_publish_year,
+                    isbn: doc.isbn.into_iter().next(),
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Look a book up by its ISBN, then by title for anything still missing.
+    pub async fn lookup(&self, book: &goodreads::BookInfo) -> Result<BookMetadata> {
+        let mut metadata = BookMetadata::default();
+        if let Some(isbn) = clean_isbn(&book.isbn) {
+            metadata = self.lookup_isbn(isbn).await?.unwrap_or_default();
+        }
+        if !metadata.is_complete()
+            && let Some(found) = self.search(&book.title, &book.author).await?
+        {
+            metadata = metadata.or(found);
+        }
+        Ok(metadata)
+    }
+
+    /// Fill in missing pages, years and ISBNs, looking up only books not
+    /// already in `cache_file`. Returns how many books were changed.
+    pub async fn enrich(
+        &self,
+        books: &mut [goodreads::BookInfo],
+        cache_file: &Path,
+    ) -> Result<usize> {
+        let mut cache: MetadataCache = json_state::load(cache_file).await;
+        let missing: Vec<_> = books
+            .iter()
+            .filter(|b| needs_metadata(b) && !cache.entries.contains_key(&b.book_id))
+            .collect();
+        if !missing.is_empty() {
+            eprintln!("Looking up {} books on Open Library...", missing.len());
+            let lookups: Vec<_> = futures::stream::iter(
+                missing
+                    .into_iter()
+                    .map(|book| async move { (book, self.lookup(book).await) })
+                    .collect::<Vec<_>>(),
+            )
+            .buffer_unordered(10)
+            .collect()
+            .await;
+            for (book, result) in lookups {
+                match result {
+                    Ok(metadata) => {
+                        cache.entries.insert(book.book_id, metadata);
+                    }
+                    Err(e) => warn!("Open Library lookup for '{}' failed: {:?}", book.title, e),
+                }
+            }
+            json_state::save(&cache, cache_file).await?;
+        }
+
+        let mut filled = 0;
+        for book in books.iter_mut() {
+            if let Some(metadata) = cache.entries.get(&book.book_id)
+                && metadata.fill(book)
+            {
+                filled += 1;
+            }
+        }
+        eprintln!("Filled in details for {} books from Open Library", filled);
+        Ok(filled)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use wiremock::Mock;
+    use wiremock::MockServer;
+    use wiremock::ResponseTemplate;
+    use wiremock::matchers::method;
+    use wiremock::matchers::path;
+    use wiremock::matchers::query_param;
+
+    use super::*;
+    use crate::fake_libby;
+
+    macro_rules! fixture {
+        ($name:literal) => {
+            include_str!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/openlibrary/",
+                $name
+            ))
+        };
+    }
+
+    fn book(book_id: i64, title: &str, author: &str, isbn: &str) -> goodreads::BookInfo {
+        goodreads::BookInfo {
+            isbn: isbn.to_string(),
+            ..fake_libby::book(book_id, title, author)
+        }
+    }
+
+    #[test]
+    fn test_parse_year() {
+        assert_eq!(parse_year("May 4, 2021"), Some(2021));
+        assert_eq!(parse_year("2021-05-04"), Some(2021));
+        assert_eq!(parse_year("unknown"), None);
+    }
+
+    #[tokio::test]
+    async fn test_enrich_fills_missing_and_caches() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/isbn/0593135202.json"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(fixture!("isbn_0593135202.json"), "application/json"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/search.json"))
+            .and(query_param("title", "The Martian"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(fixture!("search_the_martian.json"), "application/json"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/search.json"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(fixture!("search_empty.json"), "application/json"),
+            )
+            .with_priority(10)
+            .expect(1)
+            .mount(&server)
+            .await;
+        let cache_file = std::env::temp_dir().join(format!(
+            "gr2libby-openlibrary-{}-{}.json",
+            std::process::id(),
+            server.address().port()
+        ));
+        let client = OpenLibraryClient::new(server.uri()).expect("client");
+        let shelf = || {
+            vec![
+                book(1, "Project Hail Mary", "Andy Weir", "=\"0593135202\""),
+                book(2, "The Martian: A Novel (Unabridged)", "Andy Weir", "=\"\""),
+                book(3, "A Book No Library Has", "Nobody", "=\"\""),
+            ]
+        };
+
+        let mut books = shelf();
+        let filled = client
+            .enrich(&mut books, &cache_file)
+            .await
+            .expect("enrich");
+        assert_eq!(filled, 2);
+        assert_eq!(books[0].number_of_pages, Some(476));
+        assert_eq!(books[0].year_published, Some(2021));
+        assert_eq!(books[0].isbn, "=\"0593135202\"");
+        assert_eq!(books[1].number_of_pages, Some(387));
+        assert_eq!(books[1].year_published, Some(2011));
+        assert_eq!(books[1].isbn, "9780553418026");
+        assert_eq!(books[2].number_of_pages, None);
+
+        // Everything is answered from the cache the second time
+        let mut books = shelf();
+        let filled = client
+            .enrich(&mut books, &cache_file)
+            .await
+            .expect("enrich");
+        assert_eq!(filled, 2);
+        assert_eq!(books[1].number_of_pages, Some(387));
+        let _ = std::fs::remove_file(&cache_file);
+    }
+}
@@ -0,0 +1,214 @@
+//! To-read books the library lists but doesn't own, ranked for a "recommend
+//! for purchase" list, and optionally sent to the library as
+//! recommendations.
+
+use std::collections::BTreeSet;
+
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::catalog::LibraryCatalog;
+use crate::catalog::search_shelf;
+use crate::goodreads;
+use crate::libby::BookType;
+use crate::libby::LibbyClient;
+use crate::libby::LibbyError;
+use crate::libby::SearchOptions;
+
+/// A book the library could buy.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub libby_id: String,
+    pub title: String,
+    pub author: String,
+    pub average_rating: Option<f64>,
+    /// As in the export, e.g. "2024/01/06"
+    pub date_added: String,
+}
+
+/// Libby ids already recommended, so later runs don't send them again.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Recommended {
+    pub libby_ids: BTreeSet<String>,
+}
+
+/// Search every book including titles the library doesn't have, keeping
+/// the ones it lists but doesn't own. Books missing from the catalog
+/// entirely can't be recommended and are left out.
+pub async fn find_candidates<L: LibraryCatalog>(
+    library: &L,
+    book_type: BookType,
+    books: &[goodreads::BookInfo],
+) -> Result<Vec<Candidate>> {
+    let searches = search_shelf(
+        library,
+        SearchOptions {
+            book_type,
+            deep_search: true,
+            max_results: 24,
+        },
+        books,
+    )
+    .await;
+
+    let mut candidates = vec![];
+    for (book, result) in searches {
+        match result {
+            Ok(item) if item.is_owned == Some(false) => candidates.push(Candidate {
+                libby_id: item.id,
+                title: book.title.clone(),
+                author: book.author.clone(),
+                average_rating: book.average_rating,
+                date_added: book.date_added.clone(),
+            }),
+            Ok(_) | Err(LibbyError::NotFound(_)) => {}
+            // Every remaining search would fail the same way
+            Err(e @ LibbyError::Unauthorized(_)) => return Err(e).context("searching"),
+            Err(e) => warn!("Searching for '{}' failed: {:?}", book.title, e),
+        }
+    }
+    rank(&mut candidates);
+    Ok(candidates)
+}
+
+/// Highest rated first; among equal ratings, the longest wanted.
+pub fn rank(candidates: &mut [Candidate]) {
+    candidates.sort_by(|a, b| {
+        b.average_rating
+            .unwrap_or(0.0)
+            .total_cmp(&a.average_rating.unwrap_or(0.0))
+            .then_with(|| a.date_added.cmp(&b.date_added))
+    });
+}
+
+/// The ranked list, marking titles already recommended.
+pub fn render_report(candidates: &[Candidate], recommended: &Recommended) -> String {
+    let mut out = String::new();
+    for (i, c) in candidates.iter().enumerate() {
+        out.push_str(&format!(
+            "{:>3}. {:.2}  {:<10}  {} by {}{}\n",
+            i + 1,
+            c.average_rating.unwrap_or(0.0),
+            c.date_added,
+            c.title,
+            c.author,
+            if recommended.libby_ids.contains(&c.libby_id) {
+                " (recommended)"
+            } else {
+                ""
+            }
+        ));
+    }
+    if candidates.is_empty() {
+        out.push_str("The library owns or can't buy every book on the shelf\n");
+    }
+    out
+}
+
+/// Recommend up to `limit` candidates not recommended before, best ranked
+/// first. Returns the titles sent.
+pub async fn submit(
+    libby_client: &LibbyClient,
+    candidates: &[Candidate],
+    recommended: &mut Recommended,
+    limit: usize,
+) -> Result<Vec<String>> {
+    let unsent: Vec<&Candidate> = candidates
+        .iter()
+        .filter(|c| !recommended.libby_ids.contains(&c.libby_id))
+        .take(limit)
+        .collect();
+    let mut sent = vec![];
+    for c in unsent {
+        match libby_client.recommend(&c.libby_id).await {
+            Ok(()) => {
+                recommended.libby_ids.insert(c.libby_id.clone());
+                sent.push(c.title.clone());
+            }
+            Err(e @ LibbyError::Unauthorized(_)) => return Err(e).context("recommending"),
+            Err(e) => warn!("Recommending '{}' failed: {:?}", c.title, e),
+        }
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::catalog::InMemoryLibrary;
+    use crate::fake_libby;
+    use crate::fake_libby::FakeLibby;
+    use crate::fake_libby::item;
+    use crate::fake_libby::unowned;
+
+    fn book(title: &str, rating: f64, date_added: &str) -> goodreads::BookInfo {
+        goodreads::BookInfo {
+            average_rating: Some(rating),
+            date_added: date_added.to_string(),
+            ..fake_libby::book(1, title, "Andy Weir")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_find_candidates_ranks_unowned() {
+        let library = InMemoryLibrary::new()
+            .with_item(item("1", "The Martian", "Andy Weir"), &[])
+            .with_item(unowned(item("2", "Project Hail Mary", "Andy Weir")), &[])
+            .with_item(unowned(item("3", "Artemis", "Andy Weir")), &[])
+            .with_item(unowned(item("4", "Cheshire Crossing", "Andy Weir")), &[]);
+        let shelf = [
+            book("The Martian", 4.4, "2023/01/01"),
+            book("Artemis", 3.9, "2024/03/01"),
+            book("Cheshire Crossing", 3.9, "2022/05/01"),
+            book("Project Hail Mary", 4.5, "2024/01/06"),
+            book("Not In The Catalog", 5.0, "2020/01/01"),
+        ];
+
+        let candidates = find_candidates(&library, BookType::Ebook, &shelf)
+            .await
+            .expect("candidates");
+        let titles: Vec<_> = candidates.iter().map(|c| c.title.as_str()).collect();
+        assert_eq!(
+            titles,
+            vec!["Project Hail Mary", "Cheshire Crossing", "Artemis"]
+        );
+
+        let recommended = Recommended {
+            libby_ids: BTreeSet::from(["2".to_string()]),
+        };
+        let report = render_report(&candidates, &recommended);
+        assert!(
+            report.contains("  1. 4.50  2024/01/06  Project Hail Mary by Andy Weir (recommended)")
+        );
+        assert!(report.contains("  3. 3.90  2024/03/01  Artemis by Andy Weir\n"));
+    }
+
+    #[tokio::test]
+    async fn test_submit_skips_already_recommended() {
+        let fake = FakeLibby::start().await;
+        let libby_client = fake.client().await;
+        let candidate = |libby_id: &str, title: &str| Candidate {
+            libby_id: libby_id.to_string(),
+            title: title.to_string(),
+            author: "Andy Weir".to_string(),
+            average_rating: None,
+            date_added: String::new(),
+        };
+        let candidates = [
+            candidate("1001", "Already Sent"),
+            candidate(fake_libby::FOUND_ID, fake_libby::FOUND_TITLE),
+        ];
+        let mut recommended = Recommended {
+            libby_ids: BTreeSet::from(["1001".to_string()]),
+        };
+
+        let sent = submit(&libby_client, &candidates, &mut recommended, 5)
+            .await
+            .expect("submit");
+        assert_eq!(sent, vec![fake_libby::FOUND_TITLE]);
+        assert!(recommended.libby_ids.contains(fake_libby::FOUND_ID));
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use reqwest::StatusCode;
+use reqwest::header::HeaderMap;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+use tracing::debug;
+use tracing::warn;
+
+/// Longest we will wait between two attempts of the same request.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Longest `Retry-After` we wait out. A server asking for more is not going
+/// to be ready within one run, so the request is given up on instead.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+const DEFAULT_MAX_REQUESTS_PER_SEC: f64 = 10.0;
+const DEFAULT_PER_HOST_CONCURRENCY: usize = 8;
+const DEFAULT_MAX_RETRIES: u32 = 4;
+const DEFAULT_RETRY_BASE_MS: u64 = 500;
+
+/// Slowest rate accepted, so the gap between requests stays representable.
+const MIN_REQUESTS_PER_SEC: f64 = 0.001;
+
+/// Limits and retry behaviour for requests to Libby and OverDrive.
+#[derive(clap::Args, Debug, Clone)]
+pub struct RequestPolicy {
+    /// Global limit on requests per second to Libby/OverDrive (0 for no limit)
+    #[clap(long, default_value_t = DEFAULT_MAX_REQUESTS_PER_SEC, value_parser = parse_rate)]
+    pub max_requests_per_sec: f64,
+
+    /// Maximum number of concurrent requests to a single host
+    #[clap(long, default_value_t = DEFAULT_PER_HOST_CONCURRENCY)]
+    pub per_host_concurrency: usize,
+
+    /// How often to retry a request that hit a 429, a 5xx or a network error
+    #[clap(long, default_value_t = DEFAULT_MAX_RETRIES)]
+    pub max_retries: u32,
+
+    /// Delay before the first retry in milliseconds, doubled on each attempt
+    #[clap(long, default_value_t = DEFAULT_RETRY_BASE_MS)]
+    pub retry_base_ms: u64,
+}
+
+impl Default for RequestPolicy {
+    fn default() -> Self {
+        Self {
+            max_requests_per_sec: DEFAULT_MAX_REQUESTS_PER_SEC,
+            per_host_concurrency: DEFAULT_PER_HOST_CONCURRENCY,
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_base_ms: DEFAULT_RETRY_BASE_MS,
+        }
+    }
+}
+
+/// A requests-per-second limit: 0, or a finite rate of at least
+/// `MIN_REQUESTS_PER_SEC`.
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    if rate == 0.0 || (rate.is_finite() && rate >= MIN_REQUESTS_PER_SEC) {
+        Ok(rate)
+    } else {
+        Err(format!(
+            "must be 0 (no limit) or a number of at least {MIN_REQUESTS_PER_SEC}"
+        ))
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RequestStats {
+    /// Retries performed, across all requests
+    pub retried: usize,
+    /// Requests that still failed after their last attempt
+    pub failed: usize,
+}
+
+impl std::fmt::Display for RequestStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Retried {}, Failed {}", self.retried, self.failed)
+    }
+}
+
+/// Parse a `Retry-After` header given in seconds.
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+        .map(Duration::from_secs)
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Shared by every request a client makes: spaces requests out to the global
+/// rate, caps concurrency per host and retries transient failures with
+/// exponential backoff.
+pub(crate) struct RequestLayer {
+    policy: RequestPolicy,
+    next_slot: tokio::sync::Mutex<Instant>,
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+    retried: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl RequestLayer {
+    pub(crate) fn new(policy: RequestPolicy) -> Self {
+        Self {
+            policy,
+            next_slot: tokio::sync::Mutex::new(Instant::now()),
+            hosts: Mutex::new(HashMap::new()),
+            retried: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> RequestStats {
+        RequestStats {
+            retried: self.retried.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+
+    async fn wait_for_slot(&self) {
+        if self.policy.max_requests_per_sec <= 0.0 {
+            return;
+        }
+        let interval = Duration::from_secs_f64(1.0 / self.policy.max_requests_per_sec);
+        let slot = {
+            let mut next_slot = self.next_slot.lock().await;
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+
+    async fn host_permit(&self, host: &str) -> OwnedSemaphorePermit {
+        let semaphore = self
+            .hosts
+            .lock()
+            .expect("host semaphores lock poisoned")
+            .entry(host.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.policy.per_host_concurrency.max(1))))
+            .clone();
+        semaphore
+            .acquire_owned()
+            .await
+            .expect("host semaphore is never closed")
+    }
+
+    /// Exponential backoff with jitter, unless the server said how long to
+    /// wait. None when the server wants us to wait longer than we will.
+    fn backoff(&self, attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        if let Some(retry_after) = retry_after {
+            return (retry_after <= MAX_RETRY_AFTER).then_some(retry_after);
+        }
+        let exp = Duration::from_millis(self.policy.retry_base_ms)
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_BACKOFF);
+        // Cheap jitter: spread retries over the upper half of the window
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        Some(exp / 2 + (exp / 2).mul_f64(f64::from(nanos % 1000) / 1000.0))
+    }
+
+    /// Send the request from `build`, rebuilding it for each retry.
+    pub(crate) async fn send<F>(&self, build: F) -> reqwest::Result<reqwest::Response>
+    where
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        let mut attempt = 0;
+        loop {
+            let (client, request) = build().build_split();
+            let request = request?;
+            let url = request.url().clone();
+            let result = {
+                let _permit = self.host_permit(url.host_str().unwrap_or_default()).await;
+                self.wait_for_slot().await;
+                client.execute(request).await
+            };
+
+            let retry_after = match &result {
+                Ok(resp) if is_retryable_status(resp.status()) => {
+                    debug!("{} from {}", resp.status(), url);
+                    retry_after(resp.headers())
+                }
+                Err(e) if e.is_timeout() || e.is_connect() => {
+                    debug!("request to {} failed: {}", url, e);
+                    None
+                }
+                Ok(_) => return result,
+                Err(_) => {
+                    self.failed.fetch_add(1, Ordering::Relaxed);
+                    return result;
+                }
+            };
+            if attempt >= self.policy.max_retries {
+                warn!("giving up on {} after {} attempts", url, attempt + 1);
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                return result;
+            }
+
+            let Some(delay) = self.backoff(attempt, retry_after) else {
+                warn!(
+                    "giving up on {}, asked to retry after {}s",
+                    url,
+                    retry_after.unwrap_or_default().as_secs()
+                );
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                return result;
+            };
+            debug!(
+                "retrying {} in {}ms (attempt {})",
+                url,
+                delay.as_millis(),
+                attempt + 2
+            );
+            self.retried.fetch_add(1, Ordering::Relaxed);
+            attempt += 1;
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_and_honors_retry_after() {
+        let layer = RequestLayer::new(RequestPolicy {
+            retry_base_ms: 100,
+            ..Default::default()
+        });
+        let first = layer.backoff(0, None).expect("backoff");
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+        let third = layer.backoff(2, None).expect("backoff");
+        assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+        // Capped, then jittered over the upper half like the others
+        for attempt in [10, 30] {
+            let capped = layer.backoff(attempt, None).expect("backoff");
+            assert!(capped >= MAX_BACKOFF / 2 && capped <= MAX_BACKOFF);
+        }
+        assert_eq!(
+            layer.backoff(0, Some(Duration::from_secs(3))),
+            Some(Duration::from_secs(3))
+        );
+        // Longer than the exponential cap, but still worth waiting for
+        assert_eq!(
+            layer.backoff(0, Some(Duration::from_secs(120))),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(layer.backoff(0, Some(Duration::from_secs(3600))), None);
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("0"), Ok(0.0));
+        assert_eq!(parse_rate("2.5"), Ok(2.5));
+        for bad in ["NaN", "inf", "-1", "1e-300", "fast"] {
+            assert!(parse_rate(bad).is_err(), "{bad}");
+        }
+    }
+}
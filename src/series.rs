@@ -0,0 +1,267 @@
+//! Series a book belongs to, from the "(The Expanse, #1)" Goodreads adds to
+//! titles or from OverDrive's series metadata, and picking the next unread
+//! book of each series.
+
+use std::collections::HashMap;
+
+use anyhow::Context;
+use anyhow::Result;
+use tracing::debug;
+use tracing::warn;
+
+use crate::catalog::LibraryCatalog;
+use crate::catalog::search_shelf;
+use crate::goodreads;
+use crate::libby::LibbyError;
+use crate::libby::LibbySearchResultItem;
+use crate::libby::SearchOptions;
+
+/// Where a book sits in a series.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeriesPosition {
+    pub name: String,
+    /// Fractional for novellas between books, e.g. 2.5
+    pub number: f64,
+}
+
+impl SeriesPosition {
+    /// Series names compared loosely, as Goodreads and OverDrive don't
+    /// always agree on "The" or punctuation.
+    pub fn key(&self) -> String {
+        let name = self.name.to_lowercase();
+        name.strip_prefix("the ")
+            .unwrap_or(&name)
+            .chars()
+            .filter(|c| c.is_alphanumeric())
+            .collect()
+    }
+
+    /// From OverDrive's series metadata, when it has a reading order.
+    pub fn from_libby(item: &LibbySearchResultItem) -> Option<Self> {
+        let series = item.detailed_series.as_ref()?;
+        Some(Self {
+            name: series.series_name.clone(),
+            number: parse_number(series.reading_order.as_deref()?)?,
+        })
+    }
+}
+
+impl std::fmt::Display for SeriesPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} #{}", self.name, self.number)
+    }
+}
+
+/// The leading number of "1", "2.5" or "1-3".
+fn parse_number(s: &str) -> Option<f64> {
+    let end = s
+        .trim()
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.trim().len());
+    s.trim()[..end].parse().ok()
+}
+
+/// Split a Goodreads title such as "Leviathan Wakes (The Expanse, #1)" into
+/// the title and its series. Titles in several series keep the first.
+pub fn parse_goodreads_title(title: &str) -> (&str, Option<SeriesPosition>) {
+    let trimmed = title.trim_end();
+    let Some(open) = trimmed.rfind('(').filter(|_| trimmed.ends_with(')')) else {
+        return (title, None);
+    };
+    let inner = &trimmed[open + 1..trimmed.len() - 1];
+    let first = inner.split(';').next().unwrap_or(inner);
+    let Some((name, number)) = first.rsplit_once('#') else {
+        return (title, None);
+    };
+    let name = name.trim().trim_end_matches(',').trim();
+    match parse_number(number) {
+        Some(number) if !name.is_empty() => (
+            trimmed[..open].trim_end(),
+            Some(SeriesPosition {
+                name: name.to_string(),
+                number,
+            }),
+        ),
+        _ => (title, None),
+    }
+}
+
+/// The title without any Goodreads series suffix.
+pub fn strip_series(title: &str) -> &str {
+    parse_goodreads_title(title).0
+}
+
+/// The furthest book read in each series, by series key.
+pub fn series_progress(read: &[goodreads::BookInfo]) -> HashMap<String, SeriesPosition> {
+    let mut progress: HashMap<String, SeriesPosition> = HashMap::new();
+    for position in read
+        .iter()
+        .filter_map(|b| parse_goodreads_title(&b.title).1)
+    {
+        let furthest = progress.entry(position.key()).or_insert(position.clone());
+        if position.number > furthest.number {
+            *furthest = position;
+        }
+    }
+    progress
+}
+
+/// From `to_read`, the lowest numbered book past the furthest read in each
+/// series started on the `read` shelf. Books whose Goodreads title has no
+/// series are looked up in the library for OverDrive's series metadata.
+pub async fn next_in_series<L: LibraryCatalog>(
+    library: &L,
+    search_opts: SearchOptions,
+    read: &[goodreads::BookInfo],
+    to_read: &[goodreads::BookInfo],
+) -> Result<Vec<(goodreads::BookInfo, SeriesPosition)>> {
+    let progress = series_progress(read);
+    if progress.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut positioned = vec![];
+    let mut unknown = vec![];
+    for book in to_read {
+        match parse_goodreads_title(&book.title).1 {
+            Some(position) => positioned.push((book, position)),
+            None => unknown.push(book),
+        }
+    }
+    let lookups = search_shelf(library, search_opts, unknown).await;
+    for (book, result) in lookups {
+        match result {
+            Ok(item) => {
+                if let Some(position) = SeriesPosition::from_libby(&item) {
+                    positioned.push((book, position));
+                }
+            }
+            Err(LibbyError::NotFound(_)) => debug!("Not found in Libby: '{}'", book.title),
+            // Every remaining search would fail the same way
+            Err(e @ LibbyError::Unauthorized(_)) => return Err(e).context("searching"),
+            Err(e) => warn!("Searching for '{}' failed: {:?}", book.title, e),
+        }
+    }
+
+    let mut next: HashMap<String, (&goodreads::BookInfo, SeriesPosition)> = HashMap::new();
+    for (book, position) in positioned {
+        let key = position.key();
+        let Some(furthest) = progress.get(&key) else {
+            continue;
+        };
+        if position.number <= furthest.number {
+            continue;
+        }
+        match next.get(&key) {
+            Some((_, current)) if current.number <= position.number => {}
+            _ => {
+                next.insert(key, (book, position));
+            }
+        }
+    }
+    let mut next: Vec<_> = next
+        .into_values()
+        .map(|(book, position)| (book.clone(), position))
+        .collect();
+    next.sort_by(|(_, a), (_, b)| a.name.cmp(&b.name));
+    Ok(next)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::catalog::InMemoryLibrary;
+    use crate::fake_libby;
+    use crate::libby::BookType;
+    use crate::libby::LibbySeries;
+
+    fn book(title: &str) -> goodreads::BookInfo {
+        fake_libby::book(1, title, "James S.A. Corey")
+    }
+
+    #[test]
+    fn test_parse_goodreads_title() {
+        let (title, series) = parse_goodreads_title("Leviathan Wakes (The Expanse, #1)");
+        assert_eq!(title, "Leviathan Wakes");
+        assert_eq!(
+            series,
+            Some(SeriesPosition {
+                name: "The Expanse".to_string(),
+                number: 1.0
+            })
+        );
+        let (_, series) = parse_goodreads_title("Gods of Risk (The Expanse #2.5)");
+        assert_eq!(series.map(|s| s.number), Some(2.5));
+        let (title, series) = parse_goodreads_title(
+            "The Fellowship of the Ring (The Lord of the Rings, #1; Middle-earth, #3)",
+        );
+        assert_eq!(title, "The Fellowship of the Ring");
+        assert_eq!(
+            series.map(|s| s.name),
+            Some("The Lord of the Rings".to_string())
+        );
+        assert_eq!(
+            parse_goodreads_title("Project Hail Mary"),
+            ("Project Hail Mary", None)
+        );
+        assert_eq!(
+            parse_goodreads_title("Sapiens (Illustrated Edition)"),
+            ("Sapiens (Illustrated Edition)", None)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_next_in_series() {
+        let read = [
+            book("Leviathan Wakes (The Expanse, #1)"),
+            book("Caliban's War (The Expanse, #2)"),
+            book("The Fifth Season (The Broken Earth, #1)"),
+            book("Project Hail Mary"),
+        ];
+        let to_read = [
+            book("Abaddon's Gate (The Expanse, #3)"),
+            book("Caliban's War (The Expanse, #2)"),
+            book("Cibola Burn (The Expanse, #4)"),
+            book("The Obelisk Gate"),
+            book("Dune (Dune, #1)"),
+        ];
+        // Only OverDrive knows "The Obelisk Gate" is in the series
+        let library = InMemoryLibrary::new().with_item(
+            LibbySearchResultItem {
+                detailed_series: Some(LibbySeries {
+                    series_name: "Broken Earth".to_string(),
+                    reading_order: Some("2".to_string()),
+                }),
+                ..fake_libby::item("2", "The Obelisk Gate", "James S.A. Corey")
+            },
+            &[],
+        );
+
+        let next = next_in_series(
+            &library,
+            SearchOptions {
+                book_type: BookType::Ebook,
+                deep_search: false,
+                max_results: 24,
+            },
+            &read,
+            &to_read,
+        )
+        .await
+        .expect("next in series");
+        let next: Vec<_> = next
+            .iter()
+            .map(|(book, position)| (book.title.as_str(), position.to_string()))
+            .collect();
+        assert_eq!(
+            next,
+            vec![
+                ("The Obelisk Gate", "Broken Earth #2".to_string()),
+                (
+                    "Abaddon's Gate (The Expanse, #3)",
+                    "The Expanse #3".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,896 @@
+//! `watch`: check the Libby titles matched from a Goodreads shelf every so
+//! often and send a notification when one becomes available to borrow.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::anyhow;
+use anyhow::bail;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
+use crate::catalog::LibraryCatalog;
+use crate::catalog::search_shelf;
+use crate::goodreads;
+use crate::json_state;
+use crate::libby::Availability;
+use crate::libby::BookType;
+use crate::libby::LibbyClient;
+use crate::libby::LibbyEndpoints;
+use crate::libby::LibbyError;
+use crate::libby::SearchOptions;
+use crate::request_policy::RequestPolicy;
+
+/// A title that became available.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Notification {
+    pub libby_id: String,
+    pub title: String,
+    pub available_copies: Option<i64>,
+}
+
+impl std::fmt::Display for Notification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "'{}' is available to borrow in Libby", self.title)
+    }
+}
+
+/// Where notifications go. Parsed from `stdout`, `desktop`,
+/// `webhook=<url>` or `email=<address>`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NotifySink {
+    Stdout,
+    /// `notify-send`
+    Desktop,
+    /// POST the notification as JSON
+    Webhook(String),
+    /// Mail through the SMTP server in `SmtpConfig`
+    Email(String),
+}
+
+impl std::str::FromStr for NotifySink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            None if s == "stdout" => Ok(Self::Stdout),
+            None if s == "desktop" => Ok(Self::Desktop),
+            Some(("webhook", url)) if !url.is_empty() => Ok(Self::Webhook(url.to_string())),
+            Some(("email", to)) if to.contains('@') => Ok(Self::Email(to.to_string())),
+            _ => bail!(
+                "unknown sink '{s}', expected stdout, desktop, webhook=<url> or email=<address>"
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for NotifySink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stdout => write!(f, "stdout"),
+            Self::Desktop => write!(f, "desktop"),
+            Self::Webhook(url) => write!(f, "webhook={url}"),
+            Self::Email(to) => write!(f, "email={to}"),
+        }
+    }
+}
+
+/// The SMTP server email notifications are sent through. No TLS or auth, as
+/// for a local relay.
+#[derive(clap::Args, Debug, Clone)]
+pub struct SmtpConfig {
+    /// SMTP server for email notifications
+    #[clap(long = "smtp-server", default_value = "127.0.0.1:25")]
+    pub server: String,
+
+    /// Sender address for email notifications
+    #[clap(long = "smtp-from", default_value = "gr2libby@localhost")]
+    pub from: String,
+}
+
+impl NotifySink {
+    pub async fn notify(
+        &self,
+        notification: &Notification,
+        http: &reqwest::Client,
+        smtp: &SmtpConfig,
+    ) -> Result<()> {
+        match self {
+            Self::Stdout => println!("{}", notification),
+            Self::Desktop => {
+                let status = tokio::process::Command::new("notify-send")
+                    .arg("gr2libby")
+                    .arg(notification.to_string())
+                    .status()
+                    .await
+                    .context("running notify-send")?;
+                if !status.success() {
+                    bail!("notify-send failed: {status}");
+                }
+            }
+            Self::Webhook(url) => {
+                let resp = http
+                    .post(url)
+                    .json(notification)
+                    .send()
+                    .await
+                    .context("posting webhook")?;
+                if !resp.status().is_success() {
+                    bail!("webhook returned {}", resp.status());
+                }
+            }
+            Self::Email(to) => {
+                send_mail(
+                    smtp,
+                    to,
+                    &format!("Available in Libby: {}", notification.title),
+                    &notification.to_string(),
+                )
+                .await?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read an SMTP reply, failing unless its code is `expected`.
+async fn smtp_reply<R: tokio::io::AsyncBufRead + Unpin>(
+    reader: &mut tokio::io::Lines<R>,
+    expected: &str,
+) -> Result<()> {
+    loop {
+        let line = reader
+            .next_line()
+            .await?
+            .ok_or_else(|| anyhow!("SMTP server closed the connection"))?;
+        // "250-..." continues a multi-line reply, "250 ..." ends it
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        if !line.starts_with(expected) {
+            bail!("SMTP server replied '{line}', expected {expected}");
+        }
+        return Ok(());
+    }
+}
+
+/// A header value as RFC 2047 encoded words when it isn't plain printable
+/// ASCII, folded so no encoded word is longer than 75 characters.
+fn encode_header(value: &str) -> String {
+    if value.chars().all(|c| c == ' ' || c.is_ascii_graphic()) {
+        return value.to_string();
+    }
+    let mut words = vec![];
+    let mut rest = value;
+    while !rest.is_empty() {
+        // 45 bytes are 60 in base64, leaving room for "=?UTF-8?B?" and "?="
+        let mut end = rest.len().min(45);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (chunk, tail) = rest.split_at(end);
+        words.push(format!("=?UTF-8?B?{}?=", BASE64_STANDARD.encode(chunk)));
+        rest = tail;
+    }
+    words.join("\r\n ")
+}
+
+/// `secs` since the epoch as an RFC 5322 date in UTC, e.g.
+/// "Thu, 01 Jan 1970 00:00:00 +0000".
+fn mail_date(secs: i64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (days, time) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // Civil from days, after Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} +0000",
+        DAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+async fn send_mail(smtp: &SmtpConfig, to: &str, subject: &str, body: &str) -> Result<()> {
+    // A line break would start another header or SMTP command
+    for address in [to, smtp.from.as_str()] {
+        if address.contains(['\r', '\n']) {
+            bail!("email address {address:?} contains a line break");
+        }
+    }
+    let stream = tokio::net::TcpStream::connect(&smtp.server)
+        .await
+        .with_context(|| format!("connecting to SMTP server {}", smtp.server))?;
+    let (read, mut write) = stream.into_split();
+    let mut reader = BufReader::new(read).lines();
+    smtp_reply(&mut reader, "220").await?;
+    for (command, expected) in [
+        ("HELO localhost".to_string(), "250"),
+        (format!("MAIL FROM:<{}>", smtp.from), "250"),
+        (format!("RCPT TO:<{to}>"), "250"),
+        ("DATA".to_string(), "354"),
+    ] {
+        write.write_all(format!("{command}\r\n").as_bytes()).await?;
+        smtp_reply(&mut reader, expected).await?;
+    }
+    // Lines starting with "." are escaped by doubling it
+    let body = body
+        .lines()
+        .map(|l| {
+            if l.starts_with('.') {
+                format!(".{l}")
+            } else {
+                l.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n");
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs() as i64;
+    let message = format!(
+        "From: {}\r\nTo: {to}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\n\
+         Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\
+         \r\n{body}\r\n.\r\n",
+        smtp.from,
+        encode_header(subject),
+        mail_date(now)
+    );
+    write.write_all(message.as_bytes()).await?;
+    smtp_reply(&mut reader, "250").await?;
+    write.write_all(b"QUIT\r\n").await?;
+    smtp_reply(&mut reader, "221").await?;
+    Ok(())
+}
+/// A shelf book found in the library.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct WatchedTitle {
+    libby_id: String,
+    title: String,
+    /// None until first checked
+    is_available: Option<bool>,
+    /// Sinks that already took the notification waiting to be delivered
+    #[serde(default)]
+    notified: Vec<String>,
+}
+
+/// What `watch` remembers between checks and runs, by Goodreads book id.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct WatchState {
+    titles: HashMap<i64, WatchedTitle>,
+}
+
+impl WatchState {
+    /// Record the availability, returning a notification, by Goodreads book
+    /// id, for each title that was unavailable last time and is available
+    /// now. Those stay unavailable until `delivered` is called, so a failed
+    /// notification is sent again at the next check. Titles checked for the
+    /// first time are only recorded.
+    fn update(&mut self, checked: Vec<(i64, Availability)>) -> Vec<(i64, Notification)> {
+        let mut notifications = vec![];
+        for (book_id, availability) in checked {
+            let Some(watched) = self.titles.get_mut(&book_id) else {
+                continue;
+            };
+            if watched.is_available == Some(false) && availability.is_available {
+                notifications.push((
+                    book_id,
+                    Notification {
+                        libby_id: watched.libby_id.clone(),
+                        title: watched.title.clone(),
+                        available_copies: availability.available_copies,
+                    },
+                ));
+            } else {
+                watched.is_available = Some(availability.is_available);
+                watched.notified.clear();
+            }
+        }
+        notifications
+    }
+
+    /// Record that one sink took the title's notification, so it is not
+    /// sent there again while another sink is retried.
+    fn notified(&mut self, book_id: i64, sink: &NotifySink) {
+        if let Some(watched) = self.titles.get_mut(&book_id) {
+            watched.notified.push(sink.to_string());
+        }
+    }
+
+    fn was_notified(&self, book_id: i64, sink: &NotifySink) -> bool {
+        self.titles
+            .get(&book_id)
+            .is_some_and(|watched| watched.notified.contains(&sink.to_string()))
+    }
+
+    /// Record that the title's notification went out.
+    fn delivered(&mut self, book_id: i64) {
+        if let Some(watched) = self.titles.get_mut(&book_id) {
+            watched.is_available = Some(true);
+            watched.notified.clear();
+        }
+    }
+}
+
+pub struct WatchArgs {
+    pub goodreads_export_csv: PathBuf,
+    pub card_id: String,
+    pub goodreads_shelf: String,
+    pub book_type: BookType,
+    pub interval: Duration,
+    /// Check once and exit, e.g. from cron
+    pub once: bool,
+    pub state_file: PathBuf,
+    pub sinks: Vec<NotifySink>,
+    pub smtp: SmtpConfig,
+    pub request_policy: RequestPolicy,
+    pub libby_endpoints: LibbyEndpoints,
+}
+
+/// Search the library for shelf books not matched yet, and forget books
+/// that have left the shelf.
+async fn match_shelf<C: LibraryCatalog>(
+    catalog: &C,
+    args: &WatchArgs,
+    state: &mut WatchState,
+) -> Result<()> {
+    let books = goodreads::get_book_titles_from_goodreads_shelf(
+        args.goodreads_export_csv.clone(),
+        &args.goodreads_shelf,
+    )
+    .await
+    .context("reading goodreads export")?;
+    let on_shelf: HashSet<i64> = books.iter().map(|b| b.book_id).collect();
+    state.titles.retain(|book_id, _| on_shelf.contains(book_id));
+
+    let unmatched: Vec<_> = books
+        .iter()
+        .filter(|b| !state.titles.contains_key(&b.book_id))
+        .collect();
+    if !unmatched.is_empty() {
+        eprintln!("Searching Libby for {} books...", unmatched.len());
+    }
+    let searches = search_shelf(
+        catalog,
+        SearchOptions {
+            book_type: args.book_type,
+            deep_search: true,
+            max_results: 24,
+        },
+        unmatched,
+    )
+    .await;
+    for (book, result) in searches {
+        match result {
+            // It can't become available until the library buys it, which
+            // `missing` is for. It is searched again when the shelf changes.
+            Ok(item) if item.is_owned == Some(false) => {
+                debug!("Not owned by the library: '{}'", book.title)
+            }
+            Ok(item) => {
+                state.titles.insert(
+                    book.book_id,
+                    WatchedTitle {
+                        libby_id: item.id,
+                        title: book.title.clone(),
+                        is_available: None,
+                        notified: vec![],
+                    },
+                );
+            }
+            Err(LibbyError::NotFound(_)) => debug!("Not found in Libby: '{}'", book.title),
+            Err(e @ LibbyError::Unauthorized(_)) => return Err(e.into()),
+            Err(e) => warn!("Searching for '{}' failed: {:?}", book.title, e),
+        }
+    }
+    eprintln!("Watching {} titles", state.titles.len());
+    Ok(())
+}
+
+/// Match the shelf again if the export changed since `matched_at`, the
+/// modification time of the export last matched. Returns whether it did.
+async fn match_shelf_if_changed<C: LibraryCatalog>(
+    catalog: &C,
+    args: &WatchArgs,
+    state: &mut WatchState,
+    matched_at: &mut Option<SystemTime>,
+) -> Result<bool> {
+    let modified = tokio::fs::metadata(&args.goodreads_export_csv)
+        .await
+        .and_then(|m| m.modified())
+        .with_context(|| format!("reading {}", args.goodreads_export_csv.display()))?;
+    if *matched_at == Some(modified) {
+        return Ok(false);
+    }
+    match_shelf(catalog, args, state).await?;
+    *matched_at = Some(modified);
+    Ok(true)
+}
+
+/// Send the notifications to every sink, marking a title available only
+/// once every sink took its notification. Sinks that already took it are
+/// skipped when the others are retried. Returns how many were delivered.
+async fn deliver(
+    state: &mut WatchState,
+    notifications: Vec<(i64, Notification)>,
+    args: &WatchArgs,
+    http: &reqwest::Client,
+) -> usize {
+    let mut delivered = 0;
+    for (book_id, notification) in notifications {
+        let mut missed = 0;
+        for sink in &args.sinks {
+            if state.was_notified(book_id, sink) {
+                continue;
+            }
+            match sink.notify(&notification, http, &args.smtp).await {
+                Ok(()) => state.notified(book_id, sink),
+                Err(e) => {
+                    warn!("Notifying {} failed: {:#}", sink, e);
+                    missed += 1;
+                }
+            }
+        }
+        if missed == 0 {
+            state.delivered(book_id);
+            delivered += 1;
+        } else {
+            warn!(
+                "{} sinks missed '{}', will retry them",
+                missed, notification.title
+            );
+        }
+    }
+    delivered
+}
+
+/// Check every watched title once and send notifications for the ones that
+/// became available. Returns how many were delivered.
+async fn check(
+    libby_client: &LibbyClient,
+    args: &WatchArgs,
+    state: &mut WatchState,
+    http: &reqwest::Client,
+) -> Result<usize> {
+    let checks: Vec<_> = futures::stream::iter(
+        state
+            .titles
+            .iter()
+            .map(|(book_id, watched)| async move {
+                (
+                    *book_id,
+                    watched.title.clone(),
+                    libby_client.get_availability(&watched.libby_id).await,
+                )
+            })
+            .collect::<Vec<_>>(),
+    )
+    .buffer_unordered(10)
+    .collect()
+    .await;
+
+    let mut checked = vec![];
+    for (book_id, title, result) in checks {
+        match result {
+            Ok(availability) => checked.push((book_id, availability)),
+            Err(e @ LibbyError::Unauthorized(_)) => return Err(e.into()),
+            Err(e) => warn!("Checking '{}' failed: {:?}", title, e),
+        }
+    }
+    let notifications = state.update(checked);
+    Ok(deliver(state, notifications, args, http).await)
+}
+
+/// Watch the shelf's titles until interrupted, or check once with `args.once`.
+pub async fn watch(args: WatchArgs, libby_conf_file: PathBuf) -> Result<()> {
+    let libby_client = LibbyClient::new_with_endpoints(
+        libby_conf_file,
+        args.card_id.clone(),
+        args.libby_endpoints.clone(),
+    )
+    .await
+    .context("client creation")?
+    .with_request_policy(args.request_policy.clone());
+    let http = reqwest::Client::new();
+    let mut state: WatchState = json_state::load(&args.state_file).await;
+
+    let mut matched_at = None;
+    loop {
+        // Pick up books added to or removed from the shelf by a new export
+        if match_shelf_if_changed(&libby_client, &args, &mut state, &mut matched_at).await? {
+            json_state::save(&state, &args.state_file).await?;
+        }
+        let notified = check(&libby_client, &args, &mut state, &http).await?;
+        json_state::save(&state, &args.state_file).await?;
+        info!(
+            "Checked {} titles, {} became available",
+            state.titles.len(),
+            notified
+        );
+        if args.once {
+            return Ok(());
+        }
+        tokio::time::sleep(args.interval).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use wiremock::Mock;
+    use wiremock::MockServer;
+    use wiremock::ResponseTemplate;
+    use wiremock::matchers::body_partial_json;
+    use wiremock::matchers::method;
+    use wiremock::matchers::path;
+
+    use super::*;
+    use crate::fake_libby;
+    use crate::fake_libby::FakeLibby;
+
+    const HAIL_MARY_BOOK_ID: i64 = 54493401;
+
+    fn watch_args(state_file: PathBuf, libby_endpoints: LibbyEndpoints) -> WatchArgs {
+        WatchArgs {
+            goodreads_export_csv: concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/goodreads_export.csv"
+            )
+            .into(),
+            card_id: fake_libby::CARD_ID.to_string(),
+            goodreads_shelf: "to-read".to_string(),
+            book_type: BookType::Ebook,
+            interval: Duration::from_secs(60),
+            once: true,
+            state_file,
+            sinks: vec![],
+            smtp: SmtpConfig {
+                server: "127.0.0.1:25".to_string(),
+                from: "gr2libby@localhost".to_string(),
+            },
+            request_policy: RequestPolicy::default(),
+            libby_endpoints,
+        }
+    }
+
+    fn availability(is_available: bool) -> Availability {
+        Availability {
+            is_available,
+            owned_copies: Some(1),
+            available_copies: Some(i64::from(is_available)),
+            holds_count: None,
+            estimated_wait_days: None,
+        }
+    }
+
+    /// Accept one message like an SMTP server would, returning what was
+    /// sent after DATA.
+    async fn fake_smtp(listener: tokio::net::TcpListener) -> String {
+        let (stream, _) = listener.accept().await.expect("accept");
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        write.write_all(b"220 fake ESMTP\r\n").await.unwrap();
+        let mut data = String::new();
+        let mut in_data = false;
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let reply: &[u8] = if in_data {
+                if line == "." {
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else {
+                    data.push_str(&line);
+                    data.push('\n');
+                    continue;
+                }
+            } else if line == "DATA" {
+                in_data = true;
+                b"354 go ahead\r\n"
+            } else if line == "QUIT" {
+                write.write_all(b"221 bye\r\n").await.unwrap();
+                break;
+            } else {
+                b"250-ok\r\n250 ok\r\n"
+            };
+            write.write_all(reply).await.unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse_sinks() {
+        assert_eq!("stdout".parse::<NotifySink>().unwrap(), NotifySink::Stdout);
+        assert_eq!(
+            "webhook=http://localhost/hook"
+                .parse::<NotifySink>()
+                .unwrap(),
+            NotifySink::Webhook("http://localhost/hook".to_string())
+        );
+        assert_eq!(
+            "email=me@example.com".parse::<NotifySink>().unwrap(),
+            NotifySink::Email("me@example.com".to_string())
+        );
+        assert!("email=nobody".parse::<NotifySink>().is_err());
+        assert!("pager".parse::<NotifySink>().is_err());
+    }
+
+    #[test]
+    fn test_update_notifies_on_becoming_available() {
+        let mut state = WatchState::default();
+        for (book_id, is_available) in [(1, None), (2, Some(false)), (3, Some(true))] {
+            state.titles.insert(
+                book_id,
+                WatchedTitle {
+                    libby_id: format!("{book_id}00"),
+                    title: format!("Title {book_id}"),
+                    is_available,
+                    notified: vec![],
+                },
+            );
+        }
+        let notifications = state.update(vec![
+            (1, availability(true)),
+            (2, availability(true)),
+            (3, availability(false)),
+        ]);
+        assert_eq!(
+            notifications,
+            vec![(
+                2,
+                Notification {
+                    libby_id: "200".to_string(),
+                    title: "Title 2".to_string(),
+                    available_copies: Some(1),
+                }
+            )]
+        );
+        assert!(state.titles.values().all(|t| t.is_available.is_some()));
+        assert_eq!(state.titles[&3].is_available, Some(false));
+        // Not until the notification went out
+        assert_eq!(state.titles[&2].is_available, Some(false));
+        state.delivered(2);
+        assert_eq!(state.titles[&2].is_available, Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_failed_notification_is_retried() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/hook"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/hook"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+        // Taken the first time, so not sent again when the other is retried
+        Mock::given(method("POST"))
+            .and(path("/other-hook"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let mut args = watch_args(PathBuf::new(), LibbyEndpoints::default());
+        args.sinks = vec![
+            NotifySink::Webhook(format!("{}/hook", server.uri())),
+            NotifySink::Webhook(format!("{}/other-hook", server.uri())),
+        ];
+        let http = reqwest::Client::new();
+        let mut state = WatchState::default();
+        state.titles.insert(
+            2,
+            WatchedTitle {
+                libby_id: "200".to_string(),
+                title: "Title 2".to_string(),
+                is_available: Some(false),
+                notified: vec![],
+            },
+        );
+
+        let notifications = state.update(vec![(2, availability(true))]);
+        assert_eq!(deliver(&mut state, notifications, &args, &http).await, 0);
+        assert_eq!(state.titles[&2].is_available, Some(false));
+
+        let notifications = state.update(vec![(2, availability(true))]);
+        assert_eq!(deliver(&mut state, notifications, &args, &http).await, 1);
+        assert_eq!(state.titles[&2].is_available, Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_shelf_matched_again_when_export_changes() {
+        let fake = FakeLibby::start().await;
+        let export = std::env::temp_dir().join(format!(
+            "gr2libby-watch-export-{}-{}.csv",
+            std::process::id(),
+            fake.server.address().port()
+        ));
+        std::fs::copy(
+            concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/goodreads_export.csv"
+            ),
+            &export,
+        )
+        .expect("copy export");
+        let mut args = watch_args(PathBuf::new(), fake.endpoints());
+        args.goodreads_export_csv = export.clone();
+        let libby_client = fake.client().await;
+        let mut state = WatchState::default();
+        let mut matched_at = None;
+
+        assert!(
+            match_shelf_if_changed(&libby_client, &args, &mut state, &mut matched_at)
+                .await
+                .expect("match")
+        );
+        assert_eq!(state.titles.len(), 1);
+        assert!(
+            !match_shelf_if_changed(&libby_client, &args, &mut state, &mut matched_at)
+                .await
+                .expect("match")
+        );
+
+        // A new export where the book has left the shelf
+        let csv = std::fs::read_to_string(&export).expect("read export");
+        std::fs::write(&export, csv.lines().next().expect("header")).expect("write export");
+        std::fs::File::options()
+            .write(true)
+            .open(&export)
+            .and_then(|f| f.set_modified(SystemTime::now() + Duration::from_secs(60)))
+            .expect("touch export");
+        assert!(
+            match_shelf_if_changed(&libby_client, &args, &mut state, &mut matched_at)
+                .await
+                .expect("match")
+        );
+        assert!(state.titles.is_empty());
+        let _ = std::fs::remove_file(&export);
+    }
+
+    #[tokio::test]
+    async fn test_match_and_check_against_fake_libby() {
+        let fake = FakeLibby::start().await;
+        let state_file = std::env::temp_dir().join(format!(
+            "gr2libby-watch-{}-{}.json",
+            std::process::id(),
+            fake.server.address().port()
+        ));
+        let args = watch_args(state_file.clone(), fake.endpoints());
+        let libby_client = fake.client().await;
+        let mut state = WatchState::default();
+        state.titles.insert(
+            1,
+            WatchedTitle {
+                libby_id: "1".to_string(),
+                title: "Since left the shelf".to_string(),
+                is_available: None,
+                notified: vec![],
+            },
+        );
+
+        match_shelf(&libby_client, &args, &mut state)
+            .await
+            .expect("match");
+        assert_eq!(state.titles.len(), 1);
+        let watched = &state.titles[&HAIL_MARY_BOOK_ID];
+        assert_eq!(watched.libby_id, fake_libby::FOUND_ID);
+        assert_eq!(watched.is_available, None);
+
+        // The fixture has the title out on loan
+        state
+            .titles
+            .get_mut(&HAIL_MARY_BOOK_ID)
+            .unwrap()
+            .is_available = Some(true);
+        let http = reqwest::Client::new();
+        let notified = check(&libby_client, &args, &mut state, &http)
+            .await
+            .expect("check");
+        assert_eq!(notified, 0);
+        json_state::save(&state, &state_file).await.expect("save");
+
+        let state: WatchState = json_state::load(&state_file).await;
+        assert_eq!(state.titles[&HAIL_MARY_BOOK_ID].is_available, Some(false));
+        let _ = std::fs::remove_file(&state_file);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_and_email_sinks() {
+        let notification = Notification {
+            libby_id: fake_libby::FOUND_ID.to_string(),
+            title: fake_libby::FOUND_TITLE.to_string(),
+            available_copies: Some(1),
+        };
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/hook"))
+            .and(body_partial_json(serde_json::json!({
+                "libby_id": fake_libby::FOUND_ID,
+                "title": fake_libby::FOUND_TITLE,
+            })))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind");
+        let smtp = SmtpConfig {
+            server: listener.local_addr().expect("addr").to_string(),
+            from: "gr2libby@localhost".to_string(),
+        };
+        let received = tokio::spawn(fake_smtp(listener));
+        let http = reqwest::Client::new();
+
+        NotifySink::Webhook(format!("{}/hook", server.uri()))
+            .notify(&notification, &http, &smtp)
+            .await
+            .expect("webhook");
+        NotifySink::Email("me@example.com".to_string())
+            .notify(&notification, &http, &smtp)
+            .await
+            .expect("email");
+
+        let data = received.await.expect("smtp server");
+        assert!(data.contains("To: me@example.com"));
+        assert!(data.contains("Subject: Available in Libby: Project Hail Mary"));
+        assert!(data.contains("\nDate: "));
+        assert!(data.contains("MIME-Version: 1.0"));
+        assert!(data.contains("Content-Type: text/plain; charset=utf-8"));
+        assert!(data.contains("'Project Hail Mary' is available to borrow in Libby"));
+
+        // Nothing is sent for an address that would add headers
+        let injected = NotifySink::Email("me@example.com\r\nBcc: all@example.com".to_string())
+            .notify(&notification, &http, &smtp)
+            .await;
+        assert!(injected.is_err());
+    }
+
+    #[test]
+    fn test_mail_headers() {
+        assert_eq!(mail_date(0), "Thu, 01 Jan 1970 00:00:00 +0000");
+        assert_eq!(mail_date(1_709_210_096), "Thu, 29 Feb 2024 12:34:56 +0000");
+        assert_eq!(encode_header("Available in Libby"), "Available in Libby");
+        assert_eq!(
+            encode_header("Available in Libby: Ёлка"),
+            "=?UTF-8?B?QXZhaWxhYmxlIGluIExpYmJ5OiDQgdC70LrQsA==?="
+        );
+        // No raw line break gets through, and long values are folded
+        let encoded = encode_header(&format!("{}\r\nBcc: all@example.com", "Ё".repeat(30)));
+        assert!(encoded.lines().all(|l| l.trim_end().len() <= 76));
+        assert!(!encoded.contains("Bcc"));
+        assert_eq!(encoded.lines().count(), 2);
+    }
+}
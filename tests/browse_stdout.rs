@@ -0,0 +1,55 @@
+//! `browse --format json` is meant to be piped into jq, so nothing but the
+//! rendered results may reach stdout.
+
+use std::process::Command;
+
+#[macro_use]
+mod common;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_browse_json_stdout_is_only_the_results() {
+    let server = common::start_fake_libby().await;
+    let out_dir = std::env::temp_dir().join(format!(
+        "gr2libby-browse-stdout-{}-{}",
+        std::process::id(),
+        server.address().port()
+    ));
+    std::fs::create_dir_all(&out_dir).expect("create output dir");
+    let conf_file = out_dir.join("libby_config.json");
+    std::fs::write(&conf_file, fixture!("libby_config.json")).expect("write libby config");
+
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_gr2libby"));
+    cmd.current_dir(&out_dir)
+        .env("LIBBY_SENTRY_BASE", server.uri())
+        .env("LIBBY_VANDAL_BASE", server.uri())
+        .env("LIBBY_THUNDER_BASE", server.uri())
+        .arg("--libby-conf-file")
+        .arg(&conf_file)
+        .args(["browse", "--card-id", common::CARD_ID, "--format", "json"])
+        .arg("--goodreads-export-csv")
+        .arg(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/goodreads_export.csv"
+        ))
+        .arg("--cache-file")
+        .arg(out_dir.join("browse_cache.json"));
+    let output = tokio::task::spawn_blocking(move || cmd.output())
+        .await
+        .expect("join")
+        .expect("run gr2libby");
+    let history_written = out_dir.join("availability_history.sqlite").exists();
+    let _ = std::fs::remove_dir_all(&out_dir);
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8(output.stdout).expect("utf-8 stdout");
+    let results: serde_json::Value = serde_json::from_str(&stdout)
+        .unwrap_or_else(|e| panic!("stdout is not just JSON ({e}):\n{stdout}"));
+    assert_eq!(results[0]["title"], common::FOUND_TITLE);
+    assert_eq!(results.as_array().map(|r| r.len()), Some(1));
+    // History is only kept when asked for
+    assert!(!history_written);
+}
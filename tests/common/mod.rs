@@ -0,0 +1,121 @@
+//! Local stand-in for the Libby and OverDrive services, serving the recorded
+//! responses in tests/fixtures/libby. Shared by the integration tests and,
+//! through src/fake_libby.rs, the library's own tests.
+
+// Each test crate uses only some of it
+#![allow(dead_code)]
+
+use wiremock::Mock;
+use wiremock::MockServer;
+use wiremock::ResponseTemplate;
+use wiremock::matchers::method;
+use wiremock::matchers::path;
+use wiremock::matchers::path_regex;
+use wiremock::matchers::query_param;
+
+pub const CARD_ID: &str = "1234567";
+pub const TAG_NAME: &str = "🎧";
+/// Title the fake catalog has a match for, every other search comes back empty
+pub const FOUND_TITLE: &str = "Project Hail Mary";
+pub const FOUND_ID: &str = "2002";
+
+macro_rules! fixture {
+    ($name:literal) => {
+        include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/libby/",
+            $name
+        ))
+    };
+}
+
+pub fn json(body: &str) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_raw(body, "application/json")
+}
+
+/// Start a server answering for sentry, vandal and thunder, for the client
+/// config in tests/fixtures/libby/libby_config.json.
+pub async fn start_fake_libby() -> MockServer {
+    let server = MockServer::start().await;
+
+    // sentry
+    Mock::given(method("POST"))
+        .and(path("/chip"))
+        .respond_with(json(fixture!("chip.json")))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/chip/clone/code"))
+        .respond_with(json(fixture!("clone_code.json")))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/chip/sync"))
+        .respond_with(json(fixture!("chip_sync.json")))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(format!("/card/{CARD_ID}/hold/{FOUND_ID}")))
+        .respond_with(json(fixture!("hold_created.json")))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(format!("/card/{CARD_ID}/loan/{FOUND_ID}")))
+        .respond_with(json(fixture!("loan_created.json")))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(format!("/card/{CARD_ID}/recommendation/{FOUND_ID}")))
+        .respond_with(json(fixture!("recommendation_created.json")))
+        .mount(&server)
+        .await;
+
+    // vandal
+    Mock::given(method("GET"))
+        .and(path("/tags"))
+        .respond_with(json(fixture!("tags.json")))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/tag/[^/]+/[^/]+$"))
+        .respond_with(json(fixture!("tag.json")))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/tag/[^/]+/[^/]+/tagging/[^/]+$"))
+        .respond_with(json(fixture!("tagging_created.json")))
+        .mount(&server)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path_regex(r"^/tag/[^/]+/[^/]+/tagging/[^/]+$"))
+        .respond_with(json(fixture!("tagging_destroyed.json")))
+        .mount(&server)
+        .await;
+
+    // thunder
+    Mock::given(method("GET"))
+        .and(path("/v2/libraries/testlib/media"))
+        .and(query_param("query", FOUND_TITLE))
+        .respond_with(json(fixture!("search_project_hail_mary.json")))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/v2/libraries/testlib/media"))
+        .respond_with(json(fixture!("search_empty.json")))
+        .with_priority(10)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("/v2/libraries/testlib/media/{FOUND_ID}")))
+        .respond_with(json(fixture!("media_2002.json")))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!(
+            "/v2/libraries/testlib/media/{FOUND_ID}/availability"
+        )))
+        .respond_with(json(fixture!("availability_2002.json")))
+        .mount(&server)
+        .await;
+    server
+}